@@ -0,0 +1,506 @@
+use crate::cfg::SettingsHandle;
+use crate::encoding::Encoding;
+use crate::secret::Secret;
+use crate::server::ServerState;
+use crate::settings::{check_port, Settings, SettingsError, SettingsErrors, TentacleSettings};
+use crate::tentacle::{TentacleAuth, TentacleInfo, DEFAULT_PORT, DEFAULT_PROTOCOL};
+use crate::tentacle_set::{Overlay, TentacleSet};
+use crate::tentacle_tls::TentacleTls;
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{http, HttpRequest, HttpResponse, Json, Path, Scope, State};
+use log::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+/// A tentacle as it is sent to the add and update endpoints.
+#[derive(Deserialize, Debug)]
+pub struct TentacleSpec {
+    pub host: String,
+    pub port: Option<i64>,
+    pub protocol: Option<String>,
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Secrets have to be `${env:VAR}` or `${file:path}` references, which
+    /// are stored in the overlay file instead of the secrets.
+    pub auth: Option<TentacleAuth>,
+    pub tls: Option<TentacleTls>,
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
+}
+
+impl TentacleSpec {
+    /// Validates the tentacle like a configured one. Credentials and TLS
+    /// settings which are not given are kept from the tentacle it replaces.
+    fn into_info(
+        self,
+        name: Option<String>,
+        tentacles: &TentacleSet,
+    ) -> Result<TentacleInfo, SettingsErrors> {
+        let mut errors = vec![];
+        let port = check_port(self.port.unwrap_or(DEFAULT_PORT));
+        let auth = match self.auth.as_ref().map(TentacleAuth::resolve) {
+            Some(Ok(auth)) => Some(auth),
+            Some(Err(e)) => {
+                errors.push(SettingsError::new("tentacle.auth", e));
+                None
+            }
+            None => None,
+        };
+        let mut settings = TentacleSettings {
+            host: self.host,
+            port: *port.as_ref().unwrap_or(&0),
+            protocol: self
+                .protocol
+                .unwrap_or_else(|| String::from(DEFAULT_PROTOCOL)),
+            alias: name.or(self.alias),
+            labels: self.labels,
+            auth,
+            tls: self.tls,
+            encodings: self.encodings,
+        };
+        let name = settings.to_info().name;
+        if let Some(replaced) = tentacles
+            .entries()
+            .into_iter()
+            .find(|e| e.info.name == name)
+        {
+            if self.auth.is_none() {
+                settings.auth = replaced.info.auth;
+            }
+            if settings.tls.is_none() && settings.protocol == "https" {
+                settings.tls = replaced.info.tls;
+            }
+        }
+        if let Err(checked) = settings.check("tentacle") {
+            errors.extend(checked.0);
+        }
+        if let Err(e) = port {
+            errors.push(SettingsError::new("tentacle.port", e));
+        }
+        if errors.is_empty() {
+            Ok(settings.to_info())
+        } else {
+            Err(SettingsErrors(errors))
+        }
+    }
+}
+
+/// Runtime changes to the tentacle set, optionally written to an overlay file
+/// which is applied again on startup.
+#[derive(Clone)]
+pub struct TentacleAdmin {
+    tentacles: TentacleSet,
+    overlay_file: Option<PathBuf>,
+}
+
+impl TentacleAdmin {
+    pub fn from_settings(settings: &Settings, tentacles: TentacleSet) -> TentacleAdmin {
+        TentacleAdmin {
+            tentacles,
+            overlay_file: settings.admin.overlay_file.clone(),
+        }
+    }
+
+    /// Applies the overlay file to the tentacle set, if there is one. Stored
+    /// secret references are resolved again. Redacted secrets were kept from
+    /// a configured tentacle and are taken from it again, changes of tentacles
+    /// which have no credentials to take are dropped.
+    pub fn load(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = &self.overlay_file {
+            if path.exists() {
+                let mut overlay: Overlay = serde_json::from_slice(&fs::read(path)?)?;
+                let mut added = vec![];
+                for mut info in overlay.added {
+                    info.auth = match info.auth.take() {
+                        Some(auth) if auth.is_redacted() => {
+                            match self.configured_auth(&info.name) {
+                                Some(auth) => Some(auth),
+                                None => {
+                                    warn!(
+                                    "Dropping stored change of tentacle {}, its credentials are gone",
+                                    info.name
+                                );
+                                    continue;
+                                }
+                            }
+                        }
+                        Some(auth) => Some(
+                            auth.resolve()
+                                .map_err(|e| format!("auth of tentacle {}: {}", info.name, e))?,
+                        ),
+                        None => None,
+                    };
+                    added.push(info);
+                }
+                overlay.added = added;
+                self.tentacles.set_overlay(overlay);
+            }
+        }
+        Ok(())
+    }
+
+    fn configured_auth(&self, name: &str) -> Option<TentacleAuth> {
+        self.tentacles
+            .entries()
+            .into_iter()
+            .find(|e| e.info.name == name)
+            .and_then(|e| e.info.auth)
+    }
+
+    fn persist(&self) -> Result<(), String> {
+        if let Some(path) = &self.overlay_file {
+            let result = serde_json::to_vec_pretty(&self.tentacles.overlay())
+                .map_err(|e| e.to_string())
+                .and_then(|json| {
+                    let tmp = path.with_extension("tmp");
+                    fs::write(&tmp, json)
+                        .and_then(|_| fs::rename(&tmp, path))
+                        .map_err(|e| e.to_string())
+                });
+            if let Err(e) = result {
+                let message = format!("Failed to write overlay file {}: {}", path.display(), e);
+                error!("{}", message);
+                return Err(message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if the change cannot be written to the overlay file, it is
+    /// applied nevertheless but lost on restart.
+    pub fn put(&self, info: TentacleInfo) -> Result<(), String> {
+        info!("Admin: setting tentacle {} to {}", info.name, info.uri());
+        self.tentacles.put(info);
+        self.persist()
+    }
+
+    pub fn remove(&self, name: &str) -> Result<bool, String> {
+        let removed = self.tentacles.remove(name);
+        if removed {
+            info!("Admin: removed tentacle {}", name);
+            self.persist()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn drain(&self, name: &str, drained: bool) -> Result<bool, String> {
+        let changed = self.tentacles.drain(name, drained);
+        if changed {
+            info!("Admin: tentacle {} drained: {}", name, drained);
+            self.persist()?;
+        }
+        Ok(changed)
+    }
+}
+
+/// Rejects requests which do not carry the configured `admin.token` as bearer
+/// token. Without a configured token the admin API is disabled.
+pub struct AdminAuth {
+    settings: SettingsHandle,
+}
+
+impl AdminAuth {
+    pub fn new(settings: SettingsHandle) -> AdminAuth {
+        AdminAuth { settings }
+    }
+}
+
+fn is_authorized(authorization: Option<&str>, token: Option<&str>) -> bool {
+    match (authorization, token) {
+        (Some(authorization), Some(token)) if !token.is_empty() => {
+            let expected = format!("Bearer {}", token);
+            // compare in constant time, the response time must not reveal how much of the token matched
+            authorization.len() == expected.len()
+                && authorization
+                    .bytes()
+                    .zip(expected.bytes())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+        }
+        _ => false,
+    }
+}
+
+impl<S> Middleware<S> for AdminAuth {
+    fn start(&self, req: &HttpRequest<S>) -> actix_web::Result<Started> {
+        let token = self.settings.get().admin.token.clone();
+        let authorization = req
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        if token.is_none() {
+            return Ok(Started::Response(
+                HttpResponse::Forbidden().body("Admin API is disabled"),
+            ));
+        }
+        if is_authorized(authorization, token.as_ref().map(Secret::expose)) {
+            Ok(Started::Done)
+        } else {
+            Ok(Started::Response(
+                HttpResponse::Unauthorized()
+                    .header(http::header::WWW_AUTHENTICATE, "Bearer")
+                    .finish(),
+            ))
+        }
+    }
+}
+
+fn list_tentacles(state: State<ServerState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.admin.tentacles.entries())
+}
+
+fn stream_counts(state: State<ServerState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.streams.counts())
+}
+
+fn invalid_tentacle(errors: SettingsErrors) -> HttpResponse {
+    let messages: Vec<String> = errors.0.iter().map(SettingsError::to_string).collect();
+    HttpResponse::BadRequest().body(messages.join("\n"))
+}
+
+fn put_tentacle(
+    spec: TentacleSpec,
+    name: Option<String>,
+    state: &ServerState,
+    response: fn() -> HttpResponseBuilder,
+) -> HttpResponse {
+    let info = match spec.into_info(name, &state.admin.tentacles) {
+        Ok(info) => info,
+        Err(errors) => return invalid_tentacle(errors),
+    };
+    match state.admin.put(info.clone()) {
+        Ok(()) => response().json(info),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
+
+fn add_tentacle(spec: Json<TentacleSpec>, state: State<ServerState>) -> HttpResponse {
+    put_tentacle(spec.into_inner(), None, &state, HttpResponse::Created)
+}
+
+fn update_tentacle(
+    name: Path<String>,
+    spec: Json<TentacleSpec>,
+    state: State<ServerState>,
+) -> HttpResponse {
+    put_tentacle(
+        spec.into_inner(),
+        Some(name.into_inner()),
+        &state,
+        HttpResponse::Ok,
+    )
+}
+
+/// Answers a change of an existing tentacle.
+fn changed(result: Result<bool, String>) -> HttpResponse {
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
+
+fn remove_tentacle(name: Path<String>, state: State<ServerState>) -> HttpResponse {
+    changed(state.admin.remove(name.as_str()))
+}
+
+fn drain_tentacle(name: Path<String>, state: State<ServerState>) -> HttpResponse {
+    changed(state.admin.drain(name.as_str(), true))
+}
+
+fn undrain_tentacle(name: Path<String>, state: State<ServerState>) -> HttpResponse {
+    changed(state.admin.drain(name.as_str(), false))
+}
+
+/// Registers the admin routes on the given scope.
+pub fn admin_scope(scope: Scope<ServerState>, settings: SettingsHandle) -> Scope<ServerState> {
+    scope
+        .middleware(AdminAuth::new(settings))
+        .resource("/tentacles", |r| {
+            r.get().with(list_tentacles);
+            r.post().with(add_tentacle);
+            r.f(|_| HttpResponse::MethodNotAllowed());
+        })
+        .resource("/tentacles/{name}", |r| {
+            r.put().with(update_tentacle);
+            r.delete().with(remove_tentacle);
+            r.f(|_| HttpResponse::MethodNotAllowed());
+        })
+        .resource("/tentacles/{name}/drain", |r| {
+            r.post().with(drain_tentacle);
+            r.delete().with(undrain_tentacle);
+            r.f(|_| HttpResponse::MethodNotAllowed());
+        })
+        .resource("/streams", |r| {
+            r.get().with(stream_counts);
+            r.f(|_| HttpResponse::MethodNotAllowed());
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::admin::*;
+    use crate::testing::tentacle;
+    use std::env;
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(is_authorized(Some("Bearer secret"), Some("secret")));
+        assert!(!is_authorized(Some("Bearer secreT"), Some("secret")));
+        assert!(!is_authorized(Some("secret"), Some("secret")));
+        assert!(!is_authorized(None, Some("secret")));
+        assert!(!is_authorized(Some("Bearer "), Some("")));
+        assert!(!is_authorized(Some("Bearer secret"), None));
+    }
+
+    #[test]
+    fn test_overlay_file() {
+        let path =
+            std::env::temp_dir().join(format!("logtopus-overlay-{}.json", std::process::id()));
+        let admin = TentacleAdmin {
+            tentacles: TentacleSet::new(vec![tentacle("t1", 18080), tentacle("t2", 18081)]),
+            overlay_file: Some(path.clone()),
+        };
+        admin.put(tentacle("t3", 18082)).unwrap();
+        assert!(admin.drain("t1", true).unwrap());
+        assert!(admin.remove("t2").unwrap());
+        assert!(!admin.remove("t4").unwrap());
+
+        let tentacles = TentacleSet::new(vec![tentacle("t1", 18080), tentacle("t2", 18081)]);
+        let restored = TentacleAdmin {
+            tentacles: tentacles.clone(),
+            overlay_file: Some(path.clone()),
+        };
+        restored.load().unwrap();
+        assert_eq!(vec![tentacle("t3", 18082)], tentacles.snapshot());
+        let drained: Vec<String> = tentacles
+            .entries()
+            .into_iter()
+            .filter(|e| e.drained)
+            .map(|e| e.info.name)
+            .collect();
+        assert_eq!(vec!["t1"], drained);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_file_not_writable() {
+        let admin = TentacleAdmin {
+            tentacles: TentacleSet::new(vec![tentacle("t1", 18080)]),
+            overlay_file: Some(PathBuf::from("/nonexistent/overlay.json")),
+        };
+        assert!(admin.put(tentacle("t2", 18081)).is_err());
+        assert!(admin.drain("t1", true).is_err());
+        assert!(admin.remove("t2").is_err());
+    }
+
+    #[test]
+    fn test_invalid_spec() {
+        let tentacles = TentacleSet::new(vec![]);
+        let spec = |json: &str| serde_json::from_str::<TentacleSpec>(json).unwrap();
+        let info = spec(r#"{"host": "node1", "port": 18080}"#)
+            .into_info(Some(String::from("t1")), &tentacles)
+            .unwrap();
+        assert_eq!("t1", info.name);
+        assert_eq!(18080, info.port);
+
+        let paths = |json: &str| -> Vec<String> {
+            let errors = spec(json).into_info(None, &tentacles).unwrap_err();
+            errors.0.into_iter().map(|e| e.path).collect()
+        };
+        assert_eq!(vec!["tentacle.host"], paths(r#"{"host": " "}"#));
+        assert_eq!(vec!["tentacle.port"], paths(r#"{"host": "a", "port": 0}"#));
+        assert_eq!(
+            vec!["tentacle.protocol"],
+            paths(r#"{"host": "a", "protocol": "ftp"}"#)
+        );
+        assert_eq!(
+            vec!["tentacle.host", "tentacle.protocol", "tentacle.port"],
+            paths(r#"{"host": "", "port": 70000, "protocol": "ftp"}"#)
+        );
+        assert_eq!(
+            vec!["tentacle.auth"],
+            paths(r#"{"host": "a", "auth": {"type": "bearer", "token": "plain"}}"#)
+        );
+        assert_eq!(
+            vec!["tentacle.tls"],
+            paths(r#"{"host": "a", "tls": {"insecure": true}}"#)
+        );
+    }
+
+    #[test]
+    fn test_keep_credentials() {
+        env::set_var("LOGTOPUS_TEST_TENTACLE_TOKEN", "from-env");
+        let path =
+            env::temp_dir().join(format!("logtopus-overlay-auth-{}.json", std::process::id()));
+        let configured = || {
+            let mut t1 = tentacle("t1", 18080);
+            t1.auth = Some(TentacleAuth::Bearer {
+                token: Secret::from(String::from("configured")),
+            });
+            TentacleSet::new(vec![t1, tentacle("t2", 18081)])
+        };
+        let admin = TentacleAdmin {
+            tentacles: configured(),
+            overlay_file: Some(path.clone()),
+        };
+        let put = |name: &str, json: &str| {
+            let spec = serde_json::from_str::<TentacleSpec>(json).unwrap();
+            let info = spec
+                .into_info(Some(String::from(name)), &admin.tentacles)
+                .unwrap();
+            admin.put(info.clone()).unwrap();
+            info
+        };
+        let moved = put("t1", r#"{"host": "node1", "port": 18090}"#);
+        assert_eq!("node1", moved.host);
+        assert_eq!(
+            Some("Bearer configured"),
+            moved
+                .auth
+                .as_ref()
+                .map(TentacleAuth::authorization)
+                .as_deref()
+        );
+        let referenced = put(
+            "t2",
+            r#"{"host": "node2", "auth": {"type": "bearer", "token": "${env:LOGTOPUS_TEST_TENTACLE_TOKEN}"}}"#,
+        );
+        assert_eq!(
+            Some("Bearer from-env"),
+            referenced
+                .auth
+                .as_ref()
+                .map(TentacleAuth::authorization)
+                .as_deref()
+        );
+        let stored = fs::read_to_string(&path).unwrap();
+        assert!(!stored.contains("configured"));
+        assert!(!stored.contains("from-env"));
+
+        let tentacles = configured();
+        let restored = TentacleAdmin {
+            tentacles: tentacles.clone(),
+            overlay_file: Some(path.clone()),
+        };
+        restored.load().unwrap();
+        assert_eq!(vec![moved, referenced.clone()], tentacles.snapshot());
+
+        // the configured tentacle is gone, so are its credentials
+        let tentacles = TentacleSet::new(vec![]);
+        let restored = TentacleAdmin {
+            tentacles: tentacles.clone(),
+            overlay_file: Some(path.clone()),
+        };
+        restored.load().unwrap();
+        assert_eq!(vec![referenced], tentacles.snapshot());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
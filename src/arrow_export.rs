@@ -0,0 +1,265 @@
+use crate::tentacle::LogLine;
+use arrow_array::builder::{StringBuilder, TimestampMillisecondBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use bytes::Bytes;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+pub const ARROW_STREAM: &str = "application/vnd.apache.arrow.stream";
+pub const PARQUET: &str = "application/vnd.apache.parquet";
+
+/// A batch is written once its strings reach this size, which bounds the
+/// memory of an export however many lines it has.
+const BATCH_BYTES: usize = 4 * 1024 * 1024;
+const BATCH_ROWS: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExportFormat {
+    /// Arrow IPC stream, one message per batch.
+    ArrowStream,
+    /// A Parquet file with one row group per batch.
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::ArrowStream => ARROW_STREAM,
+            ExportFormat::Parquet => PARQUET,
+        }
+    }
+}
+
+/// The columns of `LogLine`, timestamps in milliseconds since the epoch.
+fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("message", DataType::Utf8, false),
+        Field::new("loglevel", DataType::Utf8, true),
+        Field::new("id", DataType::Utf8, false),
+        Field::new("source", DataType::Utf8, false),
+    ]))
+}
+
+/// The written bytes, taken out after each batch.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> Bytes {
+        Bytes::from(std::mem::take(&mut *self.0.lock().unwrap()))
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Writer {
+    ArrowStream(StreamWriter<Output>),
+    Parquet(ArrowWriter<Output>),
+}
+
+#[derive(Default)]
+struct Batch {
+    timestamps: TimestampMillisecondBuilder,
+    messages: StringBuilder,
+    loglevels: StringBuilder,
+    ids: StringBuilder,
+    sources: StringBuilder,
+    rows: usize,
+    bytes: usize,
+}
+
+impl Batch {
+    fn push(&mut self, line: &LogLine) {
+        self.timestamps.append_value(line.timestamp);
+        self.messages.append_value(&line.message);
+        self.loglevels.append_option(line.loglevel.as_ref());
+        self.ids.append_value(&line.id);
+        self.sources.append_value(&line.source);
+        self.rows += 1;
+        self.bytes += 8
+            + line.message.len()
+            + line.loglevel.as_ref().map_or(0, String::len)
+            + line.id.len()
+            + line.source.len();
+    }
+
+    fn is_full(&self) -> bool {
+        self.bytes >= BATCH_BYTES || self.rows >= BATCH_ROWS
+    }
+
+    fn finish(&mut self, schema: &SchemaRef) -> Result<RecordBatch, ArrowError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.timestamps.finish().with_timezone("UTC")),
+            Arc::new(self.messages.finish()),
+            Arc::new(self.loglevels.finish()),
+            Arc::new(self.ids.finish()),
+            Arc::new(self.sources.finish()),
+        ];
+        self.rows = 0;
+        self.bytes = 0;
+        RecordBatch::try_new(schema.clone(), columns)
+    }
+}
+
+/// Encodes a stream of lines batch by batch. Each call returns the bytes
+/// written so far, empty while a batch is being filled.
+pub struct ArrowEncoder {
+    schema: SchemaRef,
+    batch: Batch,
+    writer: Writer,
+    output: Output,
+}
+
+impl ArrowEncoder {
+    pub fn new(format: ExportFormat) -> Result<ArrowEncoder, Box<dyn Error>> {
+        let schema = schema();
+        let output = Output::default();
+        let writer = match format {
+            ExportFormat::ArrowStream => {
+                Writer::ArrowStream(StreamWriter::try_new(output.clone(), &schema)?)
+            }
+            ExportFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                Writer::Parquet(ArrowWriter::try_new(
+                    output.clone(),
+                    schema.clone(),
+                    Some(properties),
+                )?)
+            }
+        };
+        Ok(ArrowEncoder {
+            schema,
+            batch: Batch::default(),
+            writer,
+            output,
+        })
+    }
+
+    fn write_batch(&mut self) -> Result<(), Box<dyn Error>> {
+        let batch = self.batch.finish(&self.schema)?;
+        match &mut self.writer {
+            Writer::ArrowStream(writer) => writer.write(&batch)?,
+            Writer::Parquet(writer) => {
+                writer.write(&batch)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, line: &LogLine) -> Result<Bytes, Box<dyn Error>> {
+        self.batch.push(line);
+        if self.batch.is_full() {
+            self.write_batch()?;
+        }
+        Ok(self.output.take())
+    }
+
+    /// Writes the last batch and ends the stream or file.
+    pub fn finish(&mut self) -> Result<Bytes, Box<dyn Error>> {
+        if self.batch.rows > 0 {
+            self.write_batch()?;
+        }
+        match &mut self.writer {
+            Writer::ArrowStream(writer) => writer.finish()?,
+            Writer::Parquet(writer) => {
+                writer.finish()?;
+            }
+        }
+        Ok(self.output.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arrow_export::*;
+    use crate::testing::log_line;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::TimestampMillisecondType;
+    use arrow_array::Array;
+    use arrow_ipc::reader::StreamReader;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    /// Encodes lines big enough for several batches, returns the chunks.
+    fn encode(format: ExportFormat) -> Vec<Bytes> {
+        let mut encoder = ArrowEncoder::new(format).unwrap();
+        let mut chunks = vec![];
+        let message = "x".repeat(BATCH_BYTES / 4);
+        for n in 0..10 {
+            let loglevel = if n % 2 == 0 { Some("INFO") } else { None };
+            chunks.push(encoder.push(&log_line(n, &message, loglevel)).unwrap());
+        }
+        chunks.push(encoder.finish().unwrap());
+        chunks
+    }
+
+    fn concat(chunks: &[Bytes]) -> Vec<u8> {
+        chunks.iter().flat_map(|chunk| chunk.to_vec()).collect()
+    }
+
+    #[test]
+    fn test_arrow_stream() {
+        let chunks = encode(ExportFormat::ArrowStream);
+        // the schema is written at once, batches whenever they are full
+        assert!(!chunks[0].is_empty());
+        assert!(chunks[1].is_empty());
+        assert!(!chunks[3].is_empty());
+
+        let reader = StreamReader::try_new(io::Cursor::new(concat(&chunks)), None).unwrap();
+        let batches: Vec<RecordBatch> = reader.map(Result::unwrap).collect();
+        assert_eq!(3, batches.len());
+        assert_eq!(10, batches.iter().map(RecordBatch::num_rows).sum::<usize>());
+        let first = &batches[0];
+        assert_eq!(schema(), first.schema());
+        assert_eq!(
+            1,
+            first
+                .column(0)
+                .as_primitive::<TimestampMillisecondType>()
+                .value(1)
+        );
+        let loglevels = first.column(2).as_string::<i32>();
+        assert_eq!("INFO", loglevels.value(0));
+        assert!(loglevels.is_null(1));
+    }
+
+    #[test]
+    fn test_parquet() {
+        let chunks = encode(ExportFormat::Parquet);
+        assert!(chunks[1].is_empty());
+        assert!(!chunks[3].is_empty());
+
+        let path = std::env::temp_dir().join(format!("logtopus-{}.parquet", std::process::id()));
+        std::fs::write(&path, concat(&chunks)).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+        assert_eq!(3, builder.metadata().num_row_groups());
+        let batches: Vec<RecordBatch> = builder.build().unwrap().map(Result::unwrap).collect();
+        assert_eq!(10, batches.iter().map(RecordBatch::num_rows).sum::<usize>());
+        assert_eq!("web-1", batches[0].column(4).as_string::<i32>().value(9));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
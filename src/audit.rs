@@ -0,0 +1,124 @@
+use crate::cfg::SettingsHandle;
+use crate::log_file::LogFile;
+use crate::tentacle::LogLine;
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::Serialize;
+use std::time::Instant;
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct AuditFilters {
+    pub loglevels: Option<String>,
+}
+
+/// The requested start and the timestamp of the last line returned.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct TimeRange {
+    pub from_ms: u64,
+    pub to_ms: Option<i64>,
+}
+
+/// One line of the audit log, written when a content query has finished.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub principal: Option<String>,
+    pub client_ip: Option<String>,
+    pub sources: Vec<String>,
+    pub filters: AuditFilters,
+    pub time_range: TimeRange,
+    pub tentacles: Vec<String>,
+    pub lines: u64,
+    pub duration_ms: u64,
+}
+
+/// Collects the entry of a running query, written to the audit log when the
+/// query is dropped, i.e. when it completed or the client went away.
+pub struct AuditRecord {
+    settings: SettingsHandle,
+    log: LogFile,
+    entry: AuditEntry,
+    started: Instant,
+}
+
+impl AuditRecord {
+    pub fn new(settings: SettingsHandle, log: LogFile, entry: AuditEntry) -> AuditRecord {
+        AuditRecord {
+            settings,
+            log,
+            entry,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn line(&mut self, line: &LogLine) {
+        self.entry.lines += 1;
+        self.entry.time_range.to_ms = Some(line.timestamp);
+    }
+}
+
+impl Drop for AuditRecord {
+    fn drop(&mut self) {
+        let settings = self.settings.get();
+        if let Some(audit) = &settings.audit {
+            self.entry.duration_ms = self.started.elapsed().as_millis() as u64;
+            if let Err(e) = self.log.write(audit, &self.entry) {
+                error!("Failed to write audit log {}: {}", audit.file.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::*;
+    use crate::log_file::LogFileSettings;
+    use crate::settings::Settings;
+    use crate::testing::log_line;
+    use std::fs;
+
+    fn entry() -> AuditEntry {
+        AuditEntry {
+            timestamp: Utc::now(),
+            principal: Some(String::from("grafana")),
+            client_ip: Some(String::from("127.0.0.1")),
+            sources: vec![String::from("app-web")],
+            filters: AuditFilters {
+                loglevels: Some(String::from("ERROR")),
+            },
+            time_range: TimeRange {
+                from_ms: 1000,
+                to_ms: None,
+            },
+            tentacles: vec![String::from("web-1")],
+            lines: 0,
+            duration_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let path = std::env::temp_dir().join(format!("logtopus-audit-{}.log", std::process::id()));
+        let mut settings = Settings::anonymous_defaults();
+        settings.audit = Some(LogFileSettings {
+            file: path.clone(),
+            max_size_bytes: 1024,
+            max_files: 1,
+        });
+        let settings = SettingsHandle::new(settings);
+
+        let mut record = AuditRecord::new(settings, LogFile::default(), entry());
+        record.line(&log_line(1500, "hello", Some("ERROR")));
+        record.line(&log_line(2000, "hello", Some("ERROR")));
+        drop(record);
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!("grafana", written["principal"]);
+        assert_eq!(2, written["lines"]);
+        assert_eq!(1000, written["time_range"]["from_ms"]);
+        assert_eq!(2000, written["time_range"]["to_ms"]);
+        assert_eq!("ERROR", written["filters"]["loglevels"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,558 @@
+use crate::cfg::SettingsHandle;
+use crate::secret::Secret;
+use crate::settings::{AuthSettings, Settings};
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{http, HttpRequest, HttpResponse};
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+const REALM: &str = "logtopus";
+
+/// A static API token, sent as `Authorization: Bearer <token>`.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ApiToken {
+    /// Identifies the client in logs, the token itself is never logged.
+    pub name: String,
+    pub token: Secret,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// A user authenticating with HTTP basic auth.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ApiUser {
+    pub username: String,
+    /// bcrypt hash of the password, e.g. from `htpasswd -nbB`.
+    pub password_hash: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+fn default_username_claim() -> String {
+    String::from("sub")
+}
+
+fn default_roles_claim() -> String {
+    String::from("roles")
+}
+
+/// Validation of JSON web tokens issued by an external identity provider.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct JwtSettings {
+    /// JSON web key set with the public keys of the issuer, read on startup and reload.
+    pub jwks_file: PathBuf,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// The claim holding the name of the user.
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    /// The claim holding the role names, a list or a single string.
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+}
+
+#[derive(Clone, Copy, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Token,
+    Basic,
+    Jwt,
+}
+
+/// The authenticated client of a request, available from the request extensions.
+#[derive(Clone, Serialize, PartialEq, Debug)]
+pub struct Principal {
+    pub name: String,
+    pub method: AuthMethod,
+    pub roles: Vec<String>,
+}
+
+/// Checks the credentials of one authorization scheme.
+pub trait Authenticator {
+    /// The scheme of the `Authorization` header, e.g. `Bearer`.
+    fn scheme(&self) -> &'static str;
+
+    /// Returns the principal if the credentials are valid for this authenticator.
+    fn authenticate(&self, credentials: &str) -> Option<Principal>;
+}
+
+/// Compares in constant time, the response time must not reveal how much of a secret matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub struct TokenAuthenticator {
+    tokens: Vec<ApiToken>,
+}
+
+impl Authenticator for TokenAuthenticator {
+    fn scheme(&self) -> &'static str {
+        "Bearer"
+    }
+
+    fn authenticate(&self, credentials: &str) -> Option<Principal> {
+        self.tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token.expose().as_bytes(), credentials.as_bytes()))
+            .map(|t| Principal {
+                name: t.name.clone(),
+                method: AuthMethod::Token,
+                roles: t.roles.clone(),
+            })
+    }
+}
+
+pub struct BasicAuthenticator {
+    users: Vec<ApiUser>,
+}
+
+impl Authenticator for BasicAuthenticator {
+    fn scheme(&self) -> &'static str {
+        "Basic"
+    }
+
+    fn authenticate(&self, credentials: &str) -> Option<Principal> {
+        let decoded = base64::decode(credentials).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let mut parts = decoded.splitn(2, ':');
+        let (username, password) = (parts.next()?, parts.next()?);
+        let user = self.users.iter().find(|u| u.username == username)?;
+        match bcrypt::verify(password, &user.password_hash) {
+            Ok(true) => Some(Principal {
+                name: user.username.clone(),
+                method: AuthMethod::Basic,
+                roles: user.roles.clone(),
+            }),
+            Ok(false) => None,
+            Err(e) => {
+                warn!("Invalid password hash of user {}: {}", username, e);
+                None
+            }
+        }
+    }
+}
+
+/// The algorithms a key of the set may be used with, the algorithm named in a
+/// token is only accepted if it is one of them.
+fn key_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(alg) = jwk.common.algorithm {
+        return vec![alg];
+    }
+    match jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::OctetKey(_) => {
+            vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512]
+        }
+        _ => vec![],
+    }
+}
+
+fn decoding_key(jwk: &Jwk) -> Result<DecodingKey, String> {
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(rsa) => {
+            DecodingKey::from_rsa_components(&rsa.n, &rsa.e).map_err(|e| e.to_string())
+        }
+        AlgorithmParameters::OctetKey(oct) => {
+            base64::decode_config(&oct.value, base64::URL_SAFE_NO_PAD)
+                .map(|secret| DecodingKey::from_secret(&secret))
+                .map_err(|e| e.to_string())
+        }
+        _ => Err(String::from("unsupported key type, expected RSA or oct")),
+    }
+}
+
+struct JwtKey {
+    id: Option<String>,
+    algorithms: Vec<Algorithm>,
+    key: DecodingKey,
+}
+
+pub struct JwtAuthenticator {
+    keys: Vec<JwtKey>,
+    settings: JwtSettings,
+}
+
+impl JwtAuthenticator {
+    /// Reads the key set, failing if none of its keys can be used.
+    pub fn from_settings(settings: &JwtSettings) -> Result<JwtAuthenticator, String> {
+        let path = settings.jwks_file.display();
+        let json =
+            fs::read(&settings.jwks_file).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let set: JwkSet = serde_json::from_slice(&json)
+            .map_err(|e| format!("invalid key set in {}: {}", path, e))?;
+        let mut keys = vec![];
+        for jwk in &set.keys {
+            match decoding_key(jwk) {
+                Ok(key) => keys.push(JwtKey {
+                    id: jwk.common.key_id.clone(),
+                    algorithms: key_algorithms(jwk),
+                    key,
+                }),
+                Err(e) => warn!(
+                    "Skipping key {} of {}: {}",
+                    jwk.common.key_id.as_deref().unwrap_or("without id"),
+                    path,
+                    e
+                ),
+            }
+        }
+        if keys.is_empty() {
+            return Err(format!("no usable key in {}", path));
+        }
+        Ok(JwtAuthenticator {
+            keys,
+            settings: settings.clone(),
+        })
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn scheme(&self) -> &'static str {
+        "Bearer"
+    }
+
+    fn authenticate(&self, credentials: &str) -> Option<Principal> {
+        let header = jsonwebtoken::decode_header(credentials).ok()?;
+        // tokens without key id are only accepted if the set has a single key
+        let key = match &header.kid {
+            Some(kid) => self.keys.iter().find(|k| k.id.as_ref() == Some(kid))?,
+            None if self.keys.len() == 1 => &self.keys[0],
+            None => return None,
+        };
+        if !key.algorithms.contains(&header.alg) {
+            return None;
+        }
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.settings.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.settings.audience {
+            validation.set_audience(&[audience]);
+        }
+        let claims = jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+            credentials,
+            &key.key,
+            &validation,
+        )
+        .map_err(|e| debug!("Rejected JSON web token: {}", e))
+        .ok()?
+        .claims;
+        claims
+            .get(&self.settings.username_claim)
+            .and_then(|name| name.as_str())
+            .map(|name| Principal {
+                name: name.to_string(),
+                method: AuthMethod::Jwt,
+                roles: match claims.get(&self.settings.roles_claim) {
+                    Some(serde_json::Value::String(role)) => vec![role.clone()],
+                    Some(serde_json::Value::Array(roles)) => roles
+                        .iter()
+                        .filter_map(|role| role.as_str().map(String::from))
+                        .collect(),
+                    _ => vec![],
+                },
+            })
+    }
+}
+
+/// Builds the authenticators of all configured methods, an empty list means
+/// authentication is disabled.
+pub fn authenticators(settings: &AuthSettings) -> Vec<Box<dyn Authenticator>> {
+    let mut authenticators: Vec<Box<dyn Authenticator>> = vec![];
+    if !settings.tokens.is_empty() {
+        authenticators.push(Box::new(TokenAuthenticator {
+            tokens: settings.tokens.clone(),
+        }));
+    }
+    if !settings.users.is_empty() {
+        authenticators.push(Box::new(BasicAuthenticator {
+            users: settings.users.clone(),
+        }));
+    }
+    if let Some(jwt) = &settings.jwt {
+        match JwtAuthenticator::from_settings(jwt) {
+            Ok(authenticator) => authenticators.push(Box::new(authenticator)),
+            // the key set was valid when the settings were checked, reject all tokens
+            // rather than falling back to anonymous access
+            Err(e) => {
+                error!("JSON web tokens are rejected: {}", e);
+                authenticators.push(Box::new(TokenAuthenticator { tokens: vec![] }));
+            }
+        }
+    }
+    authenticators
+}
+
+fn authenticate(
+    authenticators: &[Box<dyn Authenticator>],
+    authorization: &str,
+) -> Option<Principal> {
+    let mut parts = authorization.trim().splitn(2, ' ');
+    let (scheme, credentials) = (parts.next()?, parts.next()?.trim());
+    authenticators
+        .iter()
+        .filter(|a| a.scheme().eq_ignore_ascii_case(scheme))
+        .find_map(|a| a.authenticate(credentials))
+}
+
+type Authenticators = Rc<Vec<Box<dyn Authenticator>>>;
+
+/// Requires every request outside the anonymous and skipped paths to be
+/// authenticated by one of the configured methods and adds its `Principal`
+/// to the request extensions. Without configured methods all requests pass
+/// only if `auth.allow_anonymous` is set.
+pub struct ApiAuth {
+    settings: SettingsHandle,
+    anonymous: Vec<String>,
+    skipped: Vec<String>,
+    /// The authenticators of the settings they were built from, rebuilt after a reload.
+    current: RefCell<Option<(Arc<Settings>, Authenticators)>>,
+}
+
+impl ApiAuth {
+    pub fn new(settings: SettingsHandle) -> ApiAuth {
+        ApiAuth {
+            settings,
+            anonymous: vec![],
+            skipped: vec![],
+            current: RefCell::new(None),
+        }
+    }
+
+    /// Allows anonymous access to exactly this path.
+    pub fn allow_anonymous(mut self, path: &str) -> ApiAuth {
+        self.anonymous.push(path.to_string());
+        self
+    }
+
+    /// Leaves this path and everything below it to its own authentication.
+    pub fn skip(mut self, prefix: &str) -> ApiAuth {
+        self.skipped.push(prefix.to_string());
+        self
+    }
+
+    fn authenticators(&self) -> Authenticators {
+        let settings = self.settings.get();
+        let mut current = self.current.borrow_mut();
+        match &*current {
+            Some((built_from, authenticators)) if Arc::ptr_eq(built_from, &settings) => {
+                authenticators.clone()
+            }
+            _ => {
+                let authenticators = Rc::new(authenticators(&settings.auth));
+                *current = Some((settings, authenticators.clone()));
+                authenticators
+            }
+        }
+    }
+
+    fn is_exempt(&self, path: &str) -> bool {
+        self.anonymous.iter().any(|p| p == path)
+            || self.skipped.iter().any(|prefix| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+    }
+}
+
+impl<S> Middleware<S> for ApiAuth {
+    fn start(&self, req: &HttpRequest<S>) -> actix_web::Result<Started> {
+        if self.is_exempt(req.path()) {
+            return Ok(Started::Done);
+        }
+        let authenticators = self.authenticators();
+        if authenticators.is_empty() {
+            return Ok(if self.settings.get().auth.allow_anonymous {
+                Started::Done
+            } else {
+                Started::Response(HttpResponse::Unauthorized().finish())
+            });
+        }
+        let principal = req
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|authorization| authenticate(&authenticators, authorization));
+        match principal {
+            Some(principal) => {
+                req.extensions_mut().insert(principal);
+                Ok(Started::Done)
+            }
+            None => {
+                let mut response = HttpResponse::Unauthorized();
+                let mut schemes: Vec<&str> = vec![];
+                for scheme in authenticators.iter().map(|a| a.scheme()) {
+                    if !schemes.contains(&scheme) {
+                        schemes.push(scheme);
+                    }
+                }
+                for scheme in schemes {
+                    response.header(
+                        http::header::WWW_AUTHENTICATE,
+                        format!("{} realm=\"{}\"", scheme, REALM),
+                    );
+                }
+                Ok(Started::Response(response.finish()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::*;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    fn jwt_settings() -> JwtSettings {
+        JwtSettings {
+            jwks_file: PathBuf::from("tests/jwt/jwks.json"),
+            issuer: Some(String::from("https://idp.example.com")),
+            audience: None,
+            username_claim: default_username_claim(),
+            roles_claim: default_roles_claim(),
+        }
+    }
+
+    fn sign(kid: Option<&str>, alg: Algorithm, claims: serde_json::Value) -> String {
+        let mut header = Header::new(alg);
+        header.kid = kid.map(String::from);
+        let key = match alg {
+            Algorithm::HS256 => EncodingKey::from_secret(b"logtopus-test-hmac-secret"),
+            _ => EncodingKey::from_rsa_pem(&fs::read("tests/jwt/key.pem").unwrap()).unwrap(),
+        };
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    fn claims(iss: &str, exp_offset: i64) -> serde_json::Value {
+        let exp = jsonwebtoken::get_current_timestamp() as i64 + exp_offset;
+        serde_json::json!({"sub": "alice", "iss": iss, "exp": exp, "roles": ["app", "ops"]})
+    }
+
+    #[test]
+    fn test_token_and_basic() {
+        let settings: AuthSettings = serde_json::from_value(serde_json::json!({
+            "tokens": [{"name": "grafana", "token": "t0ken", "roles": ["app"]}],
+            "users": [{"username": "bob", "password_hash": bcrypt::hash("pa:ss", 4).unwrap()}]
+        }))
+        .unwrap();
+        let authenticators = authenticators(&settings);
+
+        assert_eq!(
+            Some(Principal {
+                name: String::from("grafana"),
+                method: AuthMethod::Token,
+                roles: vec![String::from("app")],
+            }),
+            authenticate(&authenticators, "bearer t0ken")
+        );
+        assert_eq!(None, authenticate(&authenticators, "Bearer t0keN"));
+        assert_eq!(None, authenticate(&authenticators, "t0ken"));
+
+        let basic = format!("Basic {}", base64::encode("bob:pa:ss"));
+        assert_eq!(
+            Some(String::from("bob")),
+            authenticate(&authenticators, &basic).map(|p| p.name)
+        );
+        let wrong = format!("Basic {}", base64::encode("bob:pa"));
+        assert_eq!(None, authenticate(&authenticators, &wrong));
+        assert_eq!(None, authenticate(&authenticators, "Basic %%%"));
+    }
+
+    #[test]
+    fn test_jwt() {
+        let authenticator = JwtAuthenticator::from_settings(&jwt_settings()).unwrap();
+        let valid = claims("https://idp.example.com", 300);
+
+        let principal = authenticator
+            .authenticate(&sign(Some("test-rsa"), Algorithm::RS256, valid.clone()))
+            .unwrap();
+        assert_eq!("alice", principal.name);
+        assert_eq!(AuthMethod::Jwt, principal.method);
+        assert_eq!(vec!["app", "ops"], principal.roles);
+        assert!(authenticator
+            .authenticate(&sign(Some("test-hmac"), Algorithm::HS256, valid.clone()))
+            .is_some());
+
+        // unknown key, ambiguous key, wrong algorithm for the key, expired, wrong issuer
+        assert!(authenticator
+            .authenticate(&sign(Some("other"), Algorithm::RS256, valid.clone()))
+            .is_none());
+        assert!(authenticator
+            .authenticate(&sign(None, Algorithm::RS256, valid.clone()))
+            .is_none());
+        assert!(authenticator
+            .authenticate(&sign(Some("test-rsa"), Algorithm::RS512, valid))
+            .is_none());
+        assert!(authenticator
+            .authenticate(&sign(
+                Some("test-rsa"),
+                Algorithm::RS256,
+                claims("https://idp.example.com", -300)
+            ))
+            .is_none());
+        assert!(authenticator
+            .authenticate(&sign(
+                Some("test-rsa"),
+                Algorithm::RS256,
+                claims("https://other.example.com", 300)
+            ))
+            .is_none());
+
+        let missing = JwtSettings {
+            jwks_file: PathBuf::from("tests/jwt/missing.json"),
+            ..jwt_settings()
+        };
+        assert!(JwtAuthenticator::from_settings(&missing).is_err());
+    }
+
+    /// The default settings without any authentication method.
+    fn default_settings(allow_anonymous: bool) -> SettingsHandle {
+        let mut settings = Settings::anonymous_defaults();
+        settings.auth.allow_anonymous = allow_anonymous;
+        SettingsHandle::new(settings)
+    }
+
+    #[test]
+    fn test_exempt_paths() {
+        let auth = ApiAuth::new(default_settings(true))
+            .allow_anonymous("/api/v1/health")
+            .skip("/api/v1/admin");
+        assert!(auth.is_exempt("/api/v1/health"));
+        assert!(!auth.is_exempt("/api/v1/health/x"));
+        assert!(auth.is_exempt("/api/v1/admin"));
+        assert!(auth.is_exempt("/api/v1/admin/tentacles"));
+        assert!(!auth.is_exempt("/api/v1/administrators"));
+        assert!(!auth.is_exempt("/api/v1/sources/a/content"));
+        assert!(auth.authenticators().is_empty());
+    }
+
+    #[test]
+    fn test_anonymous() {
+        let is_allowed = |auth: &ApiAuth, path: &str| {
+            let req = actix_web::test::TestRequest::with_uri(path).finish();
+            matches!(auth.start(&req), Ok(Started::Done))
+        };
+        let open = ApiAuth::new(default_settings(true)).allow_anonymous("/api/v1/health");
+        assert!(is_allowed(&open, "/api/v1/sources/a/content"));
+        let closed = ApiAuth::new(default_settings(false)).allow_anonymous("/api/v1/health");
+        assert!(is_allowed(&closed, "/api/v1/health"));
+        assert!(!is_allowed(&closed, "/api/v1/sources/a/content"));
+    }
+}
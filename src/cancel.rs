@@ -0,0 +1,292 @@
+use crate::tentacle::TentacleClientError;
+use actix_net::service::{FnNewService, NewService, NewServiceExt};
+use actix_web::server::IoStream;
+use actix_web::{Extensions, HttpRequest};
+use futures::task::AtomicTask;
+use futures::{Async, Poll, Stream};
+use serde::Serialize;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+#[derive(Default)]
+struct Closed {
+    closed: AtomicBool,
+    /// The connection task, polling the response streams.
+    task: AtomicTask,
+}
+
+/// Whether the client closed its side of a connection, found in the stream
+/// extensions of the requests on it.
+#[derive(Clone, Default)]
+pub struct ClientClosed(Arc<Closed>);
+
+impl ClientClosed {
+    /// The state of the connection the request came in on, none if it did
+    /// not come in on a client connection.
+    pub fn of<S>(req: &HttpRequest<S>) -> Option<ClientClosed> {
+        req.request()
+            .stream_extensions()
+            .and_then(|extensions| extensions.get::<ClientClosed>())
+            .cloned()
+    }
+
+    fn close(&self) {
+        self.0.closed.store(true, Ordering::Relaxed);
+        self.0.task.notify();
+    }
+
+    /// Ends the stream with an error once the client closed the connection.
+    ///
+    /// actix-web keeps a response stream running after the client closed the
+    /// connection until writing to it fails, which for a query waiting on a
+    /// slow tentacle can take minutes. The error makes it drop the stream at
+    /// once, which aborts the tentacle requests. Clients which shut down
+    /// their sending side while waiting for a query are not supported.
+    pub fn abort<S>(&self, stream: S) -> UntilClosed<S> {
+        UntilClosed {
+            stream,
+            closed: self.clone(),
+        }
+    }
+}
+
+pub struct UntilClosed<S> {
+    stream: S,
+    closed: ClientClosed,
+}
+
+impl<S: Stream<Error = TentacleClientError>> Stream for UntilClosed<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        self.closed.0.task.register();
+        if self.closed.0.closed.load(Ordering::Relaxed) {
+            return Err(TentacleClientError::ClientError);
+        }
+        self.stream.poll()
+    }
+}
+
+/// A client connection which notes the end of its input, see `ClientClosed`.
+pub struct ClientConnection<T> {
+    io: T,
+    closed: ClientClosed,
+}
+
+impl<T> ClientConnection<T> {
+    pub fn new(io: T) -> ClientConnection<T> {
+        ClientConnection {
+            io,
+            closed: ClientClosed::default(),
+        }
+    }
+}
+
+impl<T: Read> Read for ClientConnection<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.io.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            self.closed.close();
+        }
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for ClientConnection<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for ClientConnection<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for ClientConnection<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+impl<T: IoStream> IoStream for ClientConnection<T> {
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        IoStream::shutdown(&mut self.io, how)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.io.set_nodelay(nodelay)
+    }
+
+    fn set_linger(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.io.set_linger(dur)
+    }
+
+    fn set_keepalive(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.io.set_keepalive(dur)
+    }
+
+    /// Asked for each request on the connection.
+    fn extensions(&self) -> Option<Rc<Extensions>> {
+        let mut extensions = Extensions::new();
+        extensions.insert(self.closed.clone());
+        Some(Rc::new(extensions))
+    }
+}
+
+/// Accepts plain connections as client connections.
+pub fn plain_acceptor() -> impl NewService<
+    Request = TcpStream,
+    Response = ClientConnection<TcpStream>,
+    Error = (),
+    InitError = (),
+> + Clone {
+    FnNewService::new(|stream| Ok::<_, ()>(ClientConnection::new(stream)))
+}
+
+/// Wraps the connections of an acceptor, e.g. a TLS acceptor, as client connections.
+pub fn client_connections<A>(
+    acceptor: A,
+) -> impl NewService<
+    Request = TcpStream,
+    Response = ClientConnection<A::Response>,
+    Error = A::Error,
+    InitError = A::InitError,
+>
+where
+    A: NewService<Request = TcpStream>,
+{
+    acceptor.map(ClientConnection::new)
+}
+
+#[derive(Default)]
+struct Counters {
+    active: AtomicUsize,
+    completed: AtomicUsize,
+    cancelled: AtomicUsize,
+}
+
+/// Counts the response streams of content queries, shared by all workers.
+#[derive(Clone, Default)]
+pub struct StreamCounters(Arc<Counters>);
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct StreamCounts {
+    pub active: usize,
+    pub completed: usize,
+    /// Streams dropped before their end, i.e. whose client went away.
+    pub cancelled: usize,
+}
+
+impl StreamCounters {
+    pub fn counts(&self) -> StreamCounts {
+        StreamCounts {
+            active: self.0.active.load(Ordering::Relaxed),
+            completed: self.0.completed.load(Ordering::Relaxed),
+            cancelled: self.0.cancelled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts the stream as active until it is dropped.
+    pub fn count<S: Stream>(&self, stream: S) -> Counted<S> {
+        self.0.active.fetch_add(1, Ordering::Relaxed);
+        Counted {
+            stream,
+            counters: self.0.clone(),
+            ended: false,
+        }
+    }
+}
+
+pub struct Counted<S> {
+    stream: S,
+    counters: Arc<Counters>,
+    ended: bool,
+}
+
+impl<S: Stream> Stream for Counted<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let result = self.stream.poll();
+        match result {
+            Ok(Async::Ready(None)) | Err(_) => self.ended = true,
+            _ => (),
+        }
+        result
+    }
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        self.counters.active.fetch_sub(1, Ordering::Relaxed);
+        let ended = if self.ended {
+            &self.counters.completed
+        } else {
+            &self.counters.cancelled
+        };
+        ended.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cancel::*;
+    use futures::{future, stream, Future};
+
+    /// Polls the stream within a task.
+    fn poll<S: Stream>(stream: &mut S) -> Poll<Option<S::Item>, S::Error> {
+        future::poll_fn(|| stream.poll().map(Async::Ready)).wait()
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = StreamCounters::default();
+        let mut completed = counters.count(stream::iter_ok::<_, ()>(vec![1, 2]));
+        let mut cancelled = counters.count(stream::iter_ok::<_, ()>(vec![1, 2]));
+        assert_eq!(2, counters.counts().active);
+
+        assert_eq!(Ok(Async::Ready(Some(1))), completed.poll());
+        assert_eq!(Ok(Async::Ready(Some(2))), completed.poll());
+        assert_eq!(Ok(Async::Ready(None)), completed.poll());
+        assert_eq!(Ok(Async::Ready(Some(1))), cancelled.poll());
+        drop(completed);
+        drop(cancelled);
+        assert_eq!(
+            StreamCounts {
+                active: 0,
+                completed: 1,
+                cancelled: 1
+            },
+            counters.counts()
+        );
+    }
+
+    #[test]
+    fn test_client_connection() {
+        let mut connection = ClientConnection::new(io::Cursor::new(b"GET".to_vec()));
+        let closed = connection.closed.clone();
+        let mut aborted = closed.abort(stream::iter_ok::<_, TentacleClientError>(vec![1, 2]));
+        let mut buf = [0u8; 8];
+        assert_eq!(3, connection.read(&mut buf).unwrap());
+        assert_eq!(0, connection.read(&mut []).unwrap());
+        assert!(matches!(poll(&mut aborted), Ok(Async::Ready(Some(1)))));
+
+        assert_eq!(0, connection.read(&mut buf).unwrap());
+        assert!(poll(&mut aborted).is_err());
+    }
+}
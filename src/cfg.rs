@@ -1,111 +1,437 @@
-use config;
-use config::ConfigError;
-use std::path::Path;
-
-pub fn read_config<S: AsRef<str>>(
-    maybe_filename: &Option<S>,
-) -> Result<config::Config, config::ConfigError> {
-    let mut settings = config::Config::new();
-
-    let defaults = include_bytes!("default_config.yml");
-    settings.merge(config::File::from_str(
-        String::from_utf8_lossy(defaults).as_ref(),
-        config::FileFormat::Yaml,
-    ))?;
-
-    match maybe_filename {
-        Some(filename_ref) => {
-            let filename = filename_ref.as_ref();
-            if !Path::new(filename).exists() {
-                return Err(ConfigError::Message(format!(
-                    "Configuration file {} does not exist",
-                    filename
-                )));
-            } else {
-                settings.merge(config::File::with_name(&filename))?
+use crate::secret::resolve_references;
+use crate::settings::Settings;
+use config::{Config, ConfigError, Source, Value};
+use log::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Shared handle on the current settings, which are swapped on reload.
+#[derive(Clone)]
+pub struct SettingsHandle {
+    current: Arc<RwLock<Arc<Settings>>>,
+}
+
+impl SettingsHandle {
+    pub fn new(settings: Settings) -> SettingsHandle {
+        SettingsHandle {
+            current: Arc::new(RwLock::new(Arc::new(settings))),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Settings> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Runs `f` on the current settings while no reload can swap them, for
+    /// reads which must see state of the same configuration, such as the
+    /// tentacles. `f` must not access the handle itself.
+    pub fn read<R, F: FnOnce(&Settings) -> R>(&self, f: F) -> R {
+        f(&self.current.read().unwrap())
+    }
+
+    /// Replaces the settings. `apply` runs before on the new settings while
+    /// readers are held off, so that state derived from them is swapped at
+    /// the same time. It must not access the handle itself.
+    pub fn replace_with<F: FnOnce(&Settings)>(&self, settings: Settings, apply: F) {
+        let mut current = self.current.write().unwrap();
+        apply(&settings);
+        *current = Arc::new(settings);
+    }
+}
+
+/// Key of the directive which pulls further files into a configuration file.
+const INCLUDE_KEY: &str = "include";
+/// Lists which are concatenated across all configuration files instead of
+/// being replaced by the last file defining them.
+const APPENDED_LISTS: [&str; 2] = ["tentacles", "discovery"];
+/// Extensions of the files picked up when a directory is included.
+const CONFIG_EXTENSIONS: [&str; 4] = ["yml", "yaml", "toml", "json"];
+
+const DEFAULTS_ORIGIN: &str = "defaults";
+const ENVIRONMENT_ORIGIN: &str = "environment";
+
+/// The merged configuration together with the origin of every setting.
+pub struct LayeredConfig {
+    pub config: Config,
+    /// Maps each setting, e.g. `http.bind.port` or `tentacles[2].host`, to
+    /// the file which set it, `defaults` or `environment`.
+    pub origins: BTreeMap<String, String>,
+    /// Settings whose values were resolved from `${env:..}` or `${file:..}`
+    /// references, they must not be printed.
+    pub secrets: BTreeSet<String>,
+}
+
+/// A single source of the configuration.
+struct Layer {
+    origin: String,
+    config: Config,
+}
+
+impl Layer {
+    fn new<T>(origin: String, source: T) -> Result<Layer, ConfigError>
+    where
+        T: Source + Send + Sync + 'static,
+    {
+        let mut config = Config::new();
+        config.merge(source)?;
+        Ok(Layer { origin, config })
+    }
+
+    fn table(&self) -> HashMap<String, Value> {
+        self.config.cache.clone().into_table().unwrap_or_default()
+    }
+
+    /// Removes the include directive and returns the included patterns.
+    fn take_includes(&mut self) -> Result<Vec<String>, ConfigError> {
+        let mut table = self.table();
+        let includes = match table.remove(INCLUDE_KEY) {
+            Some(value) => match value.clone().into_array() {
+                Ok(values) => values
+                    .into_iter()
+                    .map(Value::into_str)
+                    .collect::<Result<_, _>>()?,
+                Err(_) => vec![value.into_str()?],
+            },
+            None => vec![],
+        };
+        self.config.cache = Value::new(Some(&self.origin), table);
+        Ok(includes)
+    }
+}
+
+/// Selects the files of a directory an include pattern refers to.
+enum IncludeMatch {
+    Wildcard { prefix: String, suffix: String },
+    ConfigFiles,
+}
+
+impl IncludeMatch {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            IncludeMatch::Wildcard { prefix, suffix } => {
+                name.len() >= prefix.len() + suffix.len()
+                    && name.starts_with(prefix.as_str())
+                    && name.ends_with(suffix.as_str())
             }
+            IncludeMatch::ConfigFiles => Path::new(name)
+                .extension()
+                .is_some_and(|ext| CONFIG_EXTENSIONS.iter().any(|e| ext == *e)),
+        }
+    }
+}
+
+/// Resolves an include pattern relative to the directory of the including
+/// file. A pattern is either a file, a directory, whose configuration files
+/// are included, or a file name with a single `*` wildcard, e.g. `conf.d/*.yml`.
+/// Matches are sorted by name, hidden files are skipped.
+fn resolve_include(base_dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let path = base_dir.join(pattern);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let (dir, include) = if file_name.contains('*') {
+        let mut parts = file_name.splitn(2, '*');
+        let prefix = parts.next().unwrap_or_default().to_string();
+        let suffix = parts.next().unwrap_or_default().to_string();
+        if suffix.contains('*') {
+            return Err(ConfigError::Message(format!(
+                "Include pattern {} must contain a single wildcard",
+                pattern
+            )));
         }
-        None => &settings,
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        (dir, IncludeMatch::Wildcard { prefix, suffix })
+    } else if path.is_dir() {
+        (path, IncludeMatch::ConfigFiles)
+    } else if path.exists() {
+        return Ok(vec![path]);
+    } else {
+        return Err(ConfigError::Message(format!(
+            "Included configuration file {} does not exist",
+            path.display()
+        )));
     };
-    settings.merge(config::Environment::with_prefix("app"))?;
 
-    Ok(settings)
+    let entries = fs::read_dir(&dir).map_err(|e| {
+        ConfigError::Message(format!(
+            "Failed to read configuration directory {}: {}",
+            dir.display(),
+            e
+        ))
+    })?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            !name.starts_with('.') && include.matches(&name)
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Adds the layer of a configuration file followed by the layers of the
+/// files it includes, depth first.
+fn add_file_layers(
+    path: &Path,
+    chain: &mut Vec<PathBuf>,
+    layers: &mut Vec<Layer>,
+) -> Result<(), ConfigError> {
+    let canonical = path.canonicalize().map_err(|e| {
+        ConfigError::Message(format!(
+            "Failed to read configuration file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    if chain.contains(&canonical) {
+        return Err(ConfigError::Message(format!(
+            "Configuration file {} includes itself",
+            path.display()
+        )));
+    }
+
+    let origin = path.display().to_string();
+    let mut layer = Layer::new(origin, config::File::from(path))?;
+    let includes = layer.take_includes()?;
+    layers.push(layer);
+
+    chain.push(canonical);
+    let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    for pattern in includes {
+        for included in resolve_include(&base_dir, &pattern)? {
+            add_file_layers(&included, chain, layers)?;
+        }
+    }
+    chain.pop();
+    Ok(())
+}
+
+/// Records the origin of every leaf below `path`. Arrays replace earlier
+/// arrays as a whole, so the origins of their old entries are dropped.
+fn record_origins(
+    path: String,
+    value: Value,
+    origin: &str,
+    origins: &mut BTreeMap<String, String>,
+) {
+    if let Ok(table) = value.clone().into_table() {
+        for (key, value) in table {
+            record_origins(format!("{}.{}", path, key), value, origin, origins);
+        }
+    } else if let Ok(values) = value.into_array() {
+        let prefix = format!("{}[", path);
+        let stale: Vec<String> = origins
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            origins.remove(&key);
+        }
+        for (idx, value) in values.into_iter().enumerate() {
+            record_origins(format!("{}[{}]", path, idx), value, origin, origins);
+        }
+    } else {
+        origins.insert(path, origin.to_string());
+    }
+}
+
+/// Resolves the references in all string settings below `path`, recording the
+/// resolved settings as secrets. Returns `None` if nothing was resolved.
+fn resolve_value(
+    path: &str,
+    value: Value,
+    secrets: &mut BTreeSet<String>,
+) -> Result<Option<Value>, ConfigError> {
+    if let Ok(table) = value.clone().into_table() {
+        let mut changed = false;
+        let mut resolved = HashMap::new();
+        for (key, value) in table {
+            let child = format!("{}.{}", path, key);
+            match resolve_value(&child, value.clone(), secrets)? {
+                Some(value) => {
+                    changed = true;
+                    resolved.insert(key, value);
+                }
+                None => {
+                    resolved.insert(key, value);
+                }
+            }
+        }
+        Ok(if changed {
+            Some(Value::new(None, resolved))
+        } else {
+            None
+        })
+    } else if let Ok(values) = value.clone().into_array() {
+        let mut changed = false;
+        let mut resolved = vec![];
+        for (idx, value) in values.into_iter().enumerate() {
+            let child = format!("{}[{}]", path, idx);
+            match resolve_value(&child, value.clone(), secrets)? {
+                Some(value) => {
+                    changed = true;
+                    resolved.push(value);
+                }
+                None => resolved.push(value),
+            }
+        }
+        Ok(if changed {
+            Some(Value::new(None, resolved))
+        } else {
+            None
+        })
+    } else if let Ok(string) = value.into_str() {
+        let resolved = resolve_references(&string)
+            .map_err(|e| ConfigError::Message(format!("Setting {}: {}", path, e)))?;
+        Ok(resolved.map(|resolved| {
+            secrets.insert(path.to_string());
+            Value::new(None, resolved)
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads the configuration from the embedded defaults, the given files and
+/// the `APP_*` environment variables, in this order, later sources taking
+/// precedence.
+///
+/// Each file is directly followed by the files pulled in by its `include`
+/// directive. The lists of `tentacles` and `discovery` providers of all
+/// files are concatenated. References to secrets are resolved last.
+pub fn read_config<S: AsRef<str>>(filenames: &[S]) -> Result<LayeredConfig, ConfigError> {
+    let mut layers = vec![Layer::new(
+        String::from(DEFAULTS_ORIGIN),
+        config::File::from_str(
+            String::from_utf8_lossy(include_bytes!("default_config.yml")).as_ref(),
+            config::FileFormat::Yaml,
+        ),
+    )?];
+
+    for filename in filenames {
+        let filename = filename.as_ref();
+        if !Path::new(filename).exists() {
+            return Err(ConfigError::Message(format!(
+                "Configuration file {} does not exist",
+                filename
+            )));
+        }
+        add_file_layers(Path::new(filename), &mut vec![], &mut layers)?;
+    }
+    layers.push(Layer::new(
+        String::from(ENVIRONMENT_ORIGIN),
+        config::Environment::with_prefix("app"),
+    )?);
+
+    let mut config = Config::new();
+    let mut origins = BTreeMap::new();
+    let mut appended: HashMap<&str, Vec<Value>> = HashMap::new();
+    for layer in layers {
+        for (key, value) in layer.table() {
+            match APPENDED_LISTS.iter().find(|list| **list == key) {
+                Some(list) => {
+                    // a setting which is no list cannot be appended, e.g. an
+                    // APP_TENTACLES variable, the error names where it came from
+                    let values = value.into_array().map_err(|_| {
+                        ConfigError::Message(format!(
+                            "{} from {} must be a list, it is appended to the {} of the other files",
+                            key, layer.origin, key
+                        ))
+                    })?;
+                    let entries = appended.entry(list).or_default();
+                    for value in values {
+                        let path = format!("{}[{}]", key, entries.len());
+                        record_origins(path, value.clone(), &layer.origin, &mut origins);
+                        entries.push(value);
+                    }
+                }
+                None => record_origins(key, value, &layer.origin, &mut origins),
+            }
+        }
+        config.merge(layer.config)?;
+    }
+    for (list, entries) in appended {
+        config.set(list, entries)?;
+    }
+
+    let mut secrets = BTreeSet::new();
+    for (key, value) in config.cache.clone().into_table()? {
+        if let Some(resolved) = resolve_value(&key, value, &mut secrets)? {
+            config.set(&key, resolved)?;
+        }
+    }
+
+    Ok(LayeredConfig {
+        config,
+        origins,
+        secrets,
+    })
+}
+
+/// Reads the configuration and validates it into typed settings.
+pub fn read_settings<S: AsRef<str>>(filenames: &[S]) -> Result<Settings, Box<dyn Error>> {
+    let layered = read_config(filenames)?;
+    for (key, origin) in &layered.origins {
+        debug!("Setting {} from {}", key, origin);
+    }
+    Ok(Settings::from_config(&layered.config)?)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::cfg;
-    use crate::tentacle::*;
+    use crate::cfg::SettingsHandle;
+    use crate::settings::Settings;
+    use crate::testing::tentacle;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
 
     #[test]
     fn test_read_config() {
-        let settings = cfg::read_config(&Some("tests/test.yml")).unwrap();
+        let settings = cfg::read_settings(&["tests/test.yml"]).unwrap();
 
-        assert_eq!(28081, settings.get_int("http.bind.port").unwrap());
-        assert_eq!("127.0.0.1", settings.get_str("http.bind.ip").unwrap());
+        assert_eq!(28081, settings.http.bind.port);
+        assert_eq!("127.0.0.1", settings.http.bind.ip.to_string());
 
-        let tentacles: Vec<TentacleInfo> = settings
-            .get_array("tentacles")
-            .unwrap()
-            .into_iter()
-            .map(|v| TentacleClient::parse_tentacle(v).unwrap())
-            .collect();
+        let tentacles = settings.tentacle_infos();
 
         assert_eq!(
-            vec![
-                TentacleInfo {
-                    name: String::from("tentacle_1"),
-                    host: String::from("localhost"),
-                    port: 18080,
-                    protocol: String::from("http")
-                },
-                TentacleInfo {
-                    name: String::from("tentacle_2"),
-                    host: String::from("localhost"),
-                    port: 18081,
-                    protocol: String::from("http")
-                }
-            ],
+            vec![tentacle("tentacle_1", 18080), tentacle("tentacle_2", 18081)],
             tentacles
         );
     }
 
     #[test]
     fn test_read_config_no_alias() {
-        let settings = cfg::read_config(&Some("tests/test_no_alias.yml")).unwrap();
+        let settings = cfg::read_settings(&["tests/test_no_alias.yml"]).unwrap();
 
-        assert_eq!(28081, settings.get_int("http.bind.port").unwrap());
-        assert_eq!("127.0.0.1", settings.get_str("http.bind.ip").unwrap());
+        assert_eq!(28081, settings.http.bind.port);
+        assert_eq!("127.0.0.1", settings.http.bind.ip.to_string());
 
-        let tentacles: Vec<TentacleInfo> = settings
-            .get_array("tentacles")
-            .unwrap()
-            .into_iter()
-            .map(|v| TentacleClient::parse_tentacle(v).unwrap())
-            .collect();
+        let tentacles = settings.tentacle_infos();
 
         assert_eq!(
-            vec![
-                TentacleInfo {
-                    name: String::from("localhost"),
-                    host: String::from("localhost"),
-                    port: 18080,
-                    protocol: String::from("http")
-                },
-                TentacleInfo {
-                    name: String::from("tentacle_2"),
-                    host: String::from("localhost"),
-                    port: 18081,
-                    protocol: String::from("http")
-                }
-            ],
+            vec![tentacle("localhost", 18080), tentacle("tentacle_2", 18081)],
             tentacles
         );
     }
 
     #[test]
     fn test_read_default_config() {
-        let settings = cfg::read_config::<String>(&None).unwrap();
+        let settings = cfg::read_config::<String>(&[]).unwrap().config;
 
         assert_eq!(8081, settings.get_int("http.bind.port").unwrap());
         assert_eq!("127.0.0.1", settings.get_str("http.bind.ip").unwrap());
@@ -119,4 +445,130 @@ mod tests {
 
         assert!(tentacles.is_empty());
     }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("logtopus-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_appended_list_no_array() {
+        let dir = temp_dir("appended");
+        let main = dir.join("main.yml");
+        fs::write(&main, "tentacles:\n  - host: main\n").unwrap();
+        let override_file = dir.join("override.yml");
+        fs::write(&override_file, "tentacles: other\n").unwrap();
+
+        let files = [main.to_str().unwrap(), override_file.to_str().unwrap()];
+        let message = cfg::read_config(&files).err().unwrap().to_string();
+        assert!(message.starts_with("tentacles from "), "{}", message);
+        assert!(
+            message.contains("override.yml must be a list"),
+            "{}",
+            message
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = temp_dir("include");
+        let main = dir.join("main.yml");
+        fs::write(
+            &main,
+            "include: conf.d/*.yml\nhttp.bind.port: 28081\nauth.allow_anonymous: true\ntentacles:\n  - host: main\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("conf.d/20-b.yml"),
+            "http.bind.port: 28083\ntentacles:\n  - host: b\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("conf.d/10-a.yml"),
+            "http.bind.port: 28082\ntentacles:\n  - host: a1\n  - host: a2\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/.10-a.yml.swp"), "garbage").unwrap();
+        let override_file = dir.join("override.yml");
+        fs::write(&override_file, "http.bind.ip: 0.0.0.0\n").unwrap();
+
+        let files = [main.to_str().unwrap(), override_file.to_str().unwrap()];
+        let layered = cfg::read_config(&files).unwrap();
+        let settings = Settings::from_config(&layered.config).unwrap();
+        let hosts: Vec<String> = settings.tentacles.into_iter().map(|t| t.host).collect();
+        assert_eq!(vec!["main", "a1", "a2", "b"], hosts);
+        assert_eq!(28083, settings.http.bind.port);
+        assert_eq!("0.0.0.0", settings.http.bind.ip.to_string());
+
+        let b = dir.join("conf.d/20-b.yml").display().to_string();
+        assert_eq!(Some(&b), layered.origins.get("http.bind.port"));
+        assert_eq!(Some(&b), layered.origins.get("tentacles[3].host"));
+        assert_eq!(
+            Some(&override_file.display().to_string()),
+            layered.origins.get("http.bind.ip")
+        );
+        assert_eq!(
+            Some(&String::from("defaults")),
+            layered.origins.get("registration.ttl_secs")
+        );
+        assert!(!layered.origins.contains_key("include"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = temp_dir("include-errors");
+        let main = dir.join("main.yml");
+        fs::write(&main, "include: [conf.d]\n").unwrap();
+        fs::write(dir.join("conf.d/loop.yml"), "include: ../main.yml\n").unwrap();
+        assert!(cfg::read_config(&[main.to_str().unwrap()]).is_err());
+
+        fs::write(&main, "include: missing.yml\n").unwrap();
+        assert!(cfg::read_config(&[main.to_str().unwrap()]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_secret_references() {
+        let dir = temp_dir("secrets");
+        let main = dir.join("main.yml");
+        std::env::set_var("LOGTOPUS_TEST_ADMIN_TOKEN", "s3cret");
+        fs::write(
+            &main,
+            "admin.token: ${env:LOGTOPUS_TEST_ADMIN_TOKEN}\nauth.allow_anonymous: true\ntentacles:\n  - host: ${env:LOGTOPUS_TEST_ADMIN_TOKEN}\n",
+        )
+        .unwrap();
+
+        let layered = cfg::read_config(&[main.to_str().unwrap()]).unwrap();
+        assert_eq!("s3cret", layered.config.get_str("admin.token").unwrap());
+        let settings = Settings::from_config(&layered.config).unwrap();
+        assert_eq!("s3cret", settings.tentacles[0].host);
+        let secrets: Vec<&str> = layered.secrets.iter().map(String::as_str).collect();
+        assert_eq!(vec!["admin.token", "tentacles[0].host"], secrets);
+
+        fs::write(&main, "admin.token: ${env:LOGTOPUS_TEST_UNSET}\n").unwrap();
+        assert!(cfg::read_config(&[main.to_str().unwrap()]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replace_with() {
+        let handle = SettingsHandle::new(cfg::read_settings(&["tests/test.yml"]).unwrap());
+        let mut next = (*handle.get()).clone();
+        next.http.bind.port = 28082;
+        let reader = handle.clone();
+        let mut started = None;
+        handle.replace_with(next, |_| {
+            // a read starting while derived state is swapped waits for the new settings
+            started = Some(thread::spawn(move || reader.read(|s| s.http.bind.port)));
+            thread::sleep(Duration::from_millis(50));
+        });
+        assert_eq!(28082, started.unwrap().join().unwrap());
+    }
 }
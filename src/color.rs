@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub const RESET: &str = "\x1b[0m";
+pub const TIMESTAMP: &str = "\x1b[2m";
+pub const ERROR: &str = "\x1b[1;31m";
+
+/// 256 color codes told apart easily on dark and light terminals, none of
+/// them close to the loglevel colors.
+const TENTACLE_PALETTE: [u8; 12] = [39, 208, 141, 43, 205, 178, 75, 167, 114, 139, 37, 214];
+
+/// The `color` parameter of the text output.
+#[derive(Clone, Copy, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Colors for command line clients such as curl, which mostly write to a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+const TERMINAL_CLIENTS: [&str; 3] = ["curl/", "Wget/", "HTTPie/"];
+
+impl ColorMode {
+    pub fn enabled(self, user_agent: Option<&str>) -> bool {
+        match self {
+            ColorMode::Auto => user_agent.is_some_and(|agent| {
+                TERMINAL_CLIENTS
+                    .iter()
+                    .any(|client| agent.starts_with(client))
+            }),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+pub fn level_color(level: &str) -> Option<&'static str> {
+    match level.to_uppercase().as_str() {
+        "FATAL" | "CRITICAL" | "ERROR" => Some(ERROR),
+        "WARN" | "WARNING" => Some("\x1b[33m"),
+        "INFO" => Some("\x1b[32m"),
+        "DEBUG" => Some("\x1b[34m"),
+        "TRACE" => Some("\x1b[35m"),
+        _ => None,
+    }
+}
+
+/// FNV-1a, unlike the std hashers guaranteed to stay the same across releases.
+fn hash(name: &str) -> usize {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    }) as usize
+}
+
+fn code(idx: usize) -> String {
+    format!("\x1b[38;5;{}m", TENTACLE_PALETTE[idx])
+}
+
+/// The colors of the tentacle names. Each tentacle gets the color picked by the
+/// hash of its name or, if an earlier tentacle in name order took it, the next
+/// free one, so the colors stay the same across queries and differ as long as
+/// the palette suffices.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TentacleColors {
+    colors: HashMap<String, String>,
+}
+
+impl TentacleColors {
+    pub fn new(mut names: Vec<String>) -> TentacleColors {
+        names.sort();
+        names.dedup();
+        let mut used = [false; TENTACLE_PALETTE.len()];
+        let mut colors = HashMap::new();
+        for name in names {
+            let preferred = hash(&name) % TENTACLE_PALETTE.len();
+            let idx = (0..TENTACLE_PALETTE.len())
+                .map(|offset| (preferred + offset) % TENTACLE_PALETTE.len())
+                .find(|idx| !used[*idx])
+                .unwrap_or(preferred);
+            used[idx] = true;
+            colors.insert(name, code(idx));
+        }
+        TentacleColors { colors }
+    }
+
+    /// Tentacles added after the colors were picked get the color of their hash.
+    pub fn color(&self, name: &str) -> String {
+        self.colors
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| code(hash(name) % TENTACLE_PALETTE.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::*;
+
+    #[test]
+    fn test_enabled() {
+        assert!(ColorMode::Auto.enabled(Some("curl/7.64.0")));
+        assert!(!ColorMode::Auto.enabled(Some("Mozilla/5.0 (X11; Linux x86_64)")));
+        assert!(!ColorMode::Auto.enabled(None));
+        assert!(ColorMode::Always.enabled(None));
+        assert!(!ColorMode::Never.enabled(Some("curl/7.64.0")));
+    }
+
+    #[test]
+    fn test_tentacle_colors() {
+        let names: Vec<String> = (0..TENTACLE_PALETTE.len())
+            .map(|n| format!("web-{}", n))
+            .collect();
+        let colors = TentacleColors::new(names.clone());
+        let mut distinct: Vec<String> = names.iter().map(|name| colors.color(name)).collect();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(TENTACLE_PALETTE.len(), distinct.len());
+
+        let reversed = TentacleColors::new(names.iter().rev().cloned().collect());
+        assert_eq!(colors, reversed);
+        assert_eq!(
+            code(hash("web-99") % TENTACLE_PALETTE.len()),
+            colors.color("web-99")
+        );
+    }
+
+    #[test]
+    fn test_level_color() {
+        assert_eq!(Some(ERROR), level_color("error"));
+        assert_eq!(Some("\x1b[33m"), level_color("WARN"));
+        assert_eq!(None, level_color("NOTICE"));
+    }
+}
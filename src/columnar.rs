@@ -0,0 +1,74 @@
+use crate::tentacle::LogLine;
+use serde_json::Value;
+
+/// The media type of the columnar JSON stream.
+pub const COLUMNS_JSON: &str = "application/x-logtopus-columns+json";
+
+/// Encodes log lines as JSON lines of value arrays, preceded by an array of
+/// the field names whenever the set of fields changes, e.g.
+///
+/// ```text
+/// ["id","loglevel","message","source","timestamp"]
+/// ["app-web","INFO","started","web-1",1547114400000]
+/// ["id","message","source","timestamp"]
+/// ["app-web","no level","web-1",1547114400001]
+/// ```
+///
+/// Fields without a value are left out rather than sent as null.
+#[derive(Default)]
+pub struct ColumnarEncoder {
+    fields: Vec<String>,
+}
+
+impl ColumnarEncoder {
+    pub fn encode(&mut self, line: &LogLine) -> Vec<u8> {
+        let entries = match serde_json::to_value(line).unwrap() {
+            Value::Object(entries) => entries,
+            _ => unreachable!("a log line is serialized as an object"),
+        };
+        let (fields, values): (Vec<String>, Vec<Value>) = entries
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .unzip();
+        let mut json = vec![];
+        if fields != self.fields {
+            json = serde_json::to_vec(&fields).unwrap();
+            json.push(b'\n');
+            self.fields = fields;
+        }
+        serde_json::to_writer(&mut json, &values).unwrap();
+        json.push(b'\n');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::columnar::*;
+    use crate::testing::log_line;
+
+    fn encode(encoder: &mut ColumnarEncoder, line: &LogLine) -> String {
+        String::from_utf8(encoder.encode(line)).unwrap()
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut encoder = ColumnarEncoder::default();
+        assert_eq!(
+            "[\"id\",\"loglevel\",\"message\",\"source\",\"timestamp\"]\n[\"app-web\",\"INFO\",\"hello\",\"web-1\",1]\n",
+            encode(&mut encoder, &log_line(1, "hello", Some("INFO")))
+        );
+        assert_eq!(
+            "[\"app-web\",\"WARN\",\"hello\",\"web-1\",2]\n",
+            encode(&mut encoder, &log_line(2, "hello", Some("WARN")))
+        );
+        assert_eq!(
+            "[\"id\",\"message\",\"source\",\"timestamp\"]\n[\"app-web\",\"hello\",\"web-1\",3]\n",
+            encode(&mut encoder, &log_line(3, "hello", None))
+        );
+        assert_eq!(
+            "[\"id\",\"loglevel\",\"message\",\"source\",\"timestamp\"]\n[\"app-web\",\"INFO\",\"hello\",\"web-1\",4]\n",
+            encode(&mut encoder, &log_line(4, "hello", Some("INFO")))
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use brotli2::write::BrotliEncoder;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use futures::{Async, Poll, Stream};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::Write;
+
+/// Compressed data is sent at the latest once this much was written since the
+/// last flush, so exports need not wait for the end of the query.
+const FLUSH_BYTES: usize = 64 * 1024;
+
+/// The content codings of streamed responses.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentCoding {
+    Zstd,
+    #[serde(rename = "br")]
+    Brotli,
+    Gzip,
+}
+
+impl ContentCoding {
+    /// The name in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentCoding::Zstd => "zstd",
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gzip",
+        }
+    }
+}
+
+fn default_algorithms() -> Vec<ContentCoding> {
+    vec![
+        ContentCoding::Zstd,
+        ContentCoding::Brotli,
+        ContentCoding::Gzip,
+    ]
+}
+
+fn default_gzip_level() -> u32 {
+    6
+}
+
+fn default_brotli_level() -> u32 {
+    4
+}
+
+fn default_zstd_level() -> i32 {
+    3
+}
+
+/// Compression of the streamed query responses.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionSettings {
+    /// The codings offered, preferred in this order if a client accepts
+    /// several equally. Without any responses are not compressed.
+    #[serde(default = "default_algorithms")]
+    pub algorithms: Vec<ContentCoding>,
+    #[serde(default = "default_gzip_level")]
+    pub gzip_level: u32,
+    #[serde(default = "default_brotli_level")]
+    pub brotli_level: u32,
+    #[serde(default = "default_zstd_level")]
+    pub zstd_level: i32,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> CompressionSettings {
+        CompressionSettings {
+            algorithms: default_algorithms(),
+            gzip_level: default_gzip_level(),
+            brotli_level: default_brotli_level(),
+            zstd_level: default_zstd_level(),
+        }
+    }
+}
+
+impl CompressionSettings {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.gzip_level > 9 {
+            errors.push(String::from("gzip_level must be between 0 and 9"));
+        }
+        if self.brotli_level > 11 {
+            errors.push(String::from("brotli_level must be between 0 and 11"));
+        }
+        if !(1..=22).contains(&self.zstd_level) {
+            errors.push(String::from("zstd_level must be between 1 and 22"));
+        }
+        errors
+    }
+
+    /// The coding of a response to a request with the `Accept-Encoding`
+    /// header, the one with the highest quality, none if the client accepts
+    /// none of the configured codings.
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<ContentCoding> {
+        let mut best: Option<(ContentCoding, f32)> = None;
+        for coding in &self.algorithms {
+            if let Some(q) = quality(accept_encoding, coding.name()) {
+                if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                    best = Some((*coding, q));
+                }
+            }
+        }
+        best.map(|(coding, _)| coding)
+    }
+}
+
+/// The quality the `Accept-Encoding` header gives the encoding, none if it is
+/// not listed.
+fn quality(accept_encoding: &str, encoding: &str) -> Option<f32> {
+    accept_encoding.split(',').find_map(|entry| {
+        let mut params = entry.split(';').map(str::trim);
+        if params.next() != Some(encoding) {
+            return None;
+        }
+        Some(
+            params
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(1.0, |q| q.parse().unwrap_or(0.0)),
+        )
+    })
+}
+
+/// Whether the `Accept-Encoding` header allows the encoding.
+pub fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    quality(accept_encoding, encoding).is_some_and(|q| q > 0.0)
+}
+
+enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Brotli(BrotliEncoder<Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(coding: ContentCoding, settings: &CompressionSettings) -> io::Result<Encoder> {
+        Ok(match coding {
+            ContentCoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                vec![],
+                settings.zstd_level,
+            )?),
+            ContentCoding::Brotli => {
+                Encoder::Brotli(BrotliEncoder::new(vec![], settings.brotli_level))
+            }
+            ContentCoding::Gzip => Encoder::Gzip(GzEncoder::new(
+                vec![],
+                flate2::Compression::new(settings.gzip_level),
+            )),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Zstd(encoder) => encoder,
+            Encoder::Brotli(encoder) => encoder,
+            Encoder::Gzip(encoder) => encoder,
+        }
+    }
+
+    /// Flushes the compressed data, which decompresses completely then.
+    fn flush(&mut self) -> io::Result<Bytes> {
+        self.writer().flush()?;
+        let output = match self {
+            Encoder::Zstd(encoder) => encoder.get_mut(),
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        Ok(Bytes::from(match self {
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            Encoder::Brotli(encoder) => encoder.finish()?,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+        }))
+    }
+}
+
+/// Compresses a streamed body. The chunks ready at once are compressed
+/// together and flushed when the stream has to wait for more, so a live tail
+/// gets every line as soon as it arrives while a query over past lines is
+/// compressed in larger pieces.
+pub struct Compressed<S> {
+    inner: S,
+    encoder: Option<Encoder>,
+    pending: usize,
+}
+
+impl<S> Compressed<S> {
+    pub fn new(
+        inner: S,
+        coding: ContentCoding,
+        settings: &CompressionSettings,
+    ) -> io::Result<Compressed<S>> {
+        Ok(Compressed {
+            inner,
+            encoder: Some(Encoder::new(coding, settings)?),
+            pending: 0,
+        })
+    }
+}
+
+impl<S> Stream for Compressed<S>
+where
+    S: Stream<Item = Bytes>,
+    S::Error: From<io::Error>,
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            let encoder = match &mut self.encoder {
+                Some(encoder) => encoder,
+                None => return Ok(Async::Ready(None)),
+            };
+            let flush = match self.inner.poll()? {
+                Async::Ready(Some(chunk)) => {
+                    encoder.writer().write_all(&chunk)?;
+                    self.pending += chunk.len();
+                    self.pending >= FLUSH_BYTES
+                }
+                Async::Ready(None) => {
+                    let output = self.encoder.take().unwrap().finish()?;
+                    return Ok(Async::Ready(Some(output)));
+                }
+                Async::NotReady if self.pending > 0 => true,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            if flush {
+                self.pending = 0;
+                let output = encoder.flush()?;
+                // an empty chunk would end a chunked response
+                if !output.is_empty() {
+                    return Ok(Async::Ready(Some(output)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::*;
+    use futures::sync::mpsc;
+    use futures::{future, Future};
+
+    fn settings() -> CompressionSettings {
+        CompressionSettings::default()
+    }
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts("gzip, deflate, br", "br"));
+        assert!(accepts("br;q=0.8, gzip", "br"));
+        assert!(!accepts("br;q=0, gzip", "br"));
+        assert!(!accepts("gzip", "br"));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let settings = settings();
+        assert_eq!(
+            Some(ContentCoding::Zstd),
+            settings.negotiate("gzip, deflate, br, zstd")
+        );
+        assert_eq!(
+            Some(ContentCoding::Gzip),
+            settings.negotiate("br;q=0.5, gzip")
+        );
+        assert_eq!(
+            Some(ContentCoding::Brotli),
+            settings.negotiate("zstd;q=0, br, gzip")
+        );
+        assert_eq!(None, settings.negotiate("deflate, identity"));
+        assert_eq!(None, settings.negotiate(""));
+
+        let gzip_only = CompressionSettings {
+            algorithms: vec![ContentCoding::Gzip],
+            ..settings
+        };
+        assert_eq!(Some(ContentCoding::Gzip), gzip_only.negotiate("zstd, gzip"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(settings().validate().is_empty());
+        let invalid = CompressionSettings {
+            gzip_level: 10,
+            brotli_level: 12,
+            zstd_level: 0,
+            ..settings()
+        };
+        assert_eq!(3, invalid.validate().len());
+    }
+
+    enum Decoder {
+        Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+        Brotli(brotli2::write::BrotliDecoder<Vec<u8>>),
+        Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    }
+
+    impl Decoder {
+        fn new(coding: ContentCoding) -> Decoder {
+            match coding {
+                ContentCoding::Zstd => {
+                    Decoder::Zstd(zstd::stream::write::Decoder::new(vec![]).unwrap())
+                }
+                ContentCoding::Brotli => {
+                    Decoder::Brotli(brotli2::write::BrotliDecoder::new(vec![]))
+                }
+                ContentCoding::Gzip => Decoder::Gzip(flate2::write::GzDecoder::new(vec![])),
+            }
+        }
+
+        /// Decompresses a chunk, returns what it completed.
+        fn decode(&mut self, chunk: &[u8]) -> String {
+            let output = match self {
+                Decoder::Zstd(decoder) => {
+                    decoder.write_all(chunk).unwrap();
+                    decoder.flush().unwrap();
+                    decoder.get_mut()
+                }
+                Decoder::Brotli(decoder) => {
+                    decoder.write_all(chunk).unwrap();
+                    decoder.flush().unwrap();
+                    decoder.get_mut()
+                }
+                Decoder::Gzip(decoder) => {
+                    decoder.write_all(chunk).unwrap();
+                    decoder.flush().unwrap();
+                    decoder.get_mut()
+                }
+            };
+            String::from_utf8(std::mem::take(output)).unwrap()
+        }
+    }
+
+    fn ready(poll: Poll<Option<Bytes>, io::Error>) -> Bytes {
+        match poll.unwrap() {
+            Async::Ready(Some(chunk)) => chunk,
+            other => panic!("expected a chunk, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_flushed_when_waiting() {
+        for coding in &[
+            ContentCoding::Zstd,
+            ContentCoding::Brotli,
+            ContentCoding::Gzip,
+        ] {
+            let (tx, rx) = mpsc::unbounded::<Bytes>();
+            let rx = rx.map_err(|()| io::Error::from(io::ErrorKind::Other));
+            let mut compressed = Compressed::new(rx, *coding, &settings()).unwrap();
+            let mut decoder = Decoder::new(*coding);
+            future::lazy(|| {
+                tx.unbounded_send(Bytes::from("first line\n")).unwrap();
+                let chunk = ready(compressed.poll());
+                assert_eq!("first line\n", decoder.decode(&chunk));
+                assert_eq!(Async::NotReady, compressed.poll().unwrap());
+
+                tx.unbounded_send(Bytes::from("second line\n")).unwrap();
+                tx.unbounded_send(Bytes::from("third line\n")).unwrap();
+                let chunk = ready(compressed.poll());
+                assert_eq!("second line\nthird line\n", decoder.decode(&chunk));
+
+                drop(tx);
+                let chunk = ready(compressed.poll());
+                assert_eq!("", decoder.decode(&chunk));
+                assert_eq!(Async::Ready(None), compressed.poll().unwrap());
+                Ok::<(), ()>(())
+            })
+            .wait()
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_flushed_when_large() {
+        let line = "x".repeat(1000) + "\n";
+        let lines = (0..200).map(|_| Ok::<_, io::Error>(Bytes::from(line.as_str())));
+        let compressed = Compressed::new(
+            futures::stream::iter_result(lines),
+            ContentCoding::Gzip,
+            &settings(),
+        )
+        .unwrap();
+        let chunks: Vec<Bytes> = compressed.collect().wait().unwrap();
+        assert!(chunks.len() > 2);
+        let mut decoder = Decoder::new(ContentCoding::Gzip);
+        let decoded: String = chunks.iter().map(|chunk| decoder.decode(chunk)).collect();
+        assert_eq!(line.repeat(200), decoded);
+    }
+}
@@ -1,2 +1,2 @@
-pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-pub const AUTHORS: &'static str = env!("CARGO_PKG_AUTHORS");
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
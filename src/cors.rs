@@ -0,0 +1,178 @@
+use actix_web::http::header::HeaderName;
+use actix_web::http::Method;
+use actix_web::middleware::cors::Cors;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+const ANY_ORIGIN: &str = "*";
+
+fn default_methods() -> Vec<String> {
+    vec!["GET", "POST", "PUT", "DELETE"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+fn default_headers() -> Vec<String> {
+    vec!["Accept", "Authorization", "Content-Type"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+/// Cross-origin access to the API, e.g. for a web UI served from another origin.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CorsSettings {
+    /// Origins like `https://pallium.example.com`, `*` allows any origin.
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "default_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Allows cookies and `Authorization` headers in cross-origin requests.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache the answer of a preflight request.
+    pub max_age_secs: Option<u32>,
+}
+
+impl CorsSettings {
+    /// Returns the messages of all invalid settings.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.allowed_origins.is_empty() {
+            errors.push(String::from("allowed_origins must not be empty"));
+        }
+        if self.allow_credentials && self.allowed_origins.iter().any(|o| o == ANY_ORIGIN) {
+            errors.push(String::from(
+                "allow_credentials requires explicit allowed_origins instead of *",
+            ));
+        }
+        for method in &self.allowed_methods {
+            if Method::from_str(method).is_err() {
+                errors.push(format!("invalid method {}", method));
+            }
+        }
+        for header in &self.allowed_headers {
+            if HeaderName::from_str(header).is_err() {
+                errors.push(format!("invalid header {}", header));
+            }
+        }
+        errors
+    }
+
+    /// Builds the middleware, the settings must have been validated.
+    pub fn middleware(&self) -> Cors {
+        let mut builder = Cors::build();
+        if self.allowed_origins.iter().any(|o| o == ANY_ORIGIN) {
+            builder.send_wildcard();
+        } else {
+            for origin in &self.allowed_origins {
+                builder.allowed_origin(origin);
+            }
+        }
+        builder
+            .allowed_methods(self.allowed_methods.iter().map(String::as_str))
+            .allowed_headers(self.allowed_headers.iter().map(String::as_str));
+        if self.allow_credentials {
+            builder.supports_credentials();
+        }
+        if let Some(max_age_secs) = self.max_age_secs {
+            builder.max_age(max_age_secs as usize);
+        }
+        builder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cors::*;
+    use actix_web::test::TestServer;
+    use actix_web::{http, HttpMessage, HttpResponse};
+
+    fn settings(origins: &[&str]) -> CorsSettings {
+        CorsSettings {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allowed_methods: default_methods(),
+            allowed_headers: default_headers(),
+            allow_credentials: true,
+            max_age_secs: Some(600),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(settings(&["https://ui.example.com"]).validate().is_empty());
+        assert_eq!(
+            vec!["allow_credentials requires explicit allowed_origins instead of *"],
+            settings(&["*"]).validate()
+        );
+        assert_eq!(
+            vec!["allowed_origins must not be empty"],
+            settings(&[]).validate()
+        );
+        let invalid = CorsSettings {
+            allowed_methods: vec![String::from("GET POST")],
+            allowed_headers: vec![String::from("X Bad")],
+            ..settings(&["https://ui.example.com"])
+        };
+        assert_eq!(
+            vec!["invalid method GET POST", "invalid header X Bad"],
+            invalid.validate()
+        );
+    }
+
+    #[test]
+    fn test_preflight() {
+        let cors = settings(&["https://ui.example.com"]);
+        let mut srv = TestServer::new(move |app| {
+            app.middleware(cors.middleware())
+                .resource("/sources/{id}/content", |r| {
+                    r.get().f(|_| HttpResponse::Ok());
+                    r.f(|_| HttpResponse::MethodNotAllowed());
+                });
+        });
+
+        let request = srv
+            .client(http::Method::OPTIONS, "/sources/app/content")
+            .header("Origin", "https://ui.example.com")
+            .header("Access-Control-Request-Method", "GET")
+            .header("Access-Control-Request-Headers", "authorization")
+            .finish()
+            .unwrap();
+        let response = srv.execute(request.send()).unwrap();
+        assert!(response.status().is_success());
+        let headers = response.headers();
+        assert_eq!(
+            "https://ui.example.com",
+            headers[http::header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert_eq!(
+            "true",
+            headers[http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS]
+        );
+        assert_eq!("600", headers[http::header::ACCESS_CONTROL_MAX_AGE]);
+
+        let request = srv
+            .client(http::Method::OPTIONS, "/sources/app/content")
+            .header("Origin", "https://evil.example.com")
+            .header("Access-Control-Request-Method", "GET")
+            .finish()
+            .unwrap();
+        let response = srv.execute(request.send()).unwrap();
+        assert_eq!(http::StatusCode::BAD_REQUEST, response.status());
+
+        let request = srv
+            .get()
+            .uri(srv.url("/sources/app/content"))
+            .header("Origin", "https://ui.example.com")
+            .finish()
+            .unwrap();
+        let response = srv.execute(request.send()).unwrap();
+        assert_eq!(
+            "https://ui.example.com",
+            response.headers()[http::header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+    }
+}
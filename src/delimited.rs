@@ -0,0 +1,210 @@
+use crate::tentacle::LogLine;
+use crate::text_format::parse_tz;
+use chrono::TimeZone;
+use chrono_tz::Tz;
+
+/// Timestamps as spreadsheets recognize them.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+const COLUMNS: [(&str, Column); 6] = [
+    ("timestamp", Column::Timestamp),
+    ("timestamp_ms", Column::TimestampMs),
+    ("level", Column::Level),
+    ("source", Column::Source),
+    ("tentacle", Column::Tentacle),
+    ("message", Column::Message),
+];
+
+const DEFAULT_COLUMNS: [Column; 5] = [
+    Column::Timestamp,
+    Column::Level,
+    Column::Source,
+    Column::Tentacle,
+    Column::Message,
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Delimiter {
+    Comma,
+    Tab,
+}
+
+impl Delimiter {
+    fn char(self) -> char {
+        match self {
+            Delimiter::Comma => ',',
+            Delimiter::Tab => '\t',
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Delimiter::Comma => "text/csv",
+            Delimiter::Tab => "text/tab-separated-values",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Column {
+    Timestamp,
+    TimestampMs,
+    Level,
+    Source,
+    Tentacle,
+    Message,
+}
+
+fn parse_column(name: &str) -> Result<Column, String> {
+    COLUMNS
+        .iter()
+        .find(|(column, _)| *column == name)
+        .map(|(_, column)| *column)
+        .ok_or_else(|| {
+            let names: Vec<&str> = COLUMNS.iter().map(|(column, _)| *column).collect();
+            format!(
+                "unknown column {}, expected one of {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+fn column_name(column: Column) -> &'static str {
+    COLUMNS.iter().find(|(_, c)| *c == column).unwrap().0
+}
+
+/// CSV or TSV rows of log lines. Fields containing the delimiter, quotes or
+/// line breaks are quoted as in RFC 4180, which spreadsheets also accept in
+/// tab separated files, and rows end with CRLF.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DelimitedFormat {
+    delimiter: Delimiter,
+    columns: Vec<Column>,
+    tz: Tz,
+}
+
+impl DelimitedFormat {
+    /// Reads the comma separated `columns` and the `tz` request parameters.
+    pub fn from_request(
+        delimiter: Delimiter,
+        columns: Option<&str>,
+        tz: Option<&str>,
+    ) -> Result<DelimitedFormat, String> {
+        let columns = match columns {
+            None => DEFAULT_COLUMNS.to_vec(),
+            Some(columns) => columns
+                .split(',')
+                .map(|name| parse_column(name.trim()))
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(DelimitedFormat {
+            delimiter,
+            columns,
+            tz: parse_tz(tz)?,
+        })
+    }
+
+    fn row<'a, I: IntoIterator<Item = &'a str>>(&self, fields: I) -> String {
+        let delimiter = self.delimiter.char();
+        let mut row = String::new();
+        for (idx, field) in fields.into_iter().enumerate() {
+            if idx > 0 {
+                row.push(delimiter);
+            }
+            if field.contains([delimiter, '"', '\n', '\r']) {
+                row.push('"');
+                row.push_str(&field.replace('"', "\"\""));
+                row.push('"');
+            } else {
+                row.push_str(field);
+            }
+        }
+        row.push_str("\r\n");
+        row
+    }
+
+    pub fn header(&self) -> String {
+        self.row(self.columns.iter().map(|column| column_name(*column)))
+    }
+
+    pub fn format(&self, line: &LogLine) -> String {
+        let fields: Vec<String> = self
+            .columns
+            .iter()
+            .map(|column| match column {
+                Column::Timestamp => match self.tz.timestamp_millis_opt(line.timestamp) {
+                    chrono::LocalResult::Single(ts) => ts.format(TIMESTAMP_FORMAT).to_string(),
+                    _ => line.timestamp.to_string(),
+                },
+                Column::TimestampMs => line.timestamp.to_string(),
+                Column::Level => line.loglevel.clone().unwrap_or_default(),
+                Column::Source => line.id.clone(),
+                Column::Tentacle => line.source.clone(),
+                Column::Message => line.message.clone(),
+            })
+            .collect();
+        self.row(fields.iter().map(String::as_str))
+    }
+
+    /// The error ending a stream, a row with the error in the first column.
+    pub fn format_error(&self, error: &str) -> String {
+        let error = format!("error: {}", error);
+        let mut fields = vec![""; self.columns.len()];
+        fields[0] = &error;
+        self.row(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delimited::*;
+    use crate::testing::log_line;
+
+    const TIMESTAMP: i64 = 1_553_995_800_250;
+
+    #[test]
+    fn test_csv() {
+        let csv = DelimitedFormat::from_request(Delimiter::Comma, None, None).unwrap();
+        assert_eq!("timestamp,level,source,tentacle,message\r\n", csv.header());
+        assert_eq!(
+            "2019-03-31 01:30:00.250,WARN,app-web,web-1,disk full\r\n",
+            csv.format(&log_line(TIMESTAMP, "disk full", Some("WARN")))
+        );
+        assert_eq!(
+            "2019-03-31 01:30:00.250,WARN,app-web,web-1,\"a, \"\"b\"\"\nc\"\r\n",
+            csv.format(&log_line(TIMESTAMP, "a, \"b\"\nc", Some("WARN")))
+        );
+        assert_eq!("error: killed,,,,\r\n", csv.format_error("killed"));
+    }
+
+    #[test]
+    fn test_tsv_columns() {
+        let tsv = DelimitedFormat::from_request(
+            Delimiter::Tab,
+            Some("message, timestamp_ms,timestamp"),
+            Some("Europe/Berlin"),
+        )
+        .unwrap();
+        assert_eq!("message\ttimestamp_ms\ttimestamp\r\n", tsv.header());
+        assert_eq!(
+            "\"a\tb\"\t1553995800250\t2019-03-31 03:30:00.250\r\n",
+            tsv.format(&log_line(TIMESTAMP, "a\tb", Some("WARN")))
+        );
+        assert_eq!(
+            "a,b\t1553995800250\t2019-03-31 03:30:00.250\r\n",
+            tsv.format(&log_line(TIMESTAMP, "a,b", Some("WARN")))
+        );
+    }
+
+    #[test]
+    fn test_invalid_columns() {
+        assert_eq!(
+            Err(String::from(
+                "unknown column host, expected one of timestamp, timestamp_ms, level, source, tentacle, message"
+            )),
+            DelimitedFormat::from_request(Delimiter::Comma, Some("message,host"), None)
+        );
+        assert!(DelimitedFormat::from_request(Delimiter::Comma, Some(""), None).is_err());
+    }
+}
@@ -0,0 +1,456 @@
+use crate::settings::{parse_tentacle, DiscoverySettings};
+use crate::tentacle::{TentacleAuth, TentacleInfo};
+use crate::tentacle_set::TentacleSet;
+use crate::tentacle_tls::TentacleTls;
+use config::Config;
+use futures::sync::oneshot;
+use futures::{future, Future, Stream};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::executor::current_thread::spawn;
+use tokio::timer::Interval;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::AsyncResolver;
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    File(String),
+    Parse(String),
+    Lookup(String),
+}
+
+impl Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiscoveryError::File(msg) => write!(f, "Failed to read file: {}", msg),
+            DiscoveryError::Parse(msg) => write!(f, "Failed to parse tentacles: {}", msg),
+            DiscoveryError::Lookup(msg) => write!(f, "DNS lookup failed: {}", msg),
+        }
+    }
+}
+
+pub type DiscoveryFuture =
+    Box<dyn Future<Item = Option<Vec<TentacleInfo>>, Error = DiscoveryError>>;
+
+/// A source of tentacles which is asked periodically for the tentacles it knows.
+pub trait DiscoveryProvider {
+    /// Unique name of the provider, used as key for its group in the tentacle set.
+    fn name(&self) -> String;
+
+    fn interval(&self) -> Duration;
+
+    /// Resolves to the currently known tentacles, or `None` if nothing changed
+    /// since the last call.
+    fn discover(&mut self) -> DiscoveryFuture;
+}
+
+/// Watches a YAML or JSON file with a `tentacles` list in the same format as
+/// the main configuration.
+pub struct FileDiscovery {
+    path: PathBuf,
+    interval: Duration,
+    last_modified: Option<SystemTime>,
+}
+
+impl FileDiscovery {
+    pub fn new(path: PathBuf, interval: Duration) -> FileDiscovery {
+        FileDiscovery {
+            path,
+            interval,
+            last_modified: None,
+        }
+    }
+
+    fn read_tentacles(&self) -> Result<Vec<TentacleInfo>, DiscoveryError> {
+        let mut file = Config::new();
+        file.merge(config::File::from(self.path.as_path()))
+            .map_err(|e| DiscoveryError::Parse(e.to_string()))?;
+        file.get_array("tentacles")
+            .map_err(|e| DiscoveryError::Parse(e.to_string()))?
+            .into_iter()
+            .enumerate()
+            .map(|(idx, v)| {
+                parse_tentacle(v, &format!("tentacles[{}]", idx))
+                    .map_err(|e| DiscoveryError::Parse(e.to_string()))
+            })
+            .collect()
+    }
+}
+
+impl DiscoveryProvider for FileDiscovery {
+    fn name(&self) -> String {
+        format!("file:{}", self.path.display())
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn discover(&mut self) -> DiscoveryFuture {
+        let modified = match self.path.metadata().and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => return Box::new(future::err(DiscoveryError::File(e.to_string()))),
+        };
+        if self.last_modified == Some(modified) {
+            return Box::new(future::ok(None));
+        }
+        let result = self.read_tentacles();
+        if result.is_ok() {
+            self.last_modified = Some(modified);
+        }
+        Box::new(future::result(result.map(Some)))
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsRecord {
+    #[default]
+    Srv,
+    A,
+}
+
+/// Resolves tentacles from DNS, either from SRV records providing host and
+/// port, or from A/AAAA records combined with a configured port. All resolved
+/// tentacles share the configured protocol, credentials and TLS settings.
+pub struct DnsDiscovery {
+    name: String,
+    record: DnsRecord,
+    port: i64,
+    protocol: String,
+    auth: Option<TentacleAuth>,
+    tls: Option<TentacleTls>,
+    nameserver: Option<SocketAddr>,
+    interval: Duration,
+    resolver: Option<AsyncResolver>,
+}
+
+impl DnsDiscovery {
+    pub fn new(
+        name: String,
+        record: DnsRecord,
+        port: i64,
+        protocol: String,
+        nameserver: Option<SocketAddr>,
+        interval: Duration,
+    ) -> DnsDiscovery {
+        DnsDiscovery {
+            name,
+            record,
+            port,
+            protocol,
+            auth: None,
+            tls: None,
+            nameserver,
+            interval,
+            resolver: None,
+        }
+    }
+
+    /// Sends the given credentials and TLS settings to the resolved tentacles.
+    pub fn with_transport(
+        mut self,
+        auth: Option<TentacleAuth>,
+        tls: Option<TentacleTls>,
+    ) -> DnsDiscovery {
+        self.auth = auth;
+        self.tls = tls;
+        self
+    }
+
+    fn resolver(&mut self) -> Result<AsyncResolver, DiscoveryError> {
+        if let Some(resolver) = &self.resolver {
+            return Ok(resolver.clone());
+        }
+        let (config, mut opts) = match self.nameserver {
+            Some(addr) => (
+                ResolverConfig::from_parts(
+                    None,
+                    vec![],
+                    NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port()),
+                ),
+                ResolverOpts::default(),
+            ),
+            None => trust_dns_resolver::system_conf::read_system_conf()
+                .map_err(|e| DiscoveryError::Lookup(e.to_string()))?,
+        };
+        // never cache answers longer than one discovery round
+        opts.positive_max_ttl = Some(self.interval);
+        opts.negative_max_ttl = Some(self.interval);
+        let (resolver, background) = AsyncResolver::new(config, opts);
+        spawn(background);
+        self.resolver = Some(resolver.clone());
+        Ok(resolver)
+    }
+}
+
+impl DiscoveryProvider for DnsDiscovery {
+    fn name(&self) -> String {
+        format!("dns:{}", self.name)
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn discover(&mut self) -> DiscoveryFuture {
+        let resolver = match self.resolver() {
+            Ok(resolver) => resolver,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let protocol = self.protocol.clone();
+        let auth = self.auth.clone();
+        let tls = self.tls.clone();
+        let tentacle = move |host: String, port: i64| TentacleInfo {
+            name: host.clone(),
+            host,
+            port,
+            protocol: protocol.clone(),
+            labels: BTreeMap::new(),
+            auth: auth.clone(),
+            tls: tls.clone(),
+            encodings: vec![],
+        };
+        match self.record {
+            DnsRecord::Srv => Box::new(
+                resolver
+                    .lookup_srv(self.name.as_str())
+                    .map(move |lookup| {
+                        Some(
+                            lookup
+                                .iter()
+                                .map(|srv| {
+                                    let target = srv.target().to_utf8();
+                                    let host = target.trim_end_matches('.').to_string();
+                                    tentacle(host, i64::from(srv.port()))
+                                })
+                                .collect(),
+                        )
+                    })
+                    .map_err(|e| DiscoveryError::Lookup(e.to_string())),
+            ),
+            DnsRecord::A => {
+                let port = self.port;
+                Box::new(
+                    resolver
+                        .lookup_ip(self.name.as_str())
+                        .map(move |lookup| {
+                            Some(
+                                lookup
+                                    .iter()
+                                    .map(|ip| tentacle(ip.to_string(), port))
+                                    .collect(),
+                            )
+                        })
+                        .map_err(|e| DiscoveryError::Lookup(e.to_string())),
+                )
+            }
+        }
+    }
+}
+
+/// Creates the provider for a discovery entry of the settings.
+pub fn provider(settings: &DiscoverySettings) -> Box<dyn DiscoveryProvider> {
+    match settings {
+        DiscoverySettings::File {
+            path,
+            interval_secs,
+        } => Box::new(FileDiscovery::new(
+            path.clone(),
+            Duration::from_secs(*interval_secs),
+        )),
+        DiscoverySettings::Dns(dns) => Box::new(
+            DnsDiscovery::new(
+                dns.name.clone(),
+                dns.record,
+                i64::from(dns.port),
+                dns.protocol.clone(),
+                dns.nameserver,
+                Duration::from_secs(dns.interval_secs),
+            )
+            .with_transport(dns.auth.clone(), dns.tls.clone()),
+        ),
+    }
+}
+
+pub fn providers(settings: &[DiscoverySettings]) -> Vec<Box<dyn DiscoveryProvider>> {
+    settings.iter().map(provider).collect()
+}
+
+fn run_provider(
+    mut provider: Box<dyn DiscoveryProvider>,
+    tentacles: TentacleSet,
+    stop: oneshot::Receiver<()>,
+) -> impl Future<Item = (), Error = ()> {
+    let name = provider.name();
+    let rounds = Interval::new(Instant::now(), provider.interval())
+        .map_err(move |e| error!("Discovery timer of {} failed: {}", name, e))
+        .for_each(move |_| {
+            let name = provider.name();
+            let tentacles = tentacles.clone();
+            provider.discover().then(move |result| {
+                match result {
+                    Ok(Some(found)) => {
+                        info!("Discovery {} found {} tentacle(s)", name, found.len());
+                        tentacles.update(&name, found);
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Discovery {} failed, keeping last result: {}", name, e),
+                }
+                Ok(())
+            })
+        });
+    // the stop signal completes with an error once its sender is dropped
+    rounds.select2(stop).then(|_| Ok(()))
+}
+
+/// Handle on the running discovery providers.
+pub struct Discovery {
+    tentacles: TentacleSet,
+    running: Vec<(String, oneshot::Sender<()>)>,
+}
+
+impl Discovery {
+    pub fn new(tentacles: TentacleSet) -> Discovery {
+        Discovery {
+            tentacles,
+            running: vec![],
+        }
+    }
+
+    /// Starts the periodic discovery of each provider on the current arbiter,
+    /// replacing the providers started before. Tentacles of providers which
+    /// are no longer configured are removed from the tentacle set.
+    pub fn start(&mut self, providers: Vec<Box<dyn DiscoveryProvider>>) {
+        let names: Vec<String> = providers.iter().map(|p| p.name()).collect();
+        for (name, _stop) in self.running.drain(..) {
+            if !names.contains(&name) {
+                self.tentacles.remove_group(&name);
+            }
+        }
+        for provider in providers {
+            let (stop_tx, stop_rx) = oneshot::channel();
+            self.running.push((provider.name(), stop_tx));
+            actix::Arbiter::spawn(run_provider(provider, self.tentacles.clone(), stop_rx));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::*;
+    use crate::testing::tentacle;
+    use std::fs;
+    use std::net::UdpSocket;
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+    use trust_dns_resolver::proto::op::{Message, MessageType};
+    use trust_dns_resolver::proto::rr::rdata::SRV;
+    use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+
+    #[test]
+    fn test_providers() {
+        let mut config = Config::new();
+        config
+            .merge(config::File::from_str(
+                "discovery:\n  - type: file\n    path: /tmp/tentacles.yml\n  - type: dns\n    name: _tentacle._tcp.example.com\n    nameserver: 127.0.0.1:5353\n",
+                config::FileFormat::Yaml,
+            ))
+            .unwrap();
+        let settings: Vec<DiscoverySettings> = config.get("discovery").unwrap();
+        let providers = providers(&settings);
+        let names: Vec<String> = providers.iter().map(|p| p.name()).collect();
+        assert_eq!(
+            vec!["file:/tmp/tentacles.yml", "dns:_tentacle._tcp.example.com"],
+            names
+        );
+        assert_eq!(Duration::from_secs(10), providers[0].interval());
+        assert_eq!(Duration::from_secs(30), providers[1].interval());
+    }
+
+    #[test]
+    fn test_file_discovery() {
+        let path =
+            std::env::temp_dir().join(format!("logtopus-discovery-{}.yml", std::process::id()));
+        fs::write(
+            &path,
+            "tentacles:\n  - host: localhost\n    port: 18080\n    alias: tentacle_1\n",
+        )
+        .unwrap();
+
+        let mut provider = FileDiscovery::new(path.clone(), Duration::from_secs(1));
+        let mut rt = Runtime::new().unwrap();
+        let found = rt.block_on(provider.discover()).unwrap();
+        assert_eq!(Some(vec![tentacle("tentacle_1", 18080)]), found);
+
+        // unchanged file is not read again
+        assert_eq!(None, rt.block_on(provider.discover()).unwrap());
+
+        fs::remove_file(&path).unwrap();
+        assert!(rt.block_on(provider.discover()).is_err());
+    }
+
+    fn answer_srv_query(socket: UdpSocket) {
+        let mut buf = [0u8; 512];
+        let (len, peer) = socket.recv_from(&mut buf).unwrap();
+        let query = Message::from_vec(&buf[..len]).unwrap();
+        let name = query.queries()[0].name().clone();
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .add_query(query.queries()[0].clone());
+        for (host, port) in &[("node1.example.com.", 18080), ("node2.example.com.", 18081)] {
+            let srv = SRV::new(0, 0, *port, Name::from_ascii(host).unwrap());
+            response.add_answer(Record::from_rdata(
+                name.clone(),
+                60,
+                RecordType::SRV,
+                RData::SRV(srv),
+            ));
+        }
+        socket.send_to(&response.to_vec().unwrap(), peer).unwrap();
+    }
+
+    #[test]
+    fn test_dns_srv_discovery() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = thread::spawn(move || answer_srv_query(socket));
+
+        let auth = TentacleAuth::Bearer {
+            token: crate::secret::Secret::from(String::from("secret")),
+        };
+        let mut provider = DnsDiscovery::new(
+            String::from("_tentacle._tcp.example.com."),
+            DnsRecord::Srv,
+            8080,
+            String::from("http"),
+            Some(addr),
+            Duration::from_secs(30),
+        )
+        .with_transport(Some(auth.clone()), None);
+        let mut rt = Runtime::new().unwrap();
+        let found = rt.block_on(future::lazy(|| provider.discover())).unwrap();
+        server.join().unwrap();
+
+        let authenticated = |name: &str, port: i64| TentacleInfo {
+            host: String::from(name),
+            auth: Some(auth.clone()),
+            ..tentacle(name, port)
+        };
+        assert_eq!(
+            Some(vec![
+                authenticated("node1.example.com", 18080),
+                authenticated("node2.example.com", 18081)
+            ]),
+            found
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use bytes::BytesMut;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub const MSGPACK: &str = "application/msgpack";
+pub const CBOR: &str = "application/cbor";
+
+/// Longer values end the stream, so a tentacle cannot make the decoder buffer
+/// without bound.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// How the lines of a stream are encoded. The binary encodings prefix every
+/// value with its length as a 4 byte big endian integer.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Msgpack => MSGPACK,
+            Encoding::Cbor => CBOR,
+        }
+    }
+
+    /// The encoding of a response by its content type, JSON for any other.
+    pub fn from_content_type(content_type: &str) -> Encoding {
+        match content_type.split(';').next().unwrap_or("").trim() {
+            MSGPACK => Encoding::Msgpack,
+            CBOR => Encoding::Cbor,
+            _ => Encoding::Json,
+        }
+    }
+
+    /// The cheapest encoding a tentacle supports, JSON if it named none.
+    pub fn preferred(supported: &[Encoding]) -> Encoding {
+        [Encoding::Msgpack, Encoding::Cbor]
+            .iter()
+            .copied()
+            .find(|encoding| supported.contains(encoding))
+            .unwrap_or(Encoding::Json)
+    }
+
+    /// Encodes one value of a stream, JSON as a line.
+    pub fn encode<T: Serialize>(self, value: &T) -> Vec<u8> {
+        let payload = match self {
+            Encoding::Json => {
+                let mut json = serde_json::to_vec(value).unwrap();
+                json.push(b'\n');
+                return json;
+            }
+            Encoding::Msgpack => rmp_serde::to_vec_named(value).unwrap(),
+            Encoding::Cbor => serde_cbor::to_vec(value).unwrap(),
+        };
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            Encoding::Msgpack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+            Encoding::Cbor => serde_cbor::from_slice(payload).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Splits a stream into values, JSON at line breaks and the binary encodings
+/// by their length prefixes, whatever the chunks of the response are.
+pub struct FrameDecoder {
+    encoding: Encoding,
+    buf: BytesMut,
+    /// The start of the buffer holds no line break, it is not searched again.
+    searched: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(encoding: Encoding) -> FrameDecoder {
+        FrameDecoder {
+            encoding,
+            buf: BytesMut::new(),
+            searched: 0,
+        }
+    }
+
+    /// Adds a chunk and returns the values completed by it.
+    pub fn push<T: DeserializeOwned>(&mut self, chunk: &[u8]) -> Result<Vec<T>, String> {
+        self.buf.extend_from_slice(chunk);
+        let mut values = vec![];
+        if self.encoding == Encoding::Json {
+            while let Some(end) = self.buf[self.searched..].iter().position(|b| *b == b'\n') {
+                let line = self.buf.split_to(self.searched + end + 1);
+                self.searched = 0;
+                values.extend(self.decode_line(&line)?);
+            }
+            self.searched = self.buf.len();
+            check_len(self.buf.len())?;
+            return Ok(values);
+        }
+        while self.buf.len() >= 4 {
+            let len =
+                u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]) as usize;
+            check_len(len)?;
+            if self.buf.len() < 4 + len {
+                break;
+            }
+            let frame = self.buf.split_to(4 + len);
+            values.push(self.encoding.decode(&frame[4..])?);
+        }
+        Ok(values)
+    }
+
+    /// Returns the value of a last line without line break at the end of the
+    /// stream, fails on a truncated binary value.
+    pub fn finish<T: DeserializeOwned>(&mut self) -> Result<Vec<T>, String> {
+        let rest = self.buf.split_off(0);
+        self.searched = 0;
+        match self.encoding {
+            Encoding::Json => Ok(self.decode_line(&rest)?.into_iter().collect()),
+            _ if rest.is_empty() => Ok(vec![]),
+            _ => Err(format!("truncated value of {} bytes", rest.len())),
+        }
+    }
+
+    /// Decodes a JSON line, blank lines have no value.
+    fn decode_line<T: DeserializeOwned>(&self, line: &[u8]) -> Result<Option<T>, String> {
+        check_len(line.len())?;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(None);
+        }
+        self.encoding.decode(line).map(Some)
+    }
+}
+
+fn check_len(len: usize) -> Result<(), String> {
+    if len > MAX_FRAME_LEN {
+        Err(format!(
+            "value of {} bytes exceeds the limit of {} bytes",
+            len, MAX_FRAME_LEN
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::*;
+    use crate::tentacle::TentacleLogLine;
+    use crate::testing::tentacle_line;
+
+    #[test]
+    fn test_frames() {
+        for encoding in &[Encoding::Msgpack, Encoding::Cbor] {
+            let mut stream = encoding.encode(&tentacle_line(1, "hello", None));
+            stream.extend(encoding.encode(&tentacle_line(2, "hello", None)));
+            stream.extend(encoding.encode(&tentacle_line(3, "hello", None)));
+            let mut decoder = FrameDecoder::new(*encoding);
+            // chunks which split the values at arbitrary points
+            let (first, rest) = stream.split_at(3);
+            let (second, third) = rest.split_at(rest.len() - 5);
+            assert!(decoder.push::<TentacleLogLine>(first).unwrap().is_empty());
+            assert_eq!(
+                vec![
+                    tentacle_line(1, "hello", None),
+                    tentacle_line(2, "hello", None)
+                ],
+                decoder.push(second).unwrap()
+            );
+            assert_eq!(
+                vec![tentacle_line(3, "hello", None)],
+                decoder.push(third).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut decoder = FrameDecoder::new(Encoding::Json);
+        let stream = [
+            Encoding::Json.encode(&tentacle_line(1, "hello", None)),
+            b"\r\n".to_vec(),
+            Encoding::Json.encode(&tentacle_line(2, "hello", None)),
+        ]
+        .concat();
+        let (first, second) = stream.split_at(10);
+        assert!(decoder.push::<TentacleLogLine>(first).unwrap().is_empty());
+        assert_eq!(
+            vec![
+                tentacle_line(1, "hello", None),
+                tentacle_line(2, "hello", None)
+            ],
+            decoder.push(second).unwrap()
+        );
+        assert!(decoder.finish::<TentacleLogLine>().unwrap().is_empty());
+
+        // the last line may lack its line break
+        let last = serde_json::to_vec(&tentacle_line(3, "hello", None)).unwrap();
+        assert!(decoder.push::<TentacleLogLine>(&last).unwrap().is_empty());
+        assert_eq!(
+            vec![tentacle_line(3, "hello", None)],
+            decoder.finish().unwrap()
+        );
+
+        assert!(decoder.push::<TentacleLogLine>(b"{\"timestamp\n").is_err());
+    }
+
+    #[test]
+    fn test_frame_too_long() {
+        let mut decoder = FrameDecoder::new(Encoding::Msgpack);
+        let len = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+        assert!(decoder.push::<TentacleLogLine>(&len).is_err());
+
+        let mut decoder = FrameDecoder::new(Encoding::Json);
+        let chunk = vec![b' '; 1024 * 1024];
+        for _ in 0..MAX_FRAME_LEN / chunk.len() {
+            assert!(decoder.push::<TentacleLogLine>(&chunk).unwrap().is_empty());
+        }
+        assert!(decoder.push::<TentacleLogLine>(b" ").is_err());
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut decoder = FrameDecoder::new(Encoding::Cbor);
+        let frame = Encoding::Cbor.encode(&tentacle_line(1, "hello", None));
+        assert!(decoder
+            .push::<TentacleLogLine>(&frame[..frame.len() - 1])
+            .unwrap()
+            .is_empty());
+        assert!(decoder.finish::<TentacleLogLine>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_frame() {
+        let mut decoder = FrameDecoder::new(Encoding::Cbor);
+        assert!(decoder
+            .push::<TentacleLogLine>(&[0, 0, 0, 2, 0xff, 0xff])
+            .is_err());
+    }
+
+    #[test]
+    fn test_negotiation() {
+        assert_eq!(Encoding::Json, Encoding::preferred(&[]));
+        assert_eq!(
+            Encoding::Cbor,
+            Encoding::preferred(&[Encoding::Json, Encoding::Cbor])
+        );
+        assert_eq!(
+            Encoding::Msgpack,
+            Encoding::preferred(&[Encoding::Cbor, Encoding::Msgpack])
+        );
+        assert_eq!(
+            Encoding::Msgpack,
+            Encoding::from_content_type("application/msgpack; charset=binary")
+        );
+        assert_eq!(Encoding::Json, Encoding::from_content_type("text/plain"));
+    }
+}
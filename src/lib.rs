@@ -1,19 +1,104 @@
+mod admin;
+mod arrow_export;
+mod audit;
+mod auth;
+mod cancel;
 mod cfg;
+mod color;
+mod columnar;
+mod compression;
+mod cors;
+mod delimited;
+mod discovery;
+mod encoding;
+mod limits;
+mod log_file;
 mod log_merge;
+mod queries;
+mod rbac;
+mod registration;
+mod reload;
+mod secret;
 mod server;
+mod server_tls;
+mod settings;
+mod slow_queries;
 mod tentacle;
+mod tentacle_set;
+mod tentacle_tls;
+#[cfg(test)]
+mod testing;
+mod text_format;
+mod ui;
 
-use crate::cfg::read_config;
+use crate::admin::TentacleAdmin;
+use crate::cfg::{read_config, read_settings, SettingsHandle};
+use crate::discovery::{providers, Discovery};
+use crate::registration::{start_expiry, TentacleRegistry};
+use crate::reload::ConfigReloader;
+use crate::secret::redact;
 use crate::server::start_server;
+use crate::server_tls::TlsAcceptor;
+use crate::settings::Settings;
+use crate::tentacle_set::TentacleSet;
+use actix::Actor;
 use std::error::Error;
-use std::sync::Arc;
 
-pub fn run<S: AsRef<str>>(maybe_settings: &Option<S>) -> Result<(), Box<dyn Error>> {
-    let settings = Arc::new(read_config(maybe_settings)?);
+/// Validates the configuration and returns the effective settings as JSON with
+/// secrets redacted, or the origin of each setting if `origins` is set.
+pub fn check_config<S: AsRef<str>>(
+    config_files: &[S],
+    origins: bool,
+) -> Result<String, Box<dyn Error>> {
+    let layered = read_config(config_files)?;
+    let settings = Settings::from_config(&layered.config)?;
+    if origins {
+        Ok(layered
+            .origins
+            .iter()
+            .map(|(key, origin)| format!("{}: {}", key, origin))
+            .collect::<Vec<String>>()
+            .join("\n"))
+    } else {
+        let mut json = serde_json::to_value(&settings)?;
+        redact(&mut json, &layered.secrets);
+        Ok(serde_json::to_string_pretty(&json)?)
+    }
+}
+
+pub fn run<S: AsRef<str>>(config_files: &[S]) -> Result<(), Box<dyn Error>> {
+    let loaded = read_settings(config_files)?;
+    let tentacles = TentacleSet::new(loaded.tentacle_infos());
+    let providers = providers(&loaded.discovery);
+    let settings = SettingsHandle::new(loaded);
+    let registry = TentacleRegistry::from_settings(&settings.get(), tentacles.clone());
+    registry.load(&settings.get().registration.tokens)?;
+    let admin = TentacleAdmin::from_settings(&settings.get(), tentacles.clone());
+    admin.load()?;
+
+    let tls = match &settings.get().http.tls {
+        Some(tls) => Some(TlsAcceptor::new(tls)?),
+        None => None,
+    };
 
     let sys = actix::System::new("logtopus");
 
-    start_server(settings.clone());
+    let mut discovery = Discovery::new(tentacles.clone());
+    discovery.start(providers);
+    start_expiry(registry.clone());
+    start_server(
+        settings.clone(),
+        tentacles.clone(),
+        registry,
+        admin,
+        tls.clone(),
+    );
+
+    let config_files = config_files
+        .iter()
+        .map(|s| s.as_ref().to_string())
+        .collect();
+    ConfigReloader::new(config_files, settings, tentacles, discovery, tls).start();
 
     sys.run();
 
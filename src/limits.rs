@@ -0,0 +1,397 @@
+use crate::auth::Principal;
+use crate::cfg::SettingsHandle;
+use crate::rbac::matches_pattern;
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{http, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Buckets are pruned once there are more, full buckets are dropped.
+const MAX_IDLE_BUCKETS: usize = 1024;
+/// Buckets are pruned at most this often, pruning visits all buckets.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+/// Beyond this many buckets the least recently updated ones are evicted,
+/// even if they are not full yet.
+const MAX_BUCKETS: usize = 65_536;
+
+/// Clients over a concurrency limit are asked to retry after this many seconds.
+const QUERY_RETRY_AFTER_SECS: u64 = 1;
+
+/// Limits the request rate of each client on the matching routes. The config
+/// crate truncates narrower integers, so all numbers are i64 and validated.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Request path pattern, e.g. `/api/v1/sources/*`, `*` matches any text.
+    pub route: String,
+    pub requests_per_minute: i64,
+    /// Requests a client may send at once before the rate applies, defaults to 1.
+    pub burst: Option<i64>,
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.route.is_empty() {
+            errors.push(String::from("route must not be empty"));
+        }
+        if self.requests_per_minute <= 0 {
+            errors.push(String::from("requests_per_minute must be greater than 0"));
+        }
+        if self.burst.is_some_and(|burst| burst <= 0) {
+            errors.push(String::from("burst must be greater than 0"));
+        }
+        errors
+    }
+
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(1) as f64
+    }
+
+    fn per_sec(&self) -> f64 {
+        self.requests_per_minute as f64 / 60.0
+    }
+}
+
+#[derive(Clone, Serialize, PartialEq, Debug, Default)]
+pub struct LimitSettings {
+    /// For each request the first rate limit with a matching route applies.
+    pub rates: Vec<RateLimit>,
+    /// Streaming queries running at the same time, over all clients.
+    pub max_concurrent_queries: Option<i64>,
+    pub max_concurrent_queries_per_client: Option<i64>,
+}
+
+/// Identifies the client of a request for limits: the authenticated
+/// principal, otherwise the peer address.
+pub fn client_key<S>(req: &HttpRequest<S>) -> String {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return format!("principal {}", principal.name);
+    }
+    req.peer_addr()
+        .map(|addr| format!("ip {}", addr.ip()))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// Answer to a request over a limit.
+pub fn too_many_requests(retry_after_secs: u64) -> HttpResponse {
+    HttpResponse::build(http::StatusCode::TOO_MANY_REQUESTS)
+        .header(http::header::RETRY_AFTER, retry_after_secs.to_string())
+        .finish()
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Takes a token, or returns the seconds until one is available.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), u64> {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec()).min(limit.capacity());
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / limit.per_sec()).ceil() as u64)
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.per_sec() >= limit.capacity()
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<(RateLimit, String), Bucket>,
+    pruned: Option<Instant>,
+}
+
+impl Buckets {
+    /// Drops the full buckets, which behave like new ones, at most once per
+    /// `PRUNE_INTERVAL`.
+    fn prune(&mut self, now: Instant) {
+        let due = self
+            .pruned
+            .is_none_or(|pruned| now.duration_since(pruned) >= PRUNE_INTERVAL);
+        if self.buckets.len() > MAX_IDLE_BUCKETS && due {
+            self.buckets
+                .retain(|(limit, _), bucket| !bucket.is_full(limit, now));
+            self.pruned = Some(now);
+        }
+    }
+
+    /// Evicts the least recently updated eighth of the buckets, so that a
+    /// flood of new clients does not scan all buckets on every request.
+    fn evict(&mut self) {
+        let mut updated: Vec<Instant> = self.buckets.values().map(|b| b.updated).collect();
+        let evicted = (updated.len() / 8).max(1);
+        let (_, threshold, _) = updated.select_nth_unstable(evicted - 1);
+        let threshold = *threshold;
+        self.buckets.retain(|_, bucket| bucket.updated > threshold);
+    }
+}
+
+/// Token buckets per rate limit and client, shared by all workers.
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+    max_buckets: usize,
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter::new(MAX_BUCKETS)
+    }
+}
+
+impl RateLimiter {
+    fn new(max_buckets: usize) -> RateLimiter {
+        RateLimiter {
+            buckets: Arc::new(Mutex::new(Buckets::default())),
+            max_buckets,
+        }
+    }
+
+    fn check(&self, limit: &RateLimit, client: String, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.prune(now);
+        let key = (limit.clone(), client);
+        if buckets.buckets.len() >= self.max_buckets && !buckets.buckets.contains_key(&key) {
+            buckets.evict();
+        }
+        buckets
+            .buckets
+            .entry(key)
+            .or_insert_with(|| Bucket {
+                tokens: limit.capacity(),
+                updated: now,
+            })
+            .take(limit, now)
+    }
+}
+
+/// Rejects requests over the rate limit of their route with 429, must run
+/// after the authentication to tell clients apart by their principal.
+pub struct RateLimits {
+    settings: SettingsHandle,
+    limiter: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(settings: SettingsHandle, limiter: RateLimiter) -> RateLimits {
+        RateLimits { settings, limiter }
+    }
+}
+
+impl<S> Middleware<S> for RateLimits {
+    fn start(&self, req: &HttpRequest<S>) -> actix_web::Result<Started> {
+        let settings = self.settings.get();
+        let limit = settings
+            .limits
+            .rates
+            .iter()
+            .find(|limit| matches_pattern(&limit.route, req.path()));
+        if let Some(limit) = limit {
+            if let Err(retry_after_secs) =
+                self.limiter.check(limit, client_key(req), Instant::now())
+            {
+                return Ok(Started::Response(too_many_requests(retry_after_secs)));
+            }
+        }
+        Ok(Started::Done)
+    }
+}
+
+#[derive(Default)]
+struct RunningQueries {
+    total: usize,
+    per_client: HashMap<String, usize>,
+}
+
+/// Counts the running streaming queries, shared by all workers.
+#[derive(Clone, Default)]
+pub struct QueryLimiter {
+    running: Arc<Mutex<RunningQueries>>,
+}
+
+/// A running query, counted until it is dropped.
+pub struct QueryPermit {
+    running: Arc<Mutex<RunningQueries>>,
+    client: String,
+}
+
+impl Drop for QueryPermit {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap();
+        running.total -= 1;
+        if let Some(count) = running.per_client.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                running.per_client.remove(&self.client);
+            }
+        }
+    }
+}
+
+impl QueryLimiter {
+    /// Counts a new query of the client, or returns the seconds after which
+    /// the client should retry if a limit is reached.
+    pub fn start(&self, limits: &LimitSettings, client: String) -> Result<QueryPermit, u64> {
+        let mut running = self.running.lock().unwrap();
+        let client_count = running.per_client.get(&client).cloned().unwrap_or(0);
+        if limits
+            .max_concurrent_queries
+            .is_some_and(|max| running.total as i64 >= max)
+            || limits
+                .max_concurrent_queries_per_client
+                .is_some_and(|max| client_count as i64 >= max)
+        {
+            return Err(QUERY_RETRY_AFTER_SECS);
+        }
+        running.total += 1;
+        running.per_client.insert(client.clone(), client_count + 1);
+        Ok(QueryPermit {
+            running: self.running.clone(),
+            client,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limits::*;
+
+    fn rate(requests_per_minute: i64, burst: Option<i64>) -> RateLimit {
+        RateLimit {
+            route: String::from("/api/v1/sources/*"),
+            requests_per_minute,
+            burst,
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::default();
+        let limit = rate(60, Some(2));
+        let now = Instant::now();
+        let client = || String::from("ip 127.0.0.1");
+
+        assert_eq!(Ok(()), limiter.check(&limit, client(), now));
+        assert_eq!(Ok(()), limiter.check(&limit, client(), now));
+        assert_eq!(Err(1), limiter.check(&limit, client(), now));
+        assert_eq!(Ok(()), limiter.check(&limit, String::from("other"), now));
+        let later = now + Duration::from_millis(1500);
+        assert_eq!(Ok(()), limiter.check(&limit, client(), later));
+        assert_eq!(Err(1), limiter.check(&limit, client(), later));
+
+        let slow = rate(2, None);
+        assert_eq!(Ok(()), limiter.check(&slow, client(), now));
+        assert_eq!(Err(30), limiter.check(&slow, client(), now));
+
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            updated: now,
+        };
+        assert!(!bucket.is_full(&limit, now + Duration::from_secs(1)));
+        assert!(bucket.is_full(&limit, now + Duration::from_secs(2)));
+        assert_eq!(Ok(()), bucket.take(&limit, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_rate_limiter_bounded() {
+        let limiter = RateLimiter::new(16);
+        let limit = rate(60, None);
+        let now = Instant::now();
+        for n in 0..16 {
+            let at = now + Duration::from_millis(n);
+            assert_eq!(Ok(()), limiter.check(&limit, format!("client {}", n), at));
+        }
+        let later = now + Duration::from_millis(100);
+        assert_eq!(Ok(()), limiter.check(&limit, String::from("new"), later));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(15, buckets.buckets.len());
+        // the least recently updated buckets are gone, their clients start over
+        assert!(!buckets
+            .buckets
+            .contains_key(&(limit.clone(), String::from("client 1"))));
+        assert!(buckets
+            .buckets
+            .contains_key(&(limit.clone(), String::from("client 2"))));
+    }
+
+    #[test]
+    fn test_prune_interval() {
+        let limiter = RateLimiter::default();
+        let limit = rate(60, None);
+        let now = Instant::now();
+        for n in 0..=MAX_IDLE_BUCKETS {
+            assert_eq!(Ok(()), limiter.check(&limit, format!("client {}", n), now));
+        }
+        // all buckets are full again, but pruning waits for the interval
+        let refilled = now + Duration::from_secs(1);
+        assert_eq!(Ok(()), limiter.check(&limit, String::from("a"), refilled));
+        assert_eq!(1, limiter.buckets.lock().unwrap().buckets.len());
+        for n in 0..=MAX_IDLE_BUCKETS {
+            assert_eq!(
+                Ok(()),
+                limiter.check(&limit, format!("client {}", n), refilled)
+            );
+        }
+        let soon = refilled + Duration::from_secs(1);
+        assert_eq!(Ok(()), limiter.check(&limit, String::from("b"), soon));
+        assert_eq!(
+            MAX_IDLE_BUCKETS + 3,
+            limiter.buckets.lock().unwrap().buckets.len()
+        );
+        let after_interval = refilled + PRUNE_INTERVAL;
+        assert_eq!(
+            Ok(()),
+            limiter.check(&limit, String::from("c"), after_interval)
+        );
+        assert_eq!(1, limiter.buckets.lock().unwrap().buckets.len());
+    }
+
+    #[test]
+    fn test_query_limiter() {
+        let limiter = QueryLimiter::default();
+        let limits = LimitSettings {
+            rates: vec![],
+            max_concurrent_queries: Some(3),
+            max_concurrent_queries_per_client: Some(2),
+        };
+        let a1 = limiter.start(&limits, String::from("a")).unwrap();
+        let a2 = limiter.start(&limits, String::from("a")).unwrap();
+        assert_eq!(
+            Some(QUERY_RETRY_AFTER_SECS),
+            limiter.start(&limits, String::from("a")).err()
+        );
+        let b1 = limiter.start(&limits, String::from("b")).unwrap();
+        assert!(limiter.start(&limits, String::from("c")).is_err());
+
+        drop(a1);
+        assert!(limiter.start(&limits, String::from("a")).is_ok());
+        drop(a2);
+        drop(b1);
+        assert_eq!(0, limiter.running.lock().unwrap().total);
+        assert!(limiter.running.lock().unwrap().per_client.is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(rate(60, None).validate().is_empty());
+        assert_eq!(
+            vec![
+                "requests_per_minute must be greater than 0",
+                "burst must be greater than 0"
+            ],
+            rate(0, Some(-1)).validate()
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+fn default_max_size_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_files() -> u32 {
+    10
+}
+
+/// A JSON lines file rotated by size, e.g. the audit log.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileSettings {
+    pub file: PathBuf,
+    /// The file is rotated to `<file>.1` before it would grow beyond this size.
+    #[serde(default = "default_max_size_bytes")]
+    pub max_size_bytes: u64,
+    /// Rotated files kept, the oldest one is removed on rotation.
+    #[serde(default = "default_max_files")]
+    pub max_files: u32,
+}
+
+impl LogFileSettings {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.max_size_bytes == 0 {
+            errors.push(String::from("max_size_bytes must be greater than 0"));
+        }
+        if self.max_files == 0 {
+            errors.push(String::from("max_files must be greater than 0"));
+        }
+        errors
+    }
+}
+
+struct OpenFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl OpenFile {
+    fn open(path: &Path) -> io::Result<OpenFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(OpenFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+        })
+    }
+}
+
+fn rotated(path: &Path, idx: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", idx));
+    PathBuf::from(rotated)
+}
+
+/// Shifts `<file>.N` to `<file>.N+1` and the file to `<file>.1`, dropping
+/// the files beyond `max_files`.
+fn rotate(path: &Path, max_files: u32) -> io::Result<()> {
+    for idx in (1..max_files).rev() {
+        let from = rotated(path, idx);
+        if from.exists() {
+            fs::rename(&from, rotated(path, idx + 1))?;
+        }
+    }
+    fs::rename(path, rotated(path, 1))
+}
+
+/// An append-only JSON lines file, shared by all workers. The file is opened
+/// on the first entry and reopened if a reload changes its path.
+#[derive(Clone, Default)]
+pub struct LogFile {
+    file: Arc<Mutex<Option<OpenFile>>>,
+}
+
+impl LogFile {
+    pub fn write<T: Serialize>(&self, settings: &LogFileSettings, entry: &T) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut current = self.file.lock().unwrap();
+        if current.as_ref().is_none_or(|f| f.path != settings.file) {
+            *current = Some(OpenFile::open(&settings.file)?);
+        }
+        let mut file = current.take().unwrap();
+        if file.size > 0 && file.size + line.len() as u64 > settings.max_size_bytes {
+            drop(file);
+            rotate(&settings.file, settings.max_files)?;
+            file = OpenFile::open(&settings.file)?;
+        }
+        file.file.write_all(&line)?;
+        file.size += line.len() as u64;
+        *current = Some(file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_file::*;
+    use serde_json::json;
+
+    fn read_entries(path: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_write_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("logtopus-log-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let entry = |n: u64| json!({"principal": "grafana", "lines": n});
+        let line_len = serde_json::to_vec(&entry(0)).unwrap().len() as u64 + 1;
+        let settings = LogFileSettings {
+            file: path.clone(),
+            max_size_bytes: line_len * 2,
+            max_files: 2,
+        };
+        let log = LogFile::default();
+
+        for n in 0..7 {
+            log.write(&settings, &entry(n)).unwrap();
+        }
+        assert_eq!(vec![entry(6)], read_entries(&path));
+        assert_eq!(vec![entry(4), entry(5)], read_entries(&rotated(&path, 1)));
+        assert_eq!(2, read_entries(&rotated(&path, 2)).len());
+        assert!(!rotated(&path, 3).exists());
+
+        // another path after a reload
+        let other = LogFileSettings {
+            file: dir.join("other.log"),
+            ..settings
+        };
+        log.write(&other, &entry(7)).unwrap();
+        assert_eq!(1, read_entries(&other.file).len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate() {
+        let settings = LogFileSettings {
+            file: PathBuf::from("/var/log/logtopus/audit.log"),
+            max_size_bytes: 0,
+            max_files: 0,
+        };
+        assert_eq!(2, settings.validate().len());
+    }
+}
@@ -17,7 +17,7 @@ impl Display for LogStreamError {
     }
 }
 
-pub type LogStream = Box<Stream<Item = LogLine, Error = LogStreamError>>;
+pub type LogStream = Box<dyn Stream<Item = LogLine, Error = LogStreamError>>;
 
 #[derive(PartialEq)]
 enum SourceState {
@@ -50,8 +50,8 @@ impl LogMerge {
         }
         LogMerge {
             running_sources: num_sources,
-            sources: sources,
-            source_state: source_state,
+            sources,
+            source_state,
             buffer: Vec::with_capacity(num_sources),
             current_timestamp: 0,
         }
@@ -59,8 +59,7 @@ impl LogMerge {
 
     fn next_entry(&mut self) -> BufferEntry {
         // TODO: better error handling, remove_item -> rust nightly / 2019-02-20
-        let e = self.buffer.remove(0);
-        return e;
+        self.buffer.remove(0)
     }
 
     fn insert_into_buffer(&mut self, log_line: LogLine, source_idx: usize) {
@@ -84,9 +83,9 @@ impl LogMerge {
             LogStreamError::DefaultError(tentacle) => {
                 let log_line = LogLine {
                     timestamp: self.current_timestamp,
-                    message: format!("A tentacle failed while retrieving the log."),
-                    loglevel: Some(format!("ERROR")),
-                    id: format!(""),
+                    message: String::from("A tentacle failed while retrieving the log."),
+                    loglevel: Some(String::from("ERROR")),
+                    id: String::new(),
                     source: tentacle,
                 };
                 self.insert_into_buffer(log_line, source_idx);
@@ -124,13 +123,8 @@ impl Stream for LogMerge {
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         for s in 0..self.source_state.len() {
-            match self.source_state[s] {
-                SourceState::NeedsPoll => {
-                    if let Err(err) = self.poll_source(s) {
-                        return Err(err);
-                    }
-                }
-                _ => {}
+            if self.source_state[s] == SourceState::NeedsPoll {
+                self.poll_source(s)?;
             }
         }
         if self.running_sources == 0 && self.buffer.is_empty() {
@@ -151,25 +145,15 @@ impl Stream for LogMerge {
 #[cfg(test)]
 mod tests {
     use crate::log_merge::{LogMerge, LogStream};
-    use crate::tentacle::LogLine;
+    use crate::testing::log_line;
     use futures::stream::{empty, iter_ok, once};
     use futures::Stream;
     use tokio::runtime::current_thread::Runtime;
 
-    fn line_at(timestamp: i64, line: &str) -> LogLine {
-        LogLine {
-            timestamp: timestamp,
-            message: line.to_string(),
-            loglevel: None,
-            id: String::from("system-syslog"),
-            source: String::from("node1"),
-        }
-    }
-
     #[test]
     fn test_new() {
-        let s1: LogStream = Box::new(once(Ok(line_at(0, "s1"))));
-        let s2: LogStream = Box::new(once(Ok(line_at(1, "s2"))));
+        let s1: LogStream = Box::new(once(Ok(log_line(0, "s1", None))));
+        let s2: LogStream = Box::new(once(Ok(log_line(1, "s2", None))));
         let sources = vec![s1, s2];
         let merge = LogMerge::new(sources);
         assert!(merge.sources.len() == 2);
@@ -188,8 +172,8 @@ mod tests {
 
     #[test]
     fn test_single_stream() {
-        let l1 = line_at(0, "s1");
-        let l2 = line_at(1, "s1");
+        let l1 = log_line(0, "s1", None);
+        let l2 = log_line(1, "s1", None);
         let s1: LogStream = Box::new(iter_ok(vec![l1.clone(), l2.clone()]));
         let sources = vec![s1];
         let merge = LogMerge::new(sources);
@@ -200,15 +184,15 @@ mod tests {
 
     #[test]
     fn test_multiple_streams() {
-        let l11 = line_at(100, "s11");
-        let l12 = line_at(300, "s12");
-        let l13 = line_at(520, "s13");
-        let l21 = line_at(90, "s21");
-        let l22 = line_at(430, "s22");
-        let l31 = line_at(120, "s31");
-        let l32 = line_at(120, "s32");
-        let l33 = line_at(320, "s33");
-        let l34 = line_at(520, "s34");
+        let l11 = log_line(100, "s11", None);
+        let l12 = log_line(300, "s12", None);
+        let l13 = log_line(520, "s13", None);
+        let l21 = log_line(90, "s21", None);
+        let l22 = log_line(430, "s22", None);
+        let l31 = log_line(120, "s31", None);
+        let l32 = log_line(120, "s32", None);
+        let l33 = log_line(320, "s33", None);
+        let l34 = log_line(520, "s34", None);
         let s1: LogStream = Box::new(iter_ok(vec![l11.clone(), l12.clone(), l13.clone()]));
         let s2: LogStream = Box::new(iter_ok(vec![l21.clone(), l22.clone()]));
         let s3: LogStream = Box::new(iter_ok(vec![
@@ -1,6 +1,6 @@
 mod constants;
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use log::*;
 use std::error::Error;
 
@@ -9,7 +9,25 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     init_log(&cli_matches);
 
-    logtopus::run(&cli_matches.value_of("config"))
+    let config_files: Vec<&str> = cli_matches
+        .values_of("config")
+        .map(|files| files.collect())
+        .unwrap_or_default();
+
+    if let Some(check_matches) = cli_matches.subcommand_matches("check-config") {
+        match logtopus::check_config(&config_files, check_matches.is_present("origins")) {
+            Ok(settings) => {
+                println!("{}", settings);
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    logtopus::run(&config_files)
 }
 
 fn init_log(matches: &clap::ArgMatches) {
@@ -24,7 +42,7 @@ fn init_log(matches: &clap::ArgMatches) {
     let loglevel = match matches.value_of("module") {
         Some(module) => {
             let mut module_loglevel = String::from(module);
-            module_loglevel.push_str("=");
+            module_loglevel.push('=');
             module_loglevel.push_str(loglevel);
             module_loglevel
         }
@@ -49,12 +67,24 @@ fn parse_cli() -> clap::ArgMatches<'static> {
                 .short("c")
                 .long("config")
                 .value_name("FILE")
-                .help("Sets the configuration file name")
-                .takes_value(true),
+                .help("Sets a configuration file, may be given multiple times with later files taking precedence")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
         )
         .arg(Arg::with_name("v")
             .short("v")
             .multiple(true)
             .help("Level of verbosity (error is default) if used multiple times: warn(v), info(vv), debug(vvv) and trace(vvvv)"))
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Validates the configuration and prints the effective settings")
+                .arg(
+                    Arg::with_name("origins")
+                        .long("origins")
+                        .help("Prints the file or source each setting was read from instead"),
+                ),
+        )
         .get_matches()
 }
@@ -0,0 +1,416 @@
+use crate::cfg::SettingsHandle;
+use crate::log_file::LogFile;
+use crate::log_merge::{LogStream, LogStreamError};
+use crate::slow_queries::{SlowQueryEntry, TentacleTiming};
+use crate::tentacle::LogLine;
+use chrono::{DateTime, Utc};
+use futures::task::AtomicTask;
+use futures::{Async, Poll, Stream};
+use log::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub const KILLED: &str = "query killed by an operator";
+
+#[derive(Clone, Serialize, PartialEq, Debug)]
+pub struct QueryFilters {
+    pub from_ms: u64,
+    pub loglevels: Option<String>,
+}
+
+/// A running content query as listed by `GET /api/v1/queries`.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct QueryInfo {
+    pub id: u64,
+    pub principal: Option<String>,
+    pub source: String,
+    pub filters: QueryFilters,
+    pub started: DateTime<Utc>,
+    pub lines: u64,
+    /// Tentacles whose stream has not ended yet.
+    pub open_tentacles: BTreeSet<String>,
+}
+
+/// What a query emits: log lines, ended by an error if the query was killed.
+#[derive(PartialEq, Debug)]
+pub enum QueryEvent {
+    Line(LogLine),
+    Error(String),
+}
+
+struct QueryState {
+    principal: Option<String>,
+    source: String,
+    filters: QueryFilters,
+    started: DateTime<Utc>,
+    started_at: Instant,
+    lines: AtomicU64,
+    tentacles: Mutex<BTreeMap<String, TentacleTiming>>,
+    killed: AtomicBool,
+    /// The task polling the query, woken up to end it when it is killed.
+    task: AtomicTask,
+}
+
+impl QueryState {
+    fn elapsed_ms(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+}
+
+/// The running content queries, shared by all workers. Queries over the
+/// thresholds of `slow_queries` are logged when they end.
+#[derive(Clone)]
+pub struct QueryRegistry {
+    queries: Arc<Mutex<BTreeMap<u64, Arc<QueryState>>>>,
+    next_id: Arc<AtomicU64>,
+    settings: SettingsHandle,
+    slow_log: LogFile,
+}
+
+impl QueryRegistry {
+    pub fn new(settings: SettingsHandle) -> QueryRegistry {
+        QueryRegistry {
+            queries: Arc::new(Mutex::new(BTreeMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+            settings,
+            slow_log: LogFile::default(),
+        }
+    }
+
+    /// Registers a query, it is listed until the returned handle is dropped.
+    pub fn start(
+        &self,
+        principal: Option<String>,
+        source: String,
+        filters: QueryFilters,
+    ) -> RunningQuery {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let state = Arc::new(QueryState {
+            principal,
+            source,
+            filters,
+            started: Utc::now(),
+            started_at: Instant::now(),
+            lines: AtomicU64::new(0),
+            tentacles: Mutex::new(BTreeMap::new()),
+            killed: AtomicBool::new(false),
+            task: AtomicTask::new(),
+        });
+        self.queries.lock().unwrap().insert(id, state.clone());
+        RunningQuery {
+            id,
+            state,
+            registry: self.clone(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<QueryInfo> {
+        self.queries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, state)| QueryInfo {
+                id: *id,
+                principal: state.principal.clone(),
+                source: state.source.clone(),
+                filters: state.filters.clone(),
+                started: state.started,
+                lines: state.lines.load(Ordering::Relaxed),
+                open_tentacles: state
+                    .tentacles
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, timing)| timing.ended_ms.is_none())
+                    .map(|(tentacle, _)| tentacle.clone())
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// The source read by a running query and the principal which started it.
+    pub fn source_and_principal(&self, id: u64) -> Option<(String, Option<String>)> {
+        let queries = self.queries.lock().unwrap();
+        queries
+            .get(&id)
+            .map(|state| (state.source.clone(), state.principal.clone()))
+    }
+
+    /// Ends the query with an error event, returns false for an unknown id.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.queries.lock().unwrap().get(&id) {
+            Some(state) => {
+                state.killed.store(true, Ordering::Relaxed);
+                state.task.notify();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The handle of a registered query.
+pub struct RunningQuery {
+    id: u64,
+    state: Arc<QueryState>,
+    registry: QueryRegistry,
+}
+
+impl RunningQuery {
+    /// Times the stream of the tentacle, which is listed as open until it ends.
+    pub fn track(&self, tentacle: String, stream: LogStream) -> LogStream {
+        self.state
+            .tentacles
+            .lock()
+            .unwrap()
+            .insert(tentacle.clone(), TentacleTiming::default());
+        Box::new(TrackedTentacle {
+            tentacle,
+            stream,
+            state: self.state.clone(),
+        })
+    }
+
+    /// The events of the query, counting its lines and ending it when it is killed.
+    pub fn events<S: Stream<Item = LogLine>>(self, lines: S) -> QueryEvents<S> {
+        QueryEvents {
+            query: self,
+            lines,
+            ended: false,
+        }
+    }
+}
+
+impl Drop for RunningQuery {
+    fn drop(&mut self) {
+        self.registry.queries.lock().unwrap().remove(&self.id);
+        let settings = self.registry.settings.get();
+        let slow_queries = match &settings.slow_queries {
+            Some(slow_queries) => slow_queries,
+            None => return,
+        };
+        let state = &self.state;
+        let duration_ms = state.elapsed_ms();
+        let lines = state.lines.load(Ordering::Relaxed);
+        if !slow_queries.is_slow(duration_ms, lines) {
+            return;
+        }
+        let entry = SlowQueryEntry {
+            id: self.id,
+            principal: state.principal.clone(),
+            source: state.source.clone(),
+            filters: state.filters.clone(),
+            started: state.started,
+            duration_ms,
+            lines,
+            killed: state.killed.load(Ordering::Relaxed),
+            tentacles: state.tentacles.lock().unwrap().clone(),
+        };
+        if let Err(e) = self.registry.slow_log.write(&slow_queries.log, &entry) {
+            error!(
+                "Failed to write slow query log {}: {}",
+                slow_queries.log.file.display(),
+                e
+            );
+        }
+    }
+}
+
+struct TrackedTentacle {
+    tentacle: String,
+    stream: LogStream,
+    state: Arc<QueryState>,
+}
+
+impl Stream for TrackedTentacle {
+    type Item = LogLine;
+    type Error = LogStreamError;
+
+    fn poll(&mut self) -> Poll<Option<LogLine>, LogStreamError> {
+        let result = self.stream.poll();
+        if let Ok(Async::NotReady) = result {
+            return result;
+        }
+        let elapsed_ms = self.state.elapsed_ms();
+        let mut tentacles = self.state.tentacles.lock().unwrap();
+        let timing = tentacles.entry(self.tentacle.clone()).or_default();
+        match result {
+            Ok(Async::Ready(Some(_))) => {
+                timing.lines += 1;
+                timing.first_line_ms.get_or_insert(elapsed_ms);
+            }
+            Ok(_) => timing.ended_ms = Some(elapsed_ms),
+            Err(_) => {
+                timing.ended_ms = Some(elapsed_ms);
+                timing.failed = true;
+            }
+        }
+        result
+    }
+}
+
+pub struct QueryEvents<S> {
+    query: RunningQuery,
+    lines: S,
+    ended: bool,
+}
+
+impl<S: Stream<Item = LogLine>> Stream for QueryEvents<S> {
+    type Item = QueryEvent;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<QueryEvent>, S::Error> {
+        if self.ended {
+            return Ok(Async::Ready(None));
+        }
+        let state = &self.query.state;
+        state.task.register();
+        if state.killed.load(Ordering::Relaxed) {
+            self.ended = true;
+            return Ok(Async::Ready(Some(QueryEvent::Error(String::from(KILLED)))));
+        }
+        match self.lines.poll()? {
+            Async::Ready(Some(line)) => {
+                state.lines.fetch_add(1, Ordering::Relaxed);
+                Ok(Async::Ready(Some(QueryEvent::Line(line))))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_file::LogFileSettings;
+    use crate::queries::*;
+    use crate::settings::Settings;
+    use crate::slow_queries::SlowQuerySettings;
+    use crate::testing::log_line;
+    use futures::stream;
+    use std::fs;
+    use std::path::Path;
+
+    fn registry(slow_log: Option<&Path>) -> QueryRegistry {
+        let mut settings = Settings::anonymous_defaults();
+        settings.slow_queries = slow_log.map(|file| SlowQuerySettings {
+            threshold_ms: None,
+            max_lines: Some(1),
+            log: LogFileSettings {
+                file: file.to_path_buf(),
+                max_size_bytes: 1024 * 1024,
+                max_files: 1,
+            },
+        });
+        QueryRegistry::new(SettingsHandle::new(settings))
+    }
+
+    fn start(registry: &QueryRegistry) -> RunningQuery {
+        registry.start(
+            Some(String::from("grafana")),
+            String::from("app"),
+            QueryFilters {
+                from_ms: 0,
+                loglevels: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_list_and_track() {
+        let registry = registry(None);
+        let query = start(&registry);
+        let web1 = query.track(
+            String::from("web-1"),
+            Box::new(stream::iter_ok(vec![log_line(1, "hello", None)])),
+        );
+        let web2 = query.track(String::from("web-2"), Box::new(stream::empty()));
+        let info = &registry.list()[0];
+        assert_eq!(1, info.id);
+        assert_eq!("app", info.source);
+        assert_eq!(2, info.open_tentacles.len());
+
+        // polled in a task to register it for kills
+        let mut events = query.events(web1.select(web2)).wait();
+        assert_eq!(
+            Some(QueryEvent::Line(log_line(1, "hello", None))),
+            events.next().and_then(Result::ok)
+        );
+        assert!(events.next().is_none());
+        let info = &registry.list()[0];
+        assert_eq!(1, info.lines);
+        assert!(info.open_tentacles.is_empty());
+
+        drop(events);
+        assert!(registry.list().is_empty());
+        assert!(!registry.kill(1));
+    }
+
+    #[test]
+    fn test_kill() {
+        let registry = registry(None);
+        let events = start(&registry).events(stream::iter_ok::<_, ()>(vec![
+            log_line(1, "hello", None),
+            log_line(2, "hello", None),
+        ]));
+        let id = registry.list()[0].id;
+        assert_eq!(
+            Some((String::from("app"), Some(String::from("grafana")))),
+            registry.source_and_principal(id)
+        );
+
+        let mut events = events.wait();
+        assert_eq!(
+            Some(Ok(QueryEvent::Line(log_line(1, "hello", None)))),
+            events.next()
+        );
+        assert!(registry.kill(id));
+        assert_eq!(
+            Some(Ok(QueryEvent::Error(String::from(KILLED)))),
+            events.next()
+        );
+        assert_eq!(None, events.next());
+    }
+
+    #[test]
+    fn test_slow_query_log() {
+        let path = std::env::temp_dir().join(format!("logtopus-slow-{}.log", std::process::id()));
+        let registry = registry(Some(&path));
+
+        let fast =
+            start(&registry).events(stream::iter_ok::<_, ()>(vec![log_line(1, "hello", None)]));
+        assert_eq!(1, fast.wait().count());
+        assert!(!path.exists());
+
+        let query = start(&registry);
+        let web1 = query.track(
+            String::from("web-1"),
+            Box::new(stream::iter_ok(vec![
+                log_line(1, "hello", None),
+                log_line(2, "hello", None),
+            ])),
+        );
+        let web2 = query.track(
+            String::from("web-2"),
+            Box::new(stream::once(Err(LogStreamError::DefaultError(
+                String::from("web-2"),
+            )))),
+        );
+        let events: Vec<_> = query.events(web1.select(web2)).wait().collect();
+        assert_eq!(3, events.len());
+
+        let entry: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(2, entry["id"]);
+        assert_eq!("app", entry["source"]);
+        assert_eq!(0, entry["filters"]["from_ms"]);
+        assert_eq!(2, entry["lines"]);
+        assert_eq!(2, entry["tentacles"]["web-1"]["lines"]);
+        assert!(entry["tentacles"]["web-1"]["first_line_ms"].is_u64());
+        assert!(entry["tentacles"]["web-2"]["failed"].as_bool().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use crate::auth::Principal;
+use crate::settings::Settings;
+use crate::tentacle::TentacleInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A role grants read access to the sources matching its patterns, queried
+/// on the tentacles matching its label selectors.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RoleSettings {
+    pub name: String,
+    /// Source id patterns, `*` matches any text, patterns starting with `!`
+    /// exclude sources matched by the other patterns.
+    pub sources: Vec<String>,
+    /// A tentacle matches if it has all labels of one of the selectors,
+    /// without selectors all tentacles match.
+    #[serde(default)]
+    pub tentacles: Vec<BTreeMap<String, String>>,
+    /// Lets the clients list and kill the queries of other clients on the
+    /// sources they may read, otherwise they only see their own.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Matches a text against a pattern in which `*` stands for any text.
+pub fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        // no wildcard
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(idx) => rest = &rest[idx + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.len() >= last.len() && rest.ends_with(last)
+        }
+    }
+}
+
+impl RoleSettings {
+    pub fn may_read(&self, source: &str) -> bool {
+        let (excluded, included): (Vec<&String>, Vec<&String>) =
+            self.sources.iter().partition(|p| p.starts_with('!'));
+        included.iter().any(|p| matches_pattern(p, source))
+            && !excluded.iter().any(|p| matches_pattern(&p[1..], source))
+    }
+
+    pub fn selects(&self, tentacle: &TentacleInfo) -> bool {
+        self.tentacles.is_empty()
+            || self.tentacles.iter().any(|selector| {
+                selector
+                    .iter()
+                    .all(|(label, value)| tentacle.labels.get(label) == Some(value))
+            })
+    }
+}
+
+/// What the client of a request may read.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Access {
+    /// Without authentication or without configured roles everything is readable.
+    All,
+    Roles(Vec<RoleSettings>),
+}
+
+impl Access {
+    pub fn new(settings: &Settings, principal: Option<&Principal>) -> Access {
+        match principal {
+            Some(principal) if !settings.auth.roles.is_empty() => Access::Roles(
+                settings
+                    .auth
+                    .roles
+                    .iter()
+                    .filter(|role| principal.roles.contains(&role.name))
+                    .cloned()
+                    .collect(),
+            ),
+            _ => Access::All,
+        }
+    }
+
+    pub fn may_read(&self, source: &str) -> bool {
+        match self {
+            Access::All => true,
+            Access::Roles(roles) => roles.iter().any(|role| role.may_read(source)),
+        }
+    }
+
+    /// Whether the queries of other clients may be listed and killed. Without
+    /// authentication all queries belong to the same anonymous client.
+    pub fn is_admin(&self) -> bool {
+        match self {
+            Access::All => false,
+            Access::Roles(roles) => roles.iter().any(|role| role.admin),
+        }
+    }
+
+    /// Whether the source may be read from this tentacle.
+    pub fn may_query(&self, source: &str, tentacle: &TentacleInfo) -> bool {
+        match self {
+            Access::All => true,
+            Access::Roles(roles) => roles
+                .iter()
+                .any(|role| role.may_read(source) && role.selects(tentacle)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rbac::*;
+    use crate::testing;
+
+    fn role(
+        name: &str,
+        sources: &[&str],
+        tentacles: Vec<BTreeMap<String, String>>,
+    ) -> RoleSettings {
+        RoleSettings {
+            name: String::from(name),
+            sources: sources.iter().map(|s| s.to_string()).collect(),
+            tentacles,
+            admin: false,
+        }
+    }
+
+    fn tentacle(team: &str) -> TentacleInfo {
+        TentacleInfo {
+            labels: vec![(String::from("team"), String::from(team))]
+                .into_iter()
+                .collect(),
+            ..testing::tentacle(team, 8080)
+        }
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("app-*", "app-web"));
+        assert!(matches_pattern("app-*", "app-"));
+        assert!(!matches_pattern("app-*", "system-auth"));
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("*-auth", "system-auth"));
+        assert!(matches_pattern("a*b*c", "a-b-b-c"));
+        assert!(!matches_pattern("a*b*c", "a-c-b"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+        assert!(matches_pattern("system-auth", "system-auth"));
+        assert!(!matches_pattern("system-auth", "system-auth2"));
+    }
+
+    #[test]
+    fn test_access() {
+        let selector = vec![(String::from("team"), String::from("app"))]
+            .into_iter()
+            .collect();
+        let access = Access::Roles(vec![
+            role("app", &["app-*"], vec![selector]),
+            role("ops", &["*", "!system-auth"], vec![]),
+        ]);
+        assert!(access.may_read("app-web"));
+        assert!(access.may_read("nginx"));
+        assert!(!access.may_read("system-auth"));
+        assert!(access.may_query("app-web", &tentacle("app")));
+        assert!(access.may_query("app-web", &tentacle("db")));
+        assert!(!access.may_query("system-auth", &tentacle("app")));
+
+        let app_only = Access::Roles(vec![role(
+            "app",
+            &["app-*"],
+            vec![vec![(String::from("team"), String::from("app"))]
+                .into_iter()
+                .collect()],
+        )]);
+        assert!(app_only.may_query("app-web", &tentacle("app")));
+        assert!(!app_only.may_query("app-web", &tentacle("db")));
+        assert!(!app_only.may_read("nginx"));
+        assert!(!Access::Roles(vec![]).may_read("app-web"));
+        assert!(Access::All.may_query("system-auth", &tentacle("db")));
+    }
+
+    #[test]
+    fn test_admin() {
+        let mut admin = role("admin", &["*"], vec![]);
+        admin.admin = true;
+        assert!(Access::Roles(vec![role("app", &["app-*"], vec![]), admin]).is_admin());
+        assert!(!Access::Roles(vec![role("app", &["app-*"], vec![])]).is_admin());
+        assert!(!Access::All.is_admin());
+    }
+}
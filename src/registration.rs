@@ -0,0 +1,562 @@
+use crate::auth::constant_time_eq;
+use crate::encoding::Encoding;
+use crate::secret::Secret;
+use crate::settings::Settings;
+use crate::tentacle::{TentacleAuth, TentacleInfo};
+use crate::tentacle_set::TentacleSet;
+use crate::tentacle_tls::TentacleTls;
+use futures::Stream;
+use http::Uri;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::timer::Interval;
+
+/// Name of the group in the tentacle set which holds the registered tentacles.
+const REGISTRATION_PROVIDER: &str = "registration";
+
+#[derive(Debug)]
+pub enum RegistrationError {
+    IllegalName,
+    IllegalUrl,
+    /// The name belongs to a tentacle registered with another token or not
+    /// registered at all.
+    NameTaken,
+    /// A label the tentacle declared differs from the labels of its token.
+    IllegalLabel(String),
+    NotRegistered,
+}
+
+impl Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistrationError::IllegalName => write!(f, "Tentacle name must not be empty"),
+            RegistrationError::IllegalUrl => {
+                write!(f, "Tentacle url must be of the form http(s)://host[:port]")
+            }
+            RegistrationError::NameTaken => write!(f, "Tentacle name is taken"),
+            RegistrationError::IllegalLabel(label) => {
+                write!(f, "Label {} is not allowed for this token", label)
+            }
+            RegistrationError::NotRegistered => write!(f, "Tentacle is not registered"),
+        }
+    }
+}
+
+impl Error for RegistrationError {}
+
+/// A credential tentacles register with, sent as `Authorization: Bearer <token>`.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RegistrationToken {
+    /// Owns the names registered with the token, the token itself is never logged.
+    pub name: String,
+    pub token: Secret,
+    /// The labels of the tentacles registering with the token, labels a
+    /// tentacle declares itself have to agree with them.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Credentials sent to the tentacles registering with the token.
+    pub auth: Option<TentacleAuth>,
+    /// TLS settings of the tentacles registering with the token, applied to
+    /// https urls only.
+    pub tls: Option<TentacleTls>,
+}
+
+impl RegistrationToken {
+    /// The token sent with a request, none if the authorization matches no token.
+    pub fn find<'a>(
+        tokens: &'a [RegistrationToken],
+        authorization: Option<&str>,
+    ) -> Option<&'a RegistrationToken> {
+        let credentials = authorization?.strip_prefix("Bearer ")?;
+        tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token.expose().as_bytes(), credentials.as_bytes()))
+    }
+}
+
+/// Announcement of a tentacle, sent to the register endpoint.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Registration {
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Encodings of the log lines the tentacle can send besides JSON.
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
+}
+
+struct Registered {
+    registration: Registration,
+    /// Name of the token the tentacle registered with.
+    owner: String,
+    info: TentacleInfo,
+    last_seen: SystemTime,
+}
+
+/// A registration as it is kept in the state file.
+#[derive(Serialize, Deserialize)]
+struct StoredRegistration {
+    /// Registrations stored without an owner can only expire.
+    #[serde(default)]
+    owner: String,
+    #[serde(flatten)]
+    registration: Registration,
+}
+
+/// Tentacles which registered themselves and keep sending heartbeats.
+///
+/// Tentacles which did not send a heartbeat within the ttl are dropped. All
+/// registrations are published as one group of the tentacle set and are
+/// written to the optional state file on every change, so that they survive
+/// a restart.
+#[derive(Clone)]
+pub struct TentacleRegistry {
+    registered: Arc<Mutex<HashMap<String, Registered>>>,
+    tentacles: TentacleSet,
+    ttl: Duration,
+    state_file: Option<PathBuf>,
+}
+
+/// Resolves a registration to the tentacle it announces, with the credentials
+/// and TLS settings of its token if there is one.
+fn parse_url(
+    name: &str,
+    registration: &Registration,
+    token: Option<&RegistrationToken>,
+) -> Result<TentacleInfo, RegistrationError> {
+    let uri = registration
+        .url
+        .parse::<Uri>()
+        .map_err(|_| RegistrationError::IllegalUrl)?;
+    let protocol = match uri.scheme_str() {
+        Some(scheme @ "http") | Some(scheme @ "https") => scheme.to_string(),
+        _ => return Err(RegistrationError::IllegalUrl),
+    };
+    let host = uri.host().ok_or(RegistrationError::IllegalUrl)?;
+    let port = match uri.port_part() {
+        Some(port) => port.as_u16(),
+        None if protocol == "https" => 443,
+        None => 80,
+    };
+    let tls = token
+        .and_then(|t| t.tls.clone())
+        .filter(|_| protocol == "https");
+    Ok(TentacleInfo {
+        name: name.to_string(),
+        host: host.to_string(),
+        port: i64::from(port),
+        protocol,
+        labels: registration.labels.clone(),
+        auth: token.and_then(|t| t.auth.clone()),
+        tls,
+        encodings: registration.encodings.clone(),
+    })
+}
+
+impl TentacleRegistry {
+    pub fn new(
+        tentacles: TentacleSet,
+        ttl: Duration,
+        state_file: Option<PathBuf>,
+    ) -> TentacleRegistry {
+        TentacleRegistry {
+            registered: Arc::new(Mutex::new(HashMap::new())),
+            tentacles,
+            ttl,
+            state_file,
+        }
+    }
+
+    pub fn from_settings(settings: &Settings, tentacles: TentacleSet) -> TentacleRegistry {
+        TentacleRegistry::new(
+            tentacles,
+            Duration::from_secs(settings.registration.ttl_secs),
+            settings.registration.state_file.clone(),
+        )
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Registers a tentacle or updates an existing registration with the same
+    /// name, which only the token that registered the name may do. The
+    /// tentacle gets the labels of the token.
+    pub fn register(
+        &self,
+        mut registration: Registration,
+        token: &RegistrationToken,
+    ) -> Result<TentacleInfo, RegistrationError> {
+        let name = registration.name.trim().to_string();
+        if name.is_empty() {
+            return Err(RegistrationError::IllegalName);
+        }
+        registration.name = name.clone();
+        for (key, value) in &registration.labels {
+            if token.labels.get(key) != Some(value) {
+                return Err(RegistrationError::IllegalLabel(format!(
+                    "{}={}",
+                    key, value
+                )));
+            }
+        }
+        registration.labels = token.labels.clone();
+        let info = parse_url(&name, &registration, Some(token))?;
+        {
+            let mut registered = self.registered.lock().unwrap();
+            let taken = match registered.get(&name) {
+                Some(entry) => entry.owner != token.name,
+                None => self.tentacles.entries().iter().any(|e| e.info.name == name),
+            };
+            if taken {
+                return Err(RegistrationError::NameTaken);
+            }
+            registered.insert(
+                name,
+                Registered {
+                    registration,
+                    owner: token.name.clone(),
+                    info: info.clone(),
+                    last_seen: SystemTime::now(),
+                },
+            );
+            self.publish(&registered);
+        }
+        info!(
+            "Registered tentacle {} at {} with token {}",
+            info.name,
+            info.uri(),
+            token.name
+        );
+        Ok(info)
+    }
+
+    /// Keeps a registration alive, tentacles registered with another token
+    /// count as not registered.
+    pub fn heartbeat(
+        &self,
+        name: &str,
+        token: &RegistrationToken,
+    ) -> Result<(), RegistrationError> {
+        let mut registered = self.registered.lock().unwrap();
+        match registered.get_mut(name) {
+            Some(entry) if entry.owner == token.name => {
+                entry.last_seen = SystemTime::now();
+                Ok(())
+            }
+            _ => Err(RegistrationError::NotRegistered),
+        }
+    }
+
+    /// Drops all tentacles which did not send a heartbeat within the ttl.
+    pub fn expire(&self) {
+        let mut registered = self.registered.lock().unwrap();
+        let ttl = self.ttl;
+        let before = registered.len();
+        registered.retain(|name, entry| {
+            let alive = entry
+                .last_seen
+                .elapsed()
+                .map(|silent| silent <= ttl)
+                .unwrap_or(true);
+            if !alive {
+                info!("Tentacle {} missed its heartbeat, removing it", name);
+            }
+            alive
+        });
+        if registered.len() != before {
+            self.publish(&registered);
+        }
+    }
+
+    /// Restores the registrations from the state file. Restored tentacles
+    /// get a full ttl to send their next heartbeat and the credentials of
+    /// their token, which are never stored.
+    pub fn load(&self, tokens: &[RegistrationToken]) -> Result<(), Box<dyn Error>> {
+        let path = match &self.state_file {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let registrations: Vec<StoredRegistration> = serde_json::from_slice(&fs::read(path)?)?;
+        let mut registered = self.registered.lock().unwrap();
+        for StoredRegistration {
+            owner,
+            registration,
+        } in registrations
+        {
+            let name = registration.name.clone();
+            let token = tokens.iter().find(|t| t.name == owner);
+            match parse_url(&name, &registration, token) {
+                Ok(info) => {
+                    registered.insert(
+                        name,
+                        Registered {
+                            registration,
+                            owner,
+                            info,
+                            last_seen: SystemTime::now(),
+                        },
+                    );
+                }
+                Err(e) => warn!("Ignoring stored registration of {}: {}", name, e),
+            }
+        }
+        self.publish(&registered);
+        Ok(())
+    }
+
+    fn publish(&self, registered: &HashMap<String, Registered>) {
+        let mut infos: Vec<TentacleInfo> = registered.values().map(|r| r.info.clone()).collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        self.tentacles.update(REGISTRATION_PROVIDER, infos);
+        if let Err(e) = self.persist(registered) {
+            error!("Failed to write registration state: {}", e);
+        }
+    }
+
+    fn persist(&self, registered: &HashMap<String, Registered>) -> Result<(), Box<dyn Error>> {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut registrations: Vec<StoredRegistration> = registered
+            .values()
+            .map(|r| StoredRegistration {
+                owner: r.owner.clone(),
+                registration: r.registration.clone(),
+            })
+            .collect();
+        registrations.sort_by(|a, b| a.registration.name.cmp(&b.registration.name));
+        // write to a temporary file first, so a crash never leaves a truncated state
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&registrations)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Periodically drops the tentacles which missed their heartbeat.
+pub fn start_expiry(registry: TentacleRegistry) {
+    let check_every = registry.ttl() / 2;
+    actix::Arbiter::spawn(
+        Interval::new(Instant::now() + check_every, check_every)
+            .map_err(|e| error!("Registration expiry timer failed: {}", e))
+            .for_each(move |_| {
+                registry.expire();
+                Ok(())
+            }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registration::*;
+    use crate::testing::tentacle;
+
+    fn registration(name: &str, url: &str) -> Registration {
+        let mut labels = BTreeMap::new();
+        labels.insert(String::from("env"), String::from("prod"));
+        Registration {
+            name: String::from(name),
+            url: String::from(url),
+            labels,
+            encodings: vec![],
+        }
+    }
+
+    fn token(name: &str) -> RegistrationToken {
+        let mut labels = BTreeMap::new();
+        labels.insert(String::from("env"), String::from("prod"));
+        labels.insert(String::from("team"), String::from(name));
+        RegistrationToken {
+            name: String::from(name),
+            token: serde_json::from_str(&format!("\"{}-token\"", name)).unwrap(),
+            labels,
+            auth: None,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let tentacles = TentacleSet::new(vec![]);
+        let registry = TentacleRegistry::new(tentacles.clone(), Duration::from_secs(30), None);
+
+        let info = registry
+            .register(
+                registration("tentacle_1", "https://node1:18080"),
+                &token("app"),
+            )
+            .unwrap();
+        assert_eq!("node1", info.host);
+        assert_eq!(18080, info.port);
+        assert_eq!("https", info.protocol);
+        assert_eq!(Some(&String::from("prod")), info.labels.get("env"));
+        assert_eq!(Some(&String::from("app")), info.labels.get("team"));
+        assert_eq!(vec![info], tentacles.snapshot());
+
+        assert!(registry.heartbeat("tentacle_1", &token("app")).is_ok());
+        assert!(registry.heartbeat("tentacle_1", &token("ops")).is_err());
+        assert!(registry.heartbeat("tentacle_2", &token("app")).is_err());
+    }
+
+    #[test]
+    fn test_register_owned_names() {
+        let tentacles = TentacleSet::new(vec![tentacle("configured", 18080)]);
+        let registry = TentacleRegistry::new(tentacles.clone(), Duration::from_secs(30), None);
+        registry
+            .register(
+                registration("tentacle_1", "http://node1:18080"),
+                &token("app"),
+            )
+            .unwrap();
+
+        match registry.register(
+            registration("tentacle_1", "http://evil:18080"),
+            &token("ops"),
+        ) {
+            Err(RegistrationError::NameTaken) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match registry.register(
+            registration("configured", "http://evil:18080"),
+            &token("ops"),
+        ) {
+            Err(RegistrationError::NameTaken) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        let info = registry
+            .register(
+                registration("tentacle_1", "http://node2:18080"),
+                &token("app"),
+            )
+            .unwrap();
+        assert_eq!("node2", info.host);
+        assert_eq!(2, tentacles.snapshot().len());
+    }
+
+    #[test]
+    fn test_register_labels_of_token() {
+        let registry =
+            TentacleRegistry::new(TentacleSet::new(vec![]), Duration::from_secs(30), None);
+        let mut declared = registration("tentacle_1", "http://node1:18080");
+        declared
+            .labels
+            .insert(String::from("team"), String::from("ops"));
+        match registry.register(declared, &token("app")) {
+            Err(RegistrationError::IllegalLabel(label)) => assert_eq!("team=ops", label),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut undeclared = registration("tentacle_1", "http://node1:18080");
+        undeclared.labels.clear();
+        let info = registry.register(undeclared, &token("app")).unwrap();
+        assert_eq!(token("app").labels, info.labels);
+    }
+
+    #[test]
+    fn test_register_illegal() {
+        let registry =
+            TentacleRegistry::new(TentacleSet::new(vec![]), Duration::from_secs(30), None);
+        assert!(registry
+            .register(registration("", "http://node1"), &token("app"))
+            .is_err());
+        assert!(registry
+            .register(registration("t", "node1:8080"), &token("app"))
+            .is_err());
+        assert!(registry
+            .register(registration("t", "ftp://node1"), &token("app"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_expire() {
+        let tentacles = TentacleSet::new(vec![]);
+        let registry = TentacleRegistry::new(tentacles.clone(), Duration::from_millis(0), None);
+        registry
+            .register(
+                registration("tentacle_1", "http://node1:18080"),
+                &token("app"),
+            )
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        registry.expire();
+        assert!(tentacles.snapshot().is_empty());
+        assert!(registry.heartbeat("tentacle_1", &token("app")).is_err());
+    }
+
+    fn token_with_credentials(name: &str) -> RegistrationToken {
+        RegistrationToken {
+            auth: Some(TentacleAuth::Bearer {
+                token: Secret::from(String::from("tentacle-secret")),
+            }),
+            tls: Some(TentacleTls {
+                insecure: true,
+                ..TentacleTls::default()
+            }),
+            ..token(name)
+        }
+    }
+
+    #[test]
+    fn test_register_credentials_of_token() {
+        let registry =
+            TentacleRegistry::new(TentacleSet::new(vec![]), Duration::from_secs(30), None);
+        let token = token_with_credentials("app");
+        let https = registry
+            .register(registration("tentacle_1", "https://node1:18080"), &token)
+            .unwrap();
+        assert_eq!(token.auth, https.auth);
+        assert_eq!(token.tls, https.tls);
+        let http = registry
+            .register(registration("tentacle_2", "http://node2:18080"), &token)
+            .unwrap();
+        assert_eq!(token.auth, http.auth);
+        assert_eq!(None, http.tls);
+    }
+
+    #[test]
+    fn test_persist_and_load() {
+        let path =
+            std::env::temp_dir().join(format!("logtopus-registry-{}.json", std::process::id()));
+        let registry = TentacleRegistry::new(
+            TentacleSet::new(vec![]),
+            Duration::from_secs(30),
+            Some(path.clone()),
+        );
+        let info = registry
+            .register(
+                registration("tentacle_1", "https://node1:18080"),
+                &token_with_credentials("app"),
+            )
+            .unwrap();
+
+        let tentacles = TentacleSet::new(vec![]);
+        let restored = TentacleRegistry::new(
+            tentacles.clone(),
+            Duration::from_secs(30),
+            Some(path.clone()),
+        );
+        restored
+            .load(&[token("ops"), token_with_credentials("app")])
+            .unwrap();
+        assert_eq!(vec![info], tentacles.snapshot());
+        assert!(restored.heartbeat("tentacle_1", &token("app")).is_ok());
+        assert!(restored.heartbeat("tentacle_1", &token("ops")).is_err());
+        let state = fs::read_to_string(&path).unwrap();
+        assert!(!state.contains("app-token"));
+        assert!(!state.contains("tentacle-secret"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::cfg::{read_settings, SettingsHandle};
+use crate::discovery::{providers, Discovery};
+use crate::server_tls::TlsAcceptor;
+use crate::tentacle_set::TentacleSet;
+use actix::actors::signal::{ProcessSignals, Signal, SignalType, Subscribe};
+use actix::{Actor, AsyncContext, Context, Handler, System};
+use log::*;
+use std::error::Error;
+
+/// Re-reads the configuration files on SIGHUP and applies it to the running
+/// server. A configuration which fails validation is logged and ignored.
+/// Queries already running keep the tentacles they were started with, the
+/// bind address, the registration ttl, the registration state file and the
+/// admin overlay file cannot be changed without a restart. The certificate
+/// and key files of a https server are read again, serving https can only be
+/// switched on or off with a restart.
+pub struct ConfigReloader {
+    config_files: Vec<String>,
+    settings: SettingsHandle,
+    tentacles: TentacleSet,
+    discovery: Discovery,
+    tls: Option<TlsAcceptor>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        config_files: Vec<String>,
+        settings: SettingsHandle,
+        tentacles: TentacleSet,
+        discovery: Discovery,
+        tls: Option<TlsAcceptor>,
+    ) -> ConfigReloader {
+        ConfigReloader {
+            config_files,
+            settings,
+            tentacles,
+            discovery,
+            tls,
+        }
+    }
+
+    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        let settings = read_settings(&self.config_files)?;
+
+        if self.settings.get().http.bind != settings.http.bind {
+            warn!("Changed setting http.bind requires a restart to take effect");
+        }
+        if self.settings.get().http.cors != settings.http.cors {
+            warn!("Changed setting http.cors requires a restart to take effect");
+        }
+        if self.settings.get().registration.ttl_secs != settings.registration.ttl_secs {
+            warn!("Changed setting registration.ttl_secs requires a restart to take effect");
+        }
+        if self.settings.get().registration.state_file != settings.registration.state_file {
+            warn!("Changed setting registration.state_file requires a restart to take effect");
+        }
+        if self.settings.get().admin.overlay_file != settings.admin.overlay_file {
+            warn!("Changed setting admin.overlay_file requires a restart to take effect");
+        }
+        let tls_context = match (&self.tls, &settings.http.tls) {
+            (Some(_), Some(tls)) => Some(tls.context()?),
+            (None, None) => None,
+            _ => {
+                warn!("Switching https on or off requires a restart to take effect");
+                None
+            }
+        };
+
+        // everything is valid, queries see either the old settings and
+        // tentacles or the new ones
+        let providers = providers(&settings.discovery);
+        let tentacles = &self.tentacles;
+        self.settings.replace_with(settings, |settings| {
+            tentacles.set_base(settings.tentacle_infos())
+        });
+        if let (Some(acceptor), Some(context)) = (&self.tls, tls_context) {
+            acceptor.set_context(context);
+        }
+        self.discovery.start(providers);
+        Ok(())
+    }
+}
+
+impl Actor for ConfigReloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let signals = System::current().registry().get::<ProcessSignals>();
+        signals.do_send(Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<Signal> for ConfigReloader {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, _: &mut Context<Self>) {
+        if let SignalType::Hup = msg.0 {
+            info!("SIGHUP received, reloading configuration");
+            match self.reload() {
+                Ok(()) => info!("Configuration reloaded"),
+                Err(e) => error!("Failed to reload configuration, keeping the old one: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::{read_settings, SettingsHandle};
+    use crate::discovery::Discovery;
+    use crate::reload::ConfigReloader;
+    use crate::server_tls::{ServerTls, TlsAcceptor};
+    use crate::tentacle_set::TentacleSet;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("logtopus-reload-{}.yml", std::process::id()));
+        let filename = path.to_str().unwrap().to_string();
+        fs::write(
+            &path,
+            "auth.allow_anonymous: true\ntentacles:\n  - host: localhost\n    port: 18080\n",
+        )
+        .unwrap();
+
+        let loaded = read_settings(&[&filename]).unwrap();
+        let tentacles = TentacleSet::new(loaded.tentacle_infos());
+        let settings = SettingsHandle::new(loaded);
+        let mut reloader = ConfigReloader::new(
+            vec![filename.clone()],
+            settings.clone(),
+            tentacles.clone(),
+            Discovery::new(tentacles.clone()),
+            None,
+        );
+
+        fs::write(
+            &path,
+            "http.bind.port: 28081\nauth.allow_anonymous: true\ntentacles:\n  - host: localhost\n    port: 18081\n",
+        )
+        .unwrap();
+        reloader.reload().unwrap();
+        assert_eq!(28081, settings.get().http.bind.port);
+        assert_eq!(18081, tentacles.snapshot()[0].port);
+
+        // an invalid tentacle keeps the old configuration
+        fs::write(
+            &path,
+            "auth.allow_anonymous: true\ntentacles:\n  - port: 18082\n",
+        )
+        .unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(28081, settings.get().http.bind.port);
+        assert_eq!(18081, tentacles.snapshot()[0].port);
+
+        // as does a certificate which cannot be read
+        let tls = ServerTls {
+            cert: PathBuf::from("tests/tls/server.pem"),
+            key: PathBuf::from("tests/tls/server.key"),
+            client_ca: None,
+        };
+        let mut reloader = ConfigReloader::new(
+            vec![filename],
+            settings.clone(),
+            tentacles.clone(),
+            Discovery::new(tentacles.clone()),
+            Some(TlsAcceptor::new(&tls).unwrap()),
+        );
+        fs::write(
+            &path,
+            "http.tls:\n  cert: tests/tls/missing.pem\n  key: tests/tls/server.key\ntentacles:\n  - host: localhost\n    port: 18083\n",
+        )
+        .unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(None, settings.get().http.tls);
+        assert_eq!(18081, tentacles.snapshot()[0].port);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
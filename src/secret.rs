@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt;
+use std::fs;
+
+/// Shown instead of secret values.
+pub const REDACTED: &str = "<redacted>";
+
+/// A setting which must never show up in logs or printed configurations.
+/// Serializing and debug printing it yields `<redacted>`, the value is only
+/// available through `expose`. Secrets given at runtime keep the reference
+/// they were resolved from and serialize to it, so they can be stored.
+#[derive(Clone, Deserialize)]
+#[serde(from = "String")]
+pub struct Secret {
+    value: String,
+    reference: Option<String>,
+}
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    /// Resolves a setting which has to consist of `${env:VAR}` or
+    /// `${file:path}` references, plain values cannot be stored safely.
+    pub fn from_reference(reference: &str) -> Result<Secret, String> {
+        match resolve_references(reference)? {
+            Some(value) => Ok(Secret {
+                value,
+                reference: Some(reference.to_string()),
+            }),
+            None => Err(String::from(
+                "must be a ${env:VAR} or ${file:path} reference",
+            )),
+        }
+    }
+
+    /// Whether the secret was read back after it was serialized without a reference.
+    pub fn is_redacted(&self) -> bool {
+        self.reference.is_none() && self.value == REDACTED
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Secret {
+        Secret {
+            value,
+            reference: None,
+        }
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Secret) -> bool {
+        self.value == other.value
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.reference.as_deref().unwrap_or(REDACTED))
+    }
+}
+
+fn lookup(reference: &str) -> Result<String, String> {
+    let mut parts = reference.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("env"), Some(var)) => {
+            env::var(var).map_err(|_| format!("environment variable {} is not set", var))
+        }
+        (Some("file"), Some(path)) => fs::read_to_string(path)
+            .map(|content| content.trim_end_matches(&['\r', '\n'][..]).to_string())
+            .map_err(|e| format!("failed to read {}: {}", path, e)),
+        _ => Err(format!(
+            "unknown reference ${{{}}}, expected ${{env:VAR}} or ${{file:path}}",
+            reference
+        )),
+    }
+}
+
+/// Replaces the `${env:VAR}` and `${file:path}` references in a setting by
+/// the value of the environment variable and the content of the file, without
+/// trailing line breaks. `$${` stands for a literal `${`. Returns `None` if
+/// the value contains no references.
+pub fn resolve_references(value: &str) -> Result<Option<String>, String> {
+    if !value.contains("${") {
+        return Ok(None);
+    }
+    let mut resolved = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            // `$${` is an escaped `${`
+            resolved.push_str(&rest[..start - 1]);
+            resolved.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        resolved.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| String::from("unterminated reference, missing }"))?;
+        resolved.push_str(&lookup(&rest[start + 2..start + end])?);
+        rest = &rest[start + end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(Some(resolved))
+}
+
+/// Replaces the values at the given setting paths, e.g. `tentacles[0].auth.token`,
+/// in the JSON form of the settings by `<redacted>`.
+pub fn redact(json: &mut serde_json::Value, paths: &BTreeSet<String>) {
+    for path in paths {
+        let mut target = Some(&mut *json);
+        for segment in path.split(['.', '[']) {
+            target = match (target, segment.strip_suffix(']')) {
+                (Some(value), Some(idx)) => {
+                    idx.parse().ok().and_then(move |i: usize| value.get_mut(i))
+                }
+                (Some(value), None) => value.get_mut(segment),
+                (None, _) => None,
+            };
+        }
+        if let Some(value) = target {
+            *value = serde_json::Value::String(String::from(REDACTED));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secret::*;
+
+    #[test]
+    fn test_resolve_references() {
+        env::set_var("LOGTOPUS_TEST_SECRET", "s3cret");
+        let path = env::temp_dir().join(format!("logtopus-secret-{}", std::process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+
+        assert_eq!(None, resolve_references("plain").unwrap());
+        assert_eq!(
+            Some(String::from("Bearer s3cret")),
+            resolve_references("Bearer ${env:LOGTOPUS_TEST_SECRET}").unwrap()
+        );
+        assert_eq!(
+            Some(String::from("from-file/s3cret")),
+            resolve_references(&format!(
+                "${{file:{}}}/${{env:LOGTOPUS_TEST_SECRET}}",
+                path.display()
+            ))
+            .unwrap()
+        );
+        assert_eq!(
+            Some(String::from("${env:X}")),
+            resolve_references("$${env:X}").unwrap()
+        );
+        assert!(resolve_references("${env:LOGTOPUS_TEST_UNSET}").is_err());
+        assert!(resolve_references("${vault:x}").is_err());
+        assert!(resolve_references("${env:X").is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_redact() {
+        let mut json = serde_json::json!({
+            "admin": {"token": "t"},
+            "tentacles": [{"host": "a"}, {"host": "b", "auth": {"token": "t"}}]
+        });
+        let paths = vec!["admin.token", "tentacles[1].auth.token", "missing.key"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        redact(&mut json, &paths);
+        assert_eq!(REDACTED, json["admin"]["token"]);
+        assert_eq!(REDACTED, json["tentacles"][1]["auth"]["token"]);
+        assert_eq!("a", json["tentacles"][0]["host"]);
+        assert_eq!(
+            "Secret(<redacted>)",
+            format!("{:?}", Secret::from(String::from("t")))
+        );
+    }
+
+    #[test]
+    fn test_from_reference() {
+        env::set_var("LOGTOPUS_TEST_REFERENCE", "s3cret");
+        let secret = Secret::from_reference("${env:LOGTOPUS_TEST_REFERENCE}").unwrap();
+        assert_eq!("s3cret", secret.expose());
+        assert_eq!(
+            "\"${env:LOGTOPUS_TEST_REFERENCE}\"",
+            serde_json::to_string(&secret).unwrap()
+        );
+        assert!(Secret::from_reference("s3cret").is_err());
+        assert!(Secret::from_reference("${env:LOGTOPUS_TEST_UNSET}").is_err());
+
+        let redacted: Secret =
+            serde_json::from_str(&serde_json::to_string(&Secret::from(String::from("t"))).unwrap())
+                .unwrap();
+        assert!(redacted.is_redacted());
+        assert!(!secret.is_redacted());
+    }
+}
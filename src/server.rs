@@ -1,125 +1,834 @@
 extern crate actix;
 extern crate actix_web;
 
-use crate::tentacle::{TentacleClient, TentacleConfigError};
-use actix_web::{HttpResponse, Query, State};
-use bytes::BufMut;
+use crate::admin::{admin_scope, TentacleAdmin};
+use crate::arrow_export::{ArrowEncoder, ExportFormat, ARROW_STREAM, PARQUET};
+use crate::audit::{AuditEntry, AuditFilters, AuditRecord, TimeRange};
+use crate::auth::{ApiAuth, Principal};
+use crate::cancel::{client_connections, plain_acceptor, ClientClosed, StreamCounters};
+use crate::cfg::SettingsHandle;
+use crate::color::{ColorMode, TentacleColors};
+use crate::columnar::{ColumnarEncoder, COLUMNS_JSON};
+use crate::compression::Compressed;
+use crate::delimited::{DelimitedFormat, Delimiter};
+use crate::encoding::{Encoding, CBOR, MSGPACK};
+use crate::limits::{client_key, too_many_requests, QueryLimiter, RateLimiter, RateLimits};
+use crate::log_file::LogFile;
+use crate::queries::{QueryEvent, QueryFilters, QueryInfo, QueryRegistry};
+use crate::rbac::Access;
+use crate::registration::{Registration, RegistrationError, RegistrationToken, TentacleRegistry};
+use crate::server_tls::TlsAcceptor;
+use crate::tentacle::{TentacleClient, TentacleClientError};
+use crate::tentacle_set::TentacleSet;
+use crate::text_format::TextFormat;
+use crate::ui::ui_app;
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::error::PayloadError;
+use actix_web::http::{header, ContentEncoding};
+use actix_web::{HttpRequest, HttpResponse, Json, Query};
 use bytes::Bytes;
-use chrono::NaiveDateTime;
-use config;
-use config::Config;
-use futures::Stream;
-use serde::Deserialize;
-use std::str::FromStr;
-use std::sync::Arc;
+use chrono::Utc;
+use futures::{stream, Stream};
+use log::*;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Debug)]
 struct Filter {
     from_ms: Option<u64>,
     loglevels: Option<String>,
+    /// A template or the name of a preset of the text output.
+    format: Option<String>,
+    /// The IANA time zone of timestamps in the text output.
+    tz: Option<String>,
+    /// ANSI colors in the text output, none by default.
+    color: Option<ColorMode>,
+    /// The comma separated columns of CSV and TSV output.
+    columns: Option<String>,
+    /// Whether CSV and TSV output starts with a header row, by default it does.
+    header: Option<bool>,
 }
 
-pub fn start_server(settings: Arc<Config>) {
-    let port = settings.get_int("http.bind.port").unwrap();
-    let ip = settings.get_str("http.bind.ip").unwrap();
-    let addr: std::net::SocketAddr = format!("{}:{}", ip, port).parse().unwrap();
-    let state_factory = ServerStateFactory::from_settings(settings);
+/// The last line of a JSON stream ended by an error.
+#[derive(Serialize, Debug)]
+struct ErrorEvent {
+    error: String,
+}
+
+#[derive(Serialize, Debug)]
+struct RegistrationResponse {
+    name: String,
+    ttl_secs: u64,
+}
+
+pub struct ServerState {
+    settings: SettingsHandle,
+    client: TentacleClient,
+    registry: TentacleRegistry,
+    pub admin: TentacleAdmin,
+    queries: QueryLimiter,
+    audit: LogFile,
+    pub streams: StreamCounters,
+    query_registry: QueryRegistry,
+}
 
-    actix_web::server::new(move || {
-        actix_web::App::with_state(state_factory.create_state().unwrap())
+pub fn start_server(
+    settings: SettingsHandle,
+    tentacles: TentacleSet,
+    registry: TentacleRegistry,
+    admin: TentacleAdmin,
+    tls: Option<TlsAcceptor>,
+) {
+    if !settings.get().auth.is_enabled() {
+        warn!("auth.allow_anonymous is set, all sources can be read anonymously");
+    }
+    let bind = settings.get().http.bind.clone();
+    let addr = std::net::SocketAddr::new(bind.ip, bind.port);
+    let state_factory = ServerStateFactory::from_settings(settings, tentacles, registry, admin);
+
+    let server = actix_web::server::new(move || {
+        let admin_settings = state_factory.settings.clone();
+        let mut app = actix_web::App::with_state(state_factory.create_state())
             // enable logger
-            .middleware(actix_web::middleware::Logger::default())
+            .middleware(actix_web::middleware::Logger::default());
+        // preflight requests carry no credentials, they are answered before authentication
+        if let Some(cors) = &state_factory.settings.get().http.cors {
+            app = app.middleware(cors.middleware());
+        }
+        let api = app
+            // the admin API and tentacle registration have their own tokens
+            .middleware(
+                ApiAuth::new(state_factory.settings.clone())
+                    .allow_anonymous("/api/v1/health")
+                    .skip("/api/v1/admin")
+                    .skip("/api/v1/tentacles"),
+            )
+            // rate limits tell clients apart by the principal set by ApiAuth
+            .middleware(RateLimits::new(
+                state_factory.settings.clone(),
+                state_factory.rates.clone(),
+            ))
             .prefix("/api/v1")
             .resource("/health", |r| r.get().f(|_| HttpResponse::Ok()))
             .resource("/sources/{id}/content", |r| {
                 r.get()
                     .filter(actix_web::pred::Header("Accept", "application/json"))
                     .with(stream_json);
+                r.get()
+                    .filter(actix_web::pred::Header("Accept", ARROW_STREAM))
+                    .with(stream_arrow);
+                r.get()
+                    .filter(actix_web::pred::Header("Accept", PARQUET))
+                    .with(stream_parquet);
+                r.get()
+                    .filter(actix_web::pred::Header("Accept", MSGPACK))
+                    .with(stream_msgpack);
+                r.get()
+                    .filter(actix_web::pred::Header("Accept", CBOR))
+                    .with(stream_cbor);
+                r.get()
+                    .filter(actix_web::pred::Header("Accept", COLUMNS_JSON))
+                    .with(stream_columns);
                 r.get()
                     .filter(actix_web::pred::Header("Accept", "text/plain"))
                     .with(stream_text);
+                r.get()
+                    .filter(actix_web::pred::Header("Accept", "text/csv"))
+                    .with(stream_csv);
+                r.get()
+                    .filter(actix_web::pred::Header(
+                        "Accept",
+                        "text/tab-separated-values",
+                    ))
+                    .with(stream_tsv);
                 r.get()
                     .filter(actix_web::pred::Header("Accept", "*/*"))
                     .with(stream_text);
                 r.get().f(|_| HttpResponse::NotAcceptable());
                 r.f(|_| HttpResponse::MethodNotAllowed());
             })
-    })
-    .bind(addr)
-    .expect(&format!("Failed to bind to {}:{}", ip, port))
+            .resource("/queries", |r| {
+                r.get().with(list_queries);
+                r.f(|_| HttpResponse::MethodNotAllowed());
+            })
+            .resource("/queries/{id}", |r| {
+                r.delete().with(kill_query);
+                r.f(|_| HttpResponse::MethodNotAllowed());
+            })
+            .resource("/tentacles/register", |r| {
+                r.post().with(register_tentacle);
+                r.f(|_| HttpResponse::MethodNotAllowed());
+            })
+            .resource("/tentacles/{name}/heartbeat", |r| {
+                r.post().with(heartbeat_tentacle);
+                r.f(|_| HttpResponse::MethodNotAllowed());
+            })
+            .scope("/admin", |scope| admin_scope(scope, admin_settings.clone()));
+        // everything outside the API is left to the web UI
+        vec![api.boxed(), ui_app(state_factory.settings.clone()).boxed()]
+    });
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    // connections note the client going away, which cancels its queries
+    match tls {
+        Some(acceptor) => server.bind_with(addr, move || client_connections(acceptor.clone())),
+        None => server.bind_with(addr, plain_acceptor),
+    }
+    .unwrap_or_else(|_| panic!("Failed to bind to {}", addr))
     .start();
 
-    println!("Started http server: {:?}", addr);
+    println!("Started {} server: {:?}", scheme, addr);
+}
+
+/// The access of the authenticated client, checked by every handler reading sources.
+fn access(req: &HttpRequest<ServerState>) -> Access {
+    Access::new(
+        &req.state().settings.get(),
+        req.extensions().get::<Principal>(),
+    )
+}
+
+fn forbidden(id: &str) -> HttpResponse {
+    HttpResponse::Forbidden().body(format!("Access to source {} denied", id))
+}
+
+/// Checks the access and the limits of a content query and starts it. The
+/// query stays registered, counted as running and is audited until the
+/// stream is dropped.
+fn query_logs(
+    id: &str,
+    filter: &Filter,
+    req: &HttpRequest<ServerState>,
+) -> Result<Box<dyn Stream<Item = QueryEvent, Error = TentacleClientError>>, HttpResponse> {
+    let state = req.state();
+    // the access and the tentacles are read from the same configuration
+    let streams = state.settings.read(|settings| {
+        let access = Access::new(settings, req.extensions().get::<Principal>());
+        if !access.may_read(id) {
+            return Err(forbidden(id));
+        }
+        Ok(state.client.query_tentacles(
+            String::from(id),
+            filter.from_ms.unwrap_or(0),
+            &filter.loglevels,
+            &|tentacle| access.may_query(id, tentacle),
+        ))
+    })?;
+    let permit = state
+        .queries
+        .start(&state.settings.get().limits, client_key(req))
+        .map_err(too_many_requests)?;
+    let principal = req
+        .extensions()
+        .get::<Principal>()
+        .map(|principal| principal.name.clone());
+    let query = state.query_registry.start(
+        principal.clone(),
+        String::from(id),
+        QueryFilters {
+            from_ms: filter.from_ms.unwrap_or(0),
+            loglevels: filter.loglevels.clone(),
+        },
+    );
+    let tentacles = streams.iter().map(|(name, _)| name.clone()).collect();
+    let log_stream = TentacleClient::merge(
+        streams
+            .into_iter()
+            .map(|(name, stream)| query.track(name, stream))
+            .collect(),
+    );
+    let mut audit = AuditRecord::new(
+        state.settings.clone(),
+        state.audit.clone(),
+        AuditEntry {
+            timestamp: Utc::now(),
+            principal,
+            client_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            sources: vec![String::from(id)],
+            filters: AuditFilters {
+                loglevels: filter.loglevels.clone(),
+            },
+            time_range: TimeRange {
+                from_ms: filter.from_ms.unwrap_or(0),
+                to_ms: None,
+            },
+            tentacles,
+            lines: 0,
+            duration_ms: 0,
+        },
+    );
+    let events = state
+        .streams
+        .count(query.events(log_stream).map(move |event| {
+            let _running = &permit;
+            if let QueryEvent::Line(log_line) = &event {
+                audit.line(log_line);
+            }
+            event
+        }));
+    // only queries are aborted when the client goes away, other requests
+    // are answered to clients which shut down their sending side
+    match ClientClosed::of(req) {
+        Some(closed) => Ok(Box::new(closed.abort(events))),
+        None => Ok(Box::new(events)),
+    }
+}
+
+/// Streams a response body, compressed with the coding negotiated from the
+/// `Accept-Encoding` header, see `Compressed`. The compression of actix is
+/// turned off for it, as it neither flushes nor knows zstd.
+fn streaming<S>(
+    req: &HttpRequest<ServerState>,
+    response: &mut HttpResponseBuilder,
+    body: S,
+) -> HttpResponse
+where
+    S: Stream<Item = Bytes, Error = PayloadError> + 'static,
+{
+    let compression = req.state().settings.get().compression.clone();
+    let coding = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|accept_encoding| compression.negotiate(accept_encoding));
+    response
+        .content_encoding(ContentEncoding::Identity)
+        .header(header::VARY, "Accept-Encoding");
+    let coding = match coding {
+        Some(coding) => coding,
+        None => return response.streaming(body),
+    };
+    match Compressed::new(body, coding, &compression) {
+        Ok(compressed) => response
+            .header(header::CONTENT_ENCODING, coding.name())
+            .streaming(compressed),
+        Err(e) => {
+            error!("Failed to start {} compression: {}", coding.name(), e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
 }
 
 fn stream_json(
     id: actix_web::Path<String>,
     filter: Query<Filter>,
-    state: State<TentacleClient>,
+    req: HttpRequest<ServerState>,
 ) -> HttpResponse {
-    let log_stream = state.stream_logs(
-        String::from_str(id.as_str()).unwrap(),
-        filter.from_ms.unwrap_or(0),
-        &filter.loglevels,
-    );
-    HttpResponse::Ok()
-        .header("Content-Type", "application/json")
-        .streaming(
-            log_stream
-                .map(move |log_line| {
-                    let mut json = serde_json::to_vec(&log_line).unwrap();
-                    json.put_u8('\n' as u8);
-                    Bytes::from(json)
+    stream_encoded(id, filter, req, Encoding::Json)
+}
+
+fn stream_msgpack(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+) -> HttpResponse {
+    stream_encoded(id, filter, req, Encoding::Msgpack)
+}
+
+fn stream_cbor(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+) -> HttpResponse {
+    stream_encoded(id, filter, req, Encoding::Cbor)
+}
+
+/// Streams the lines as JSON lines or length prefixed binary values, a stream
+/// ended by an error ends with an `ErrorEvent`.
+fn stream_encoded(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+    encoding: Encoding,
+) -> HttpResponse {
+    let log_stream = match query_logs(id.as_str(), &filter, &req) {
+        Ok(log_stream) => log_stream,
+        Err(response) => return response,
+    };
+    streaming(
+        &req,
+        HttpResponse::Ok().header("Content-Type", encoding.content_type()),
+        log_stream
+            .map(move |event| {
+                Bytes::from(match event {
+                    QueryEvent::Line(log_line) => encoding.encode(&log_line),
+                    QueryEvent::Error(error) => encoding.encode(&ErrorEvent { error }),
                 })
-                .map_err(|_| actix_web::error::PayloadError::Incomplete),
-        )
+            })
+            .map_err(|_| PayloadError::Incomplete),
+    )
+}
+
+/// Like `stream_json`, but with the lines as value arrays, see `ColumnarEncoder`.
+fn stream_columns(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+) -> HttpResponse {
+    let log_stream = match query_logs(id.as_str(), &filter, &req) {
+        Ok(log_stream) => log_stream,
+        Err(response) => return response,
+    };
+    let mut encoder = ColumnarEncoder::default();
+    streaming(
+        &req,
+        HttpResponse::Ok().header("Content-Type", COLUMNS_JSON),
+        log_stream
+            .map(move |event| match event {
+                QueryEvent::Line(log_line) => Bytes::from(encoder.encode(&log_line)),
+                QueryEvent::Error(error) => {
+                    Bytes::from(Encoding::Json.encode(&ErrorEvent { error }))
+                }
+            })
+            .map_err(|_| PayloadError::Incomplete),
+    )
+}
+
+fn stream_arrow(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+) -> HttpResponse {
+    stream_export(id, filter, req, ExportFormat::ArrowStream)
+}
+
+fn stream_parquet(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+) -> HttpResponse {
+    stream_export(id, filter, req, ExportFormat::Parquet)
+}
+
+/// Streams the lines in batches for offline analysis. Batches cannot carry
+/// errors, so a query ended by an error aborts the response instead.
+fn stream_export(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+    format: ExportFormat,
+) -> HttpResponse {
+    let mut encoder = match ArrowEncoder::new(format) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            error!("Failed to start {} export: {}", format.content_type(), e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let log_stream = match query_logs(id.as_str(), &filter, &req) {
+        Ok(log_stream) => log_stream,
+        Err(response) => return response,
+    };
+    let source = id.into_inner();
+    let mut response = HttpResponse::Ok();
+    response.header("Content-Type", format.content_type());
+    if format == ExportFormat::Parquet {
+        let file_name: String = source
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+                _ => '_',
+            })
+            .collect();
+        response.header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.parquet\"", file_name),
+        );
+    }
+    let body = log_stream
+        .map(Some)
+        .chain(stream::once(Ok(None)))
+        .and_then(move |event| {
+            let encoded = match event {
+                Some(QueryEvent::Line(log_line)) => encoder.push(&log_line),
+                Some(QueryEvent::Error(error)) => {
+                    warn!("Aborted export of {}: {}", source, error);
+                    return Err(TentacleClientError::ClientError);
+                }
+                None => encoder.finish(),
+            };
+            encoded.map_err(|e| {
+                error!("Failed to export {}: {}", source, e);
+                TentacleClientError::ClientError
+            })
+        })
+        // a chunk is only written when a batch is full
+        .filter(|chunk| !chunk.is_empty())
+        .map_err(|_| PayloadError::Incomplete);
+    if format == ExportFormat::Parquet {
+        // the columns are compressed already
+        response.streaming(body)
+    } else {
+        streaming(&req, &mut response, body)
+    }
 }
 
 fn stream_text(
     id: actix_web::Path<String>,
     filter: Query<Filter>,
-    state: State<TentacleClient>,
+    req: HttpRequest<ServerState>,
 ) -> HttpResponse {
-    let log_stream = state.stream_logs(
-        String::from_str(id.as_str()).unwrap(),
-        filter.from_ms.unwrap_or(0),
-        &filter.loglevels,
-    );
-    HttpResponse::Ok()
-        .header("Content-Type", "text/plain")
-        .streaming(
-            log_stream
-                .map(move |log_line| {
-                    let timestamp = NaiveDateTime::from_timestamp(
-                        log_line.timestamp / 1000,
-                        ((log_line.timestamp % 1000) * 1_000_000) as u32,
-                    );
-                    let ts_string = timestamp.format("%H:%M:%S.%3f %d-%m-%Y");
-                    let text_line = format!("{} {}\n", ts_string, log_line.message);
-                    Bytes::from(text_line)
-                })
-                .map_err(|_| actix_web::error::PayloadError::Incomplete),
-        )
+    let mut text_format = match TextFormat::from_request(
+        filter.format.as_deref(),
+        filter.tz.as_deref(),
+        &req.state().settings.get().text_formats,
+    ) {
+        Ok(text_format) => text_format,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let user_agent = req
+        .headers()
+        .get(actix_web::http::header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok());
+    if filter.color.unwrap_or(ColorMode::Never).enabled(user_agent) {
+        let colors = TentacleColors::new(req.state().client.tentacle_names());
+        text_format = text_format.with_colors(colors);
+    }
+    let log_stream = match query_logs(id.as_str(), &filter, &req) {
+        Ok(log_stream) => log_stream,
+        Err(response) => return response,
+    };
+    streaming(
+        &req,
+        HttpResponse::Ok().header("Content-Type", "text/plain"),
+        log_stream
+            .map(move |event| match event {
+                QueryEvent::Line(log_line) => Bytes::from(text_format.format(&log_line)),
+                QueryEvent::Error(error) => Bytes::from(text_format.format_error(&error)),
+            })
+            .map_err(|_| PayloadError::Incomplete),
+    )
+}
+
+fn stream_csv(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+) -> HttpResponse {
+    stream_delimited(id, filter, req, Delimiter::Comma)
+}
+
+fn stream_tsv(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+) -> HttpResponse {
+    stream_delimited(id, filter, req, Delimiter::Tab)
+}
+
+fn stream_delimited(
+    id: actix_web::Path<String>,
+    filter: Query<Filter>,
+    req: HttpRequest<ServerState>,
+    delimiter: Delimiter,
+) -> HttpResponse {
+    let format = match DelimitedFormat::from_request(
+        delimiter,
+        filter.columns.as_deref(),
+        filter.tz.as_deref(),
+    ) {
+        Ok(format) => format,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let log_stream = match query_logs(id.as_str(), &filter, &req) {
+        Ok(log_stream) => log_stream,
+        Err(response) => return response,
+    };
+    let header = if filter.header.unwrap_or(true) {
+        Some(Bytes::from(format.header()))
+    } else {
+        None
+    };
+    streaming(
+        &req,
+        HttpResponse::Ok().header("Content-Type", delimiter.content_type()),
+        stream::iter_ok(header)
+            .chain(log_stream.map(move |event| match event {
+                QueryEvent::Line(log_line) => Bytes::from(format.format(&log_line)),
+                QueryEvent::Error(error) => Bytes::from(format.format_error(&error)),
+            }))
+            .map_err(|_| PayloadError::Incomplete),
+    )
+}
+
+/// Whether the client may see and kill a query, which it may if it started
+/// the query or has an admin role and may read the source.
+fn may_manage(
+    req: &HttpRequest<ServerState>,
+    access: &Access,
+    source: &str,
+    principal: Option<&String>,
+) -> bool {
+    let own = req.extensions().get::<Principal>().map(|p| &p.name) == principal;
+    access.may_read(source) && (own || access.is_admin())
+}
+
+/// The running queries of the client, or of all clients for admins, on
+/// sources the client may read.
+fn list_queries(req: HttpRequest<ServerState>) -> HttpResponse {
+    let access = access(&req);
+    let queries: Vec<QueryInfo> = req
+        .state()
+        .query_registry
+        .list()
+        .into_iter()
+        .filter(|query| may_manage(&req, &access, &query.source, query.principal.as_ref()))
+        .collect();
+    HttpResponse::Ok().json(queries)
+}
+
+fn kill_query(id: actix_web::Path<u64>, req: HttpRequest<ServerState>) -> HttpResponse {
+    let registry = &req.state().query_registry;
+    match registry.source_and_principal(*id) {
+        Some((source, principal))
+            if may_manage(&req, &access(&req), &source, principal.as_ref())
+                && registry.kill(*id) =>
+        {
+            HttpResponse::NoContent().finish()
+        }
+        _ => HttpResponse::NotFound().body(format!("No running query {}", id)),
+    }
+}
+
+/// The registration token the request is sent with, registration is
+/// refused without one.
+fn registration_token(req: &HttpRequest<ServerState>) -> Result<RegistrationToken, HttpResponse> {
+    req.state().settings.read(|settings| {
+        let tokens = &settings.registration.tokens;
+        if tokens.is_empty() {
+            return Err(HttpResponse::Forbidden().body("Tentacle registration is disabled"));
+        }
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        RegistrationToken::find(tokens, authorization)
+            .cloned()
+            .ok_or_else(|| {
+                HttpResponse::Unauthorized()
+                    .header(header::WWW_AUTHENTICATE, "Bearer")
+                    .finish()
+            })
+    })
+}
+
+fn register_tentacle(
+    (req, registration): (HttpRequest<ServerState>, Json<Registration>),
+) -> HttpResponse {
+    let token = match registration_token(&req) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    let registry = &req.state().registry;
+    match registry.register(registration.into_inner(), &token) {
+        Ok(info) => HttpResponse::Ok().json(RegistrationResponse {
+            name: info.name,
+            ttl_secs: registry.ttl().as_secs(),
+        }),
+        Err(e @ RegistrationError::NameTaken) => HttpResponse::Conflict().body(e.to_string()),
+        Err(e @ RegistrationError::IllegalLabel(_)) => {
+            HttpResponse::Forbidden().body(e.to_string())
+        }
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+fn heartbeat_tentacle(
+    (req, name): (HttpRequest<ServerState>, actix_web::Path<String>),
+) -> HttpResponse {
+    let token = match registration_token(&req) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    match req.state().registry.heartbeat(name.as_str(), &token) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::NotFound().body(e.to_string()),
+    }
 }
 
 struct ServerStateFactory {
-    settings: Arc<Config>,
+    settings: SettingsHandle,
+    tentacles: TentacleSet,
+    registry: TentacleRegistry,
+    admin: TentacleAdmin,
+    rates: RateLimiter,
+    queries: QueryLimiter,
+    audit: LogFile,
+    streams: StreamCounters,
+    query_registry: QueryRegistry,
 }
 
 impl Clone for ServerStateFactory {
     fn clone(&self) -> ServerStateFactory {
         ServerStateFactory {
             settings: self.settings.clone(),
+            tentacles: self.tentacles.clone(),
+            registry: self.registry.clone(),
+            admin: self.admin.clone(),
+            rates: self.rates.clone(),
+            queries: self.queries.clone(),
+            audit: self.audit.clone(),
+            streams: self.streams.clone(),
+            query_registry: self.query_registry.clone(),
         }
     }
 }
 
 impl ServerStateFactory {
-    fn from_settings(settings: Arc<Config>) -> ServerStateFactory {
-        ServerStateFactory { settings: settings }
+    fn from_settings(
+        settings: SettingsHandle,
+        tentacles: TentacleSet,
+        registry: TentacleRegistry,
+        admin: TentacleAdmin,
+    ) -> ServerStateFactory {
+        ServerStateFactory {
+            query_registry: QueryRegistry::new(settings.clone()),
+            settings,
+            tentacles,
+            registry,
+            admin,
+            rates: RateLimiter::default(),
+            queries: QueryLimiter::default(),
+            audit: LogFile::default(),
+            streams: StreamCounters::default(),
+        }
     }
 
-    fn create_state(&self) -> Result<TentacleClient, TentacleConfigError> {
-        TentacleClient::from_settings(self.settings.clone())
+    fn create_state(&self) -> ServerState {
+        ServerState {
+            settings: self.settings.clone(),
+            client: TentacleClient::new(self.tentacles.clone()),
+            registry: self.registry.clone(),
+            admin: self.admin.clone(),
+            queries: self.queries.clone(),
+            audit: self.audit.clone(),
+            streams: self.streams.clone(),
+            query_registry: self.query_registry.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::admin::TentacleAdmin;
+    use crate::cfg::{read_settings, SettingsHandle};
+    use crate::registration::TentacleRegistry;
+    use crate::server::start_server;
+    use crate::tentacle_set::TentacleSet;
+    use actix_web::test::TestServer;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Starts the server like `run` does, with a single tentacle.
+    fn start(tentacle: SocketAddr) -> SocketAddr {
+        let addr = TestServer::unused_addr();
+        let path = std::env::temp_dir().join(format!("logtopus-server-{}.yml", addr.port()));
+        std::fs::write(
+            &path,
+            format!(
+                "http.bind.port: {}\nadmin.token: adm\nauth.allow_anonymous: true\ntentacles:\n  - host: 127.0.0.1\n    port: {}\n",
+                addr.port(),
+                tentacle.port()
+            ),
+        )
+        .unwrap();
+        let settings = read_settings(&[path.to_str().unwrap()]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        thread::spawn(move || {
+            let sys = actix::System::new("test");
+            let tentacles = TentacleSet::new(settings.tentacle_infos());
+            let registry = TentacleRegistry::from_settings(&settings, tentacles.clone());
+            let admin = TentacleAdmin::from_settings(&settings, tentacles.clone());
+            start_server(
+                SettingsHandle::new(settings),
+                tentacles,
+                registry,
+                admin,
+                None,
+            );
+            sys.run();
+        });
+        let started = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(20));
+        }
+        addr
+    }
+
+    fn request(addr: SocketAddr, request: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream
+    }
+
+    fn response(mut stream: TcpStream) -> String {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_client_disconnect() {
+        // a tentacle which never answers, reporting when the query reached it
+        // and when the query closed its connection
+        let tentacle = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tentacle_addr = tentacle.local_addr().unwrap();
+        let (events, received) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut connection, _) = tentacle.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = connection.read(&mut buf);
+            events.send("queried").unwrap();
+            while let Ok(len) = connection.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+            }
+            events.send("closed").unwrap();
+        });
+        let addr = start(tentacle_addr);
+
+        let mut client = request(
+            addr,
+            "GET /api/v1/sources/app/content HTTP/1.1\r\nHost: logtopus\r\nAccept: application/json\r\n\r\n",
+        );
+        let timeout = Duration::from_secs(10);
+        assert_eq!(Ok("queried"), received.recv_timeout(timeout));
+        // with unread data the client would reset the connection instead of
+        // closing it, which actix-web notices by itself
+        let mut head = vec![];
+        let mut byte = [0u8];
+        while !head.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(client);
+        assert_eq!(Ok("closed"), received.recv_timeout(timeout));
+
+        let streams = response(request(
+            addr,
+            "GET /api/v1/admin/streams HTTP/1.1\r\nHost: logtopus\r\nAuthorization: Bearer adm\r\nConnection: close\r\n\r\n",
+        ));
+        assert!(
+            streams.ends_with(r#"{"active":0,"completed":0,"cancelled":1}"#),
+            "{}",
+            streams
+        );
+
+        // other requests are still answered to clients which only shut down
+        // their sending side, once the request was read
+        let client = request(
+            addr,
+            "GET /api/v1/health HTTP/1.1\r\nHost: logtopus\r\n\r\n",
+        );
+        thread::sleep(Duration::from_millis(100));
+        client.shutdown(Shutdown::Write).unwrap();
+        assert!(response(client).starts_with("HTTP/1.1 200 OK"));
     }
 }
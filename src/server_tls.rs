@@ -0,0 +1,303 @@
+use actix_net::service::{NewService, Service};
+use actix_web::server::IoStream;
+use futures::future::{ok, FutureResult};
+use futures::{Async, Future, Poll};
+use log::*;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, Ssl, SslAcceptor, SslContext, SslMethod,
+    SslStream, SslVerifyMode,
+};
+use openssl::x509::X509Name;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// TLS settings of the HTTP server.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTls {
+    /// PEM certificate chain, starting with the server certificate.
+    pub cert: PathBuf,
+    /// PEM private key of the server certificate.
+    pub key: PathBuf,
+    /// PEM bundle of the CAs issuing client certificates, clients have to
+    /// present a certificate if set.
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct ServerTlsError(String);
+
+impl Display for ServerTlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ServerTlsError {}
+
+impl ServerTls {
+    /// Reads the certificates and the key into a context for new connections.
+    pub fn context(&self) -> Result<SslContext, ServerTlsError> {
+        let context_error = |what: &PathBuf, e: openssl::error::ErrorStack| {
+            ServerTlsError(format!("{}: {}", what.display(), e))
+        };
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+            .map_err(|e| ServerTlsError(e.to_string()))?;
+        builder
+            .set_certificate_chain_file(&self.cert)
+            .map_err(|e| context_error(&self.cert, e))?;
+        builder
+            .set_private_key_file(&self.key, openssl::ssl::SslFiletype::PEM)
+            .map_err(|e| context_error(&self.key, e))?;
+        builder
+            .check_private_key()
+            .map_err(|_| ServerTlsError(String::from("key does not match the certificate")))?;
+        if let Some(client_ca) = &self.client_ca {
+            builder
+                .set_ca_file(client_ca)
+                .map_err(|e| context_error(client_ca, e))?;
+            builder.set_client_ca_list(
+                X509Name::load_client_ca_file(client_ca)
+                    .map_err(|e| context_error(client_ca, e))?,
+            );
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        Ok(builder.build().into_context())
+    }
+}
+
+/// Accepts TLS connections of the HTTP server. Every connection is set up
+/// from the current context, so a reloaded certificate applies to new
+/// connections while established ones keep theirs.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    context: Arc<RwLock<SslContext>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(tls: &ServerTls) -> Result<TlsAcceptor, ServerTlsError> {
+        Ok(TlsAcceptor {
+            context: Arc::new(RwLock::new(tls.context()?)),
+        })
+    }
+
+    /// Replaces the context for the connections accepted from now on.
+    pub fn set_context(&self, context: SslContext) {
+        *self.context.write().unwrap() = context;
+    }
+}
+
+impl NewService for TlsAcceptor {
+    type Request = TcpStream;
+    type Response = TlsStream;
+    type Error = ();
+    type InitError = ();
+    type Service = TlsAcceptor;
+    type Future = FutureResult<Self::Service, Self::InitError>;
+
+    fn new_service(&self) -> Self::Future {
+        ok(self.clone())
+    }
+}
+
+impl Service for TlsAcceptor {
+    type Request = TcpStream;
+    type Response = TlsStream;
+    type Error = ();
+    type Future = Handshake;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        Ok(Async::Ready(()))
+    }
+
+    fn call(&mut self, stream: TcpStream) -> Handshake {
+        match Ssl::new(&self.context.read().unwrap()) {
+            Ok(ssl) => Handshake::Start(ssl, stream),
+            Err(e) => {
+                error!("Failed to set up tls connection: {}", e);
+                Handshake::Failed
+            }
+        }
+    }
+}
+
+/// The server side of a TLS handshake on a non-blocking stream.
+pub enum Handshake {
+    Start(Ssl, TcpStream),
+    Mid(MidHandshakeSslStream<TcpStream>),
+    Failed,
+}
+
+impl Future for Handshake {
+    type Item = TlsStream;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<TlsStream, ()> {
+        let result = match std::mem::replace(self, Handshake::Failed) {
+            Handshake::Start(ssl, stream) => ssl.accept(stream),
+            Handshake::Mid(mid) => mid.handshake(),
+            Handshake::Failed => return Err(()),
+        };
+        match result {
+            Ok(stream) => Ok(Async::Ready(TlsStream(stream))),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                *self = Handshake::Mid(mid);
+                Ok(Async::NotReady)
+            }
+            Err(HandshakeError::Failure(mid)) => {
+                debug!("TLS handshake failed: {}", mid.error());
+                Err(())
+            }
+            Err(HandshakeError::SetupFailure(e)) => {
+                error!("Failed to set up tls connection: {}", e);
+                Err(())
+            }
+        }
+    }
+}
+
+/// An established TLS connection, reads and writes return `WouldBlock` like
+/// the underlying stream.
+pub struct TlsStream(SslStream<TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsyncRead for TlsStream {}
+
+impl AsyncWrite for TlsStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => (),
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => (),
+            Err(ref e) if e.code() == ErrorCode::WANT_READ || e.code() == ErrorCode::WANT_WRITE => {
+                return Ok(Async::NotReady)
+            }
+            Err(e) => return Err(e.into_io_error().unwrap_or_else(io::Error::other)),
+        }
+        AsyncWrite::shutdown(self.0.get_mut())
+    }
+}
+
+impl IoStream for TlsStream {
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self.0.get_ref(), how)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0.get_ref().peer_addr().ok()
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.0.get_mut().set_nodelay(nodelay)
+    }
+
+    fn set_linger(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.get_mut().set_linger(dur)
+    }
+
+    fn set_keepalive(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.get_mut().set_keepalive(dur)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server_tls::*;
+    use openssl::ssl::{SslConnector, SslFiletype};
+    use std::thread;
+
+    fn server_tls(client_ca: Option<&str>) -> ServerTls {
+        ServerTls {
+            cert: PathBuf::from("tests/tls/server.pem"),
+            key: PathBuf::from("tests/tls/server.key"),
+            client_ca: client_ca.map(PathBuf::from),
+        }
+    }
+
+    /// Accepts one connection with the acceptor and answers `hello`, returns
+    /// what the client received.
+    fn exchange(acceptor: &TlsAcceptor, client_cert: bool) -> Option<String> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_ca_file("tests/tls/ca.pem").unwrap();
+            if client_cert {
+                connector
+                    .set_certificate_file("tests/tls/client.pem", SslFiletype::PEM)
+                    .unwrap();
+                connector
+                    .set_private_key_file("tests/tls/client.key", SslFiletype::PEM)
+                    .unwrap();
+            }
+            let tcp = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut stream = connector.build().connect("localhost", tcp).ok()?;
+            let mut received = String::new();
+            stream.read_to_string(&mut received).ok()?;
+            Some(received)
+        });
+        let (tcp, _) = listener.accept().unwrap();
+        let tcp = TcpStream::from_std(tcp, &tokio::reactor::Handle::default()).unwrap();
+        let mut service = acceptor.clone();
+        actix::System::new("test")
+            .block_on(futures::future::lazy(move || {
+                service.call(tcp).and_then(|stream| {
+                    tokio::io::write_all(stream, b"hello")
+                        .and_then(|(stream, _)| tokio::io::shutdown(stream))
+                        .map(|_| ())
+                        .map_err(|_| ())
+                })
+            }))
+            .ok();
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn test_context() {
+        assert!(server_tls(None).context().is_ok());
+        assert!(server_tls(Some("tests/tls/ca.pem")).context().is_ok());
+        assert!(server_tls(Some("tests/tls/missing.pem")).context().is_err());
+        let mismatch = ServerTls {
+            key: PathBuf::from("tests/tls/client.key"),
+            ..server_tls(None)
+        };
+        assert!(mismatch.context().is_err());
+    }
+
+    #[test]
+    fn test_handshake_and_reload() {
+        let acceptor = TlsAcceptor::new(&server_tls(None)).unwrap();
+        assert_eq!(Some(String::from("hello")), exchange(&acceptor, false));
+
+        acceptor.set_context(server_tls(Some("tests/tls/ca.pem")).context().unwrap());
+        assert_eq!(None, exchange(&acceptor, false));
+        assert_eq!(Some(String::from("hello")), exchange(&acceptor, true));
+
+        // an invalid configuration yields no context to replace the current one
+        assert!(server_tls(Some("tests/tls/missing.pem")).context().is_err());
+        assert_eq!(Some(String::from("hello")), exchange(&acceptor, true));
+    }
+}
@@ -0,0 +1,1045 @@
+use crate::auth::{ApiToken, ApiUser, JwtAuthenticator, JwtSettings};
+use crate::compression::CompressionSettings;
+use crate::cors::CorsSettings;
+use crate::discovery::DnsRecord;
+use crate::encoding::Encoding;
+use crate::limits::{LimitSettings, RateLimit};
+use crate::log_file::LogFileSettings;
+use crate::rbac::RoleSettings;
+use crate::registration::RegistrationToken;
+use crate::secret::Secret;
+use crate::server_tls::ServerTls;
+use crate::slow_queries::SlowQuerySettings;
+use crate::tentacle::{TentacleAuth, TentacleInfo, DEFAULT_PORT, DEFAULT_PROTOCOL};
+use crate::tentacle_tls::TentacleTls;
+use crate::text_format::TextFormat;
+use crate::ui::UiSettings;
+use config::{Config, ConfigError, Value};
+use serde::de;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+const PROTOCOLS: [&str; 2] = ["http", "https"];
+
+/// A single invalid setting.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SettingsError {
+    pub path: String,
+    pub message: String,
+}
+
+impl SettingsError {
+    pub fn new<S: Into<String>>(path: &str, message: S) -> SettingsError {
+        SettingsError {
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+
+    fn from_config(path: &str, err: ConfigError) -> SettingsError {
+        match err {
+            ConfigError::Type {
+                unexpected,
+                expected,
+                key,
+                ..
+            } => {
+                let path = match key {
+                    Some(ref key) if key != path && !path.is_empty() => format!("{}.{}", path, key),
+                    Some(key) => key,
+                    None => path.to_string(),
+                };
+                SettingsError::new(
+                    &path,
+                    format!("invalid type: {}, expected {}", unexpected, expected),
+                )
+            }
+            ConfigError::NotFound(_) => SettingsError::new(path, "missing setting"),
+            ConfigError::Message(message) => SettingsError::new(path, message),
+            err => SettingsError::new(path, err.to_string()),
+        }
+    }
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// All invalid settings found in a configuration.
+#[derive(Debug)]
+pub struct SettingsErrors(pub Vec<SettingsError>);
+
+impl Display for SettingsErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for err in &self.0 {
+            writeln!(f, "  {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for SettingsErrors {}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct BindSettings {
+    pub ip: IpAddr,
+    pub port: u16,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct HttpSettings {
+    pub bind: BindSettings,
+    /// Serves HTTPS instead of HTTP if set.
+    pub tls: Option<ServerTls>,
+    pub cors: Option<CorsSettings>,
+}
+
+/// A tentacle as it is configured, see `TentacleInfo` for the resolved form.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct TentacleSettings {
+    pub host: String,
+    #[serde(default = "default_port", deserialize_with = "deserialize_port")]
+    pub port: u16,
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub auth: Option<TentacleAuth>,
+    pub tls: Option<TentacleTls>,
+    /// Encodings of the log lines the tentacle can send besides JSON.
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
+}
+
+/// The config crate truncates integers to the requested width, so ports are
+/// read as i64 and checked here.
+fn deserialize_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let port = i64::deserialize(deserializer)?;
+    check_port(port).map_err(de::Error::custom)
+}
+
+pub fn check_port(port: i64) -> Result<u16, String> {
+    if (1..=i64::from(u16::MAX)).contains(&port) {
+        Ok(port as u16)
+    } else {
+        Err(format!("port {} out of range 1-65535", port))
+    }
+}
+
+/// Like `deserialize_port`, rejects negative numbers of seconds.
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let secs = i64::deserialize(deserializer)?;
+    if secs < 0 {
+        Err(de::Error::custom(format!("negative duration {}", secs)))
+    } else {
+        Ok(secs as u64)
+    }
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT as u16
+}
+
+fn default_protocol() -> String {
+    String::from(DEFAULT_PROTOCOL)
+}
+
+impl TentacleSettings {
+    /// Validates a tentacle which is not part of the configuration, like one
+    /// added via the admin API.
+    pub fn check(&self, path: &str) -> Result<(), SettingsErrors> {
+        let mut errors = vec![];
+        self.validate(path, &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingsErrors(errors))
+        }
+    }
+
+    fn validate(&self, path: &str, errors: &mut Vec<SettingsError>) {
+        if self.host.trim().is_empty() {
+            errors.push(SettingsError::new(
+                &format!("{}.host", path),
+                "must not be empty",
+            ));
+        }
+        if !PROTOCOLS.contains(&self.protocol.as_str()) {
+            errors.push(SettingsError::new(
+                &format!("{}.protocol", path),
+                format!(
+                    "unsupported protocol {}, expected one of http, https",
+                    self.protocol
+                ),
+            ));
+        }
+        validate_transport(
+            path,
+            Some(&self.protocol),
+            self.auth.as_ref(),
+            self.tls.as_ref(),
+            errors,
+        );
+    }
+
+    pub fn to_info(&self) -> TentacleInfo {
+        TentacleInfo {
+            name: self.alias.clone().unwrap_or_else(|| self.host.clone()),
+            host: self.host.clone(),
+            port: i64::from(self.port),
+            protocol: self.protocol.clone(),
+            labels: self.labels.clone(),
+            auth: self.auth.clone(),
+            tls: self.tls.clone(),
+            encodings: self.encodings.clone(),
+        }
+    }
+}
+
+/// Validates the credentials and TLS settings sent to tentacles. Without a
+/// protocol, e.g. for registered tentacles, TLS settings only apply to https.
+fn validate_transport(
+    path: &str,
+    protocol: Option<&str>,
+    auth: Option<&TentacleAuth>,
+    tls: Option<&TentacleTls>,
+    errors: &mut Vec<SettingsError>,
+) {
+    let empty = match auth {
+        Some(TentacleAuth::Bearer { token }) => token.expose().is_empty(),
+        Some(TentacleAuth::Basic { username, .. }) => username.is_empty(),
+        None => false,
+    };
+    if empty {
+        errors.push(SettingsError::new(
+            &format!("{}.auth", path),
+            "credentials must not be empty",
+        ));
+    }
+    if let Some(tls) = tls {
+        let tls_path = format!("{}.tls", path);
+        if protocol.is_some_and(|protocol| protocol != "https") {
+            errors.push(SettingsError::new(&tls_path, "requires protocol https"));
+        } else if let Err(e) = tls.connector() {
+            errors.push(SettingsError::new(&tls_path, e.to_string()));
+        }
+    }
+}
+
+fn default_file_interval_secs() -> u64 {
+    10
+}
+
+fn default_dns_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DiscoverySettings {
+    File {
+        path: PathBuf,
+        #[serde(
+            default = "default_file_interval_secs",
+            deserialize_with = "deserialize_secs"
+        )]
+        interval_secs: u64,
+    },
+    Dns(Box<DnsSettings>),
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct DnsSettings {
+    pub name: String,
+    #[serde(default)]
+    pub record: DnsRecord,
+    #[serde(default = "default_port", deserialize_with = "deserialize_port")]
+    pub port: u16,
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+    pub nameserver: Option<SocketAddr>,
+    #[serde(
+        default = "default_dns_interval_secs",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub interval_secs: u64,
+    /// Credentials and TLS settings of all resolved tentacles.
+    pub auth: Option<TentacleAuth>,
+    pub tls: Option<TentacleTls>,
+}
+
+impl DiscoverySettings {
+    fn validate(&self, path: &str, errors: &mut Vec<SettingsError>) {
+        let interval_secs = match self {
+            DiscoverySettings::File { interval_secs, .. } => *interval_secs,
+            DiscoverySettings::Dns(dns) => {
+                if PROTOCOLS.contains(&dns.protocol.as_str()) {
+                    validate_transport(
+                        path,
+                        Some(&dns.protocol),
+                        dns.auth.as_ref(),
+                        dns.tls.as_ref(),
+                        errors,
+                    );
+                } else {
+                    errors.push(SettingsError::new(
+                        &format!("{}.protocol", path),
+                        format!(
+                            "unsupported protocol {}, expected one of http, https",
+                            dns.protocol
+                        ),
+                    ));
+                }
+                dns.interval_secs
+            }
+        };
+        if interval_secs == 0 {
+            errors.push(SettingsError::new(
+                &format!("{}.interval_secs", path),
+                "must be greater than 0",
+            ));
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct RegistrationSettings {
+    pub ttl_secs: u64,
+    pub state_file: Option<PathBuf>,
+    /// Registration is disabled without tokens.
+    pub tokens: Vec<RegistrationToken>,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct AdminSettings {
+    pub token: Option<Secret>,
+    pub overlay_file: Option<PathBuf>,
+}
+
+/// Authentication of API clients, enabled as soon as one method is configured.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct AuthSettings {
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+    #[serde(default)]
+    pub users: Vec<ApiUser>,
+    pub jwt: Option<JwtSettings>,
+    /// Without roles every authenticated client may read all sources.
+    #[serde(default)]
+    pub roles: Vec<RoleSettings>,
+    /// Serves all sources to anyone if no method is configured, which
+    /// otherwise is an invalid configuration.
+    #[serde(default)]
+    pub allow_anonymous: bool,
+}
+
+impl AuthSettings {
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty() || self.jwt.is_some()
+    }
+}
+
+/// The validated configuration of the server.
+#[derive(Clone, Serialize, PartialEq, Debug)]
+pub struct Settings {
+    pub http: HttpSettings,
+    pub tentacles: Vec<TentacleSettings>,
+    pub discovery: Vec<DiscoverySettings>,
+    pub registration: RegistrationSettings,
+    pub admin: AdminSettings,
+    pub auth: AuthSettings,
+    pub limits: LimitSettings,
+    pub audit: Option<LogFileSettings>,
+    pub slow_queries: Option<SlowQuerySettings>,
+    /// Named templates of the text output, see `TextFormat`.
+    pub text_formats: BTreeMap<String, String>,
+    pub compression: CompressionSettings,
+    pub ui: Option<UiSettings>,
+}
+
+fn get<T: DeserializeOwned>(
+    config: &Config,
+    key: &str,
+    errors: &mut Vec<SettingsError>,
+) -> Option<T> {
+    match config.get::<T>(key) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            errors.push(SettingsError::from_config(key, e));
+            None
+        }
+    }
+}
+
+fn get_optional<T: DeserializeOwned>(
+    config: &Config,
+    key: &str,
+    errors: &mut Vec<SettingsError>,
+) -> Option<T> {
+    match config.get::<T>(key) {
+        Ok(value) => Some(value),
+        Err(ConfigError::NotFound(_)) => None,
+        Err(e) => {
+            errors.push(SettingsError::from_config(key, e));
+            None
+        }
+    }
+}
+
+/// Deserializes each entry of a list on its own, so that all invalid entries are reported.
+fn get_list<T: DeserializeOwned>(
+    config: &Config,
+    key: &str,
+    errors: &mut Vec<SettingsError>,
+) -> Vec<(String, T)> {
+    let values: Vec<Value> = match config.get_array(key) {
+        Ok(values) => values,
+        Err(ConfigError::NotFound(_)) => vec![],
+        Err(e) => {
+            errors.push(SettingsError::from_config(key, e));
+            vec![]
+        }
+    };
+    values
+        .into_iter()
+        .enumerate()
+        .filter_map(|(idx, value)| {
+            let path = format!("{}[{}]", key, idx);
+            match value.try_into::<T>() {
+                Ok(entry) => Some((path, entry)),
+                Err(e) => {
+                    errors.push(SettingsError::from_config(&path, e));
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Deserializes and validates a single tentacle entry.
+pub fn parse_tentacle(value: Value, path: &str) -> Result<TentacleInfo, SettingsErrors> {
+    let tentacle: TentacleSettings = value
+        .try_into()
+        .map_err(|e| SettingsErrors(vec![SettingsError::from_config(path, e)]))?;
+    let mut errors = vec![];
+    tentacle.validate(path, &mut errors);
+    if errors.is_empty() {
+        Ok(tentacle.to_info())
+    } else {
+        Err(SettingsErrors(errors))
+    }
+}
+
+impl Settings {
+    /// Reads all settings from the layered configuration, collecting every
+    /// invalid setting instead of stopping at the first one.
+    pub fn from_config(config: &Config) -> Result<Settings, SettingsErrors> {
+        let mut errors = vec![];
+
+        let ip = get(config, "http.bind.ip", &mut errors);
+        let port = get::<i64>(config, "http.bind.port", &mut errors).and_then(|port| {
+            check_port(port)
+                .map_err(|e| errors.push(SettingsError::new("http.bind.port", e)))
+                .ok()
+        });
+        let tls: Option<ServerTls> = get_optional(config, "http.tls", &mut errors);
+        if let Some(Err(e)) = tls.as_ref().map(ServerTls::context) {
+            errors.push(SettingsError::new("http.tls", e.to_string()));
+        }
+        let cors: Option<CorsSettings> = get_optional(config, "http.cors", &mut errors);
+        for message in cors.iter().flat_map(CorsSettings::validate) {
+            errors.push(SettingsError::new("http.cors", message));
+        }
+
+        let tentacle_entries: Vec<(String, TentacleSettings)> =
+            get_list(config, "tentacles", &mut errors);
+        let mut names = HashSet::new();
+        for (path, tentacle) in &tentacle_entries {
+            tentacle.validate(path, &mut errors);
+            let name = tentacle.to_info().name;
+            if !names.insert(name.clone()) {
+                errors.push(SettingsError::new(
+                    path,
+                    format!("duplicate tentacle name {}", name),
+                ));
+            }
+        }
+
+        let discovery_entries: Vec<(String, DiscoverySettings)> =
+            get_list(config, "discovery", &mut errors);
+        for (path, discovery) in &discovery_entries {
+            discovery.validate(path, &mut errors);
+        }
+
+        let ttl_secs = get::<i64>(config, "registration.ttl_secs", &mut errors);
+        if ttl_secs.is_some_and(|ttl_secs| ttl_secs <= 0) {
+            errors.push(SettingsError::new(
+                "registration.ttl_secs",
+                "must be greater than 0",
+            ));
+        }
+        let state_file = get_optional(config, "registration.state_file", &mut errors);
+        let registration_tokens: Vec<(String, RegistrationToken)> =
+            get_list(config, "registration.tokens", &mut errors);
+        let mut registration_token_names = HashSet::new();
+        for (path, token) in &registration_tokens {
+            if token.name.is_empty() {
+                errors.push(SettingsError::new(
+                    &format!("{}.name", path),
+                    "must not be empty",
+                ));
+            }
+            if token.token.expose().is_empty() {
+                errors.push(SettingsError::new(
+                    &format!("{}.token", path),
+                    "must not be empty",
+                ));
+            }
+            validate_transport(
+                path,
+                None,
+                token.auth.as_ref(),
+                token.tls.as_ref(),
+                &mut errors,
+            );
+            if !registration_token_names.insert(token.name.clone()) {
+                errors.push(SettingsError::new(
+                    path,
+                    format!("duplicate token name {}", token.name),
+                ));
+            }
+        }
+
+        let token = get_optional(config, "admin.token", &mut errors);
+        let overlay_file = get_optional(config, "admin.overlay_file", &mut errors);
+
+        let role_entries: Vec<(String, RoleSettings)> = get_list(config, "auth.roles", &mut errors);
+        let mut role_names = HashSet::new();
+        for (path, role) in &role_entries {
+            if role.sources.is_empty() {
+                errors.push(SettingsError::new(
+                    &format!("{}.sources", path),
+                    "must not be empty",
+                ));
+            }
+            if !role_names.insert(role.name.clone()) {
+                errors.push(SettingsError::new(
+                    path,
+                    format!("duplicate role name {}", role.name),
+                ));
+            }
+        }
+        let check_roles = |path: &str, roles: &[String], errors: &mut Vec<SettingsError>| {
+            for role in roles.iter().filter(|role| !role_names.contains(*role)) {
+                errors.push(SettingsError::new(
+                    &format!("{}.roles", path),
+                    format!("unknown role {}", role),
+                ));
+            }
+        };
+
+        let token_entries: Vec<(String, ApiToken)> = get_list(config, "auth.tokens", &mut errors);
+        let mut token_names = HashSet::new();
+        for (path, token) in &token_entries {
+            check_roles(path, &token.roles, &mut errors);
+            if token.token.expose().is_empty() {
+                errors.push(SettingsError::new(
+                    &format!("{}.token", path),
+                    "must not be empty",
+                ));
+            }
+            if !token_names.insert(token.name.clone()) {
+                errors.push(SettingsError::new(
+                    path,
+                    format!("duplicate token name {}", token.name),
+                ));
+            }
+        }
+        let user_entries: Vec<(String, ApiUser)> = get_list(config, "auth.users", &mut errors);
+        let mut usernames = HashSet::new();
+        for (path, user) in &user_entries {
+            check_roles(path, &user.roles, &mut errors);
+            if user.password_hash.parse::<bcrypt::HashParts>().is_err() {
+                errors.push(SettingsError::new(
+                    &format!("{}.password_hash", path),
+                    "not a bcrypt hash",
+                ));
+            }
+            if !usernames.insert(user.username.clone()) {
+                errors.push(SettingsError::new(
+                    path,
+                    format!("duplicate username {}", user.username),
+                ));
+            }
+        }
+        let jwt: Option<JwtSettings> = get_optional(config, "auth.jwt", &mut errors);
+        if let Some(Err(e)) = jwt.as_ref().map(JwtAuthenticator::from_settings) {
+            errors.push(SettingsError::new("auth.jwt.jwks_file", e));
+        }
+        let allow_anonymous = get_optional(config, "auth.allow_anonymous", &mut errors);
+        if token_entries.is_empty()
+            && user_entries.is_empty()
+            && jwt.is_none()
+            && allow_anonymous != Some(true)
+        {
+            errors.push(SettingsError::new(
+                "auth",
+                "no authentication method configured, add tokens, users or jwt, \
+                 or set auth.allow_anonymous: true to serve all sources to anyone",
+            ));
+        }
+
+        let rate_entries: Vec<(String, RateLimit)> = get_list(config, "limits.rates", &mut errors);
+        for (path, rate) in &rate_entries {
+            for message in rate.validate() {
+                errors.push(SettingsError::new(path, message));
+            }
+        }
+        let mut get_max = |key: &str| {
+            let max = get_optional::<i64>(config, key, &mut errors);
+            if max.is_some_and(|max| max <= 0) {
+                errors.push(SettingsError::new(key, "must be greater than 0"));
+            }
+            max
+        };
+        let max_concurrent_queries = get_max("limits.max_concurrent_queries");
+        let max_concurrent_queries_per_client = get_max("limits.max_concurrent_queries_per_client");
+
+        let audit: Option<LogFileSettings> = get_optional(config, "audit", &mut errors);
+        for message in audit.iter().flat_map(LogFileSettings::validate) {
+            errors.push(SettingsError::new("audit", message));
+        }
+
+        let slow_queries: Option<SlowQuerySettings> =
+            get_optional(config, "slow_queries", &mut errors);
+        if let Some(slow_queries) = &slow_queries {
+            for message in slow_queries.validate() {
+                errors.push(SettingsError::new("slow_queries", message));
+            }
+            for message in slow_queries.log.validate() {
+                errors.push(SettingsError::new("slow_queries.log", message));
+            }
+        }
+
+        let text_formats: BTreeMap<String, String> =
+            get_optional(config, "text_formats", &mut errors).unwrap_or_default();
+        for (name, template) in &text_formats {
+            let path = format!("text_formats.{}", name);
+            if name.contains('{') {
+                errors.push(SettingsError::new(&path, "name must not contain {"));
+            }
+            if let Err(e) = TextFormat::parse(template) {
+                errors.push(SettingsError::new(&path, e));
+            }
+        }
+
+        let compression: CompressionSettings =
+            get_optional(config, "compression", &mut errors).unwrap_or_default();
+        for message in compression.validate() {
+            errors.push(SettingsError::new("compression", message));
+        }
+
+        let ui: Option<UiSettings> = get_optional(config, "ui", &mut errors);
+        if let Some(Err(e)) = ui.as_ref().map(UiSettings::validate) {
+            errors.push(SettingsError::new("ui.dir", e));
+        }
+
+        match (ip, port, ttl_secs) {
+            (Some(ip), Some(port), Some(ttl_secs)) if errors.is_empty() => Ok(Settings {
+                http: HttpSettings {
+                    bind: BindSettings { ip, port },
+                    tls,
+                    cors,
+                },
+                tentacles: tentacle_entries.into_iter().map(|(_, t)| t).collect(),
+                discovery: discovery_entries.into_iter().map(|(_, d)| d).collect(),
+                registration: RegistrationSettings {
+                    ttl_secs: ttl_secs as u64,
+                    state_file,
+                    tokens: registration_tokens.into_iter().map(|(_, t)| t).collect(),
+                },
+                admin: AdminSettings {
+                    token,
+                    overlay_file,
+                },
+                auth: AuthSettings {
+                    tokens: token_entries.into_iter().map(|(_, t)| t).collect(),
+                    users: user_entries.into_iter().map(|(_, u)| u).collect(),
+                    jwt,
+                    roles: role_entries.into_iter().map(|(_, r)| r).collect(),
+                    allow_anonymous: allow_anonymous.unwrap_or(false),
+                },
+                limits: LimitSettings {
+                    rates: rate_entries.into_iter().map(|(_, r)| r).collect(),
+                    max_concurrent_queries,
+                    max_concurrent_queries_per_client,
+                },
+                audit,
+                slow_queries,
+                text_formats,
+                compression,
+                ui,
+            }),
+            _ => Err(SettingsErrors(errors)),
+        }
+    }
+
+    pub fn tentacle_infos(&self) -> Vec<TentacleInfo> {
+        self.tentacles
+            .iter()
+            .map(TentacleSettings::to_info)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl Settings {
+    /// The embedded defaults with anonymous access.
+    pub fn anonymous_defaults() -> Settings {
+        let mut config = crate::cfg::read_config::<&str>(&[]).unwrap().config;
+        config.set("auth.allow_anonymous", true).unwrap();
+        Settings::from_config(&config).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::ContentCoding;
+    use crate::settings::*;
+
+    /// The defaults with anonymous access and the given settings.
+    fn config(yaml: &str) -> Config {
+        let mut config = Config::new();
+        config
+            .merge(config::File::from_str(
+                include_str!("default_config.yml"),
+                config::FileFormat::Yaml,
+            ))
+            .unwrap();
+        config
+            .merge(config::File::from_str(
+                "auth.allow_anonymous: true",
+                config::FileFormat::Yaml,
+            ))
+            .unwrap();
+        config
+            .merge(config::File::from_str(yaml, config::FileFormat::Yaml))
+            .unwrap();
+        config
+    }
+
+    #[test]
+    fn test_defaults() {
+        let settings = Settings::from_config(&config("")).unwrap();
+        assert_eq!(8081, settings.http.bind.port);
+        assert_eq!(
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            settings.http.bind.ip
+        );
+        assert!(settings.tentacles.is_empty());
+        assert!(settings.discovery.is_empty());
+        assert_eq!(30, settings.registration.ttl_secs);
+        assert!(settings.registration.tokens.is_empty());
+        assert_eq!(AdminSettings::default(), settings.admin);
+        assert!(!settings.auth.is_enabled());
+        assert!(settings.auth.allow_anonymous);
+        assert_eq!(None, settings.http.tls);
+        assert_eq!(None, settings.http.cors);
+        assert_eq!(None, settings.ui);
+        assert_eq!(LimitSettings::default(), settings.limits);
+        assert_eq!(None, settings.audit);
+        assert_eq!(None, settings.slow_queries);
+        assert!(settings.text_formats.is_empty());
+        assert_eq!(CompressionSettings::default(), settings.compression);
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let errors = Settings::from_config(&config(
+            "http.bind.port: high\nhttp.bind.ip: localhost\ntentacles:\n  - port: 1\n  - host: a\n    protocol: ftp\n  - host: b\n    port: 70000\ndiscovery:\n  - type: file\n    path: /tmp/t.yml\n    interval_secs: 0\n  - type: consul\n",
+        ))
+        .unwrap_err();
+        let paths: Vec<&str> = errors.0.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            vec![
+                "http.bind.ip",
+                "http.bind.port",
+                "tentacles[0]",
+                "tentacles[2]",
+                "tentacles[1].protocol",
+                "discovery[1]",
+                "discovery[0].interval_secs",
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn test_duplicate_tentacle_names() {
+        let errors = Settings::from_config(&config(
+            "tentacles:\n  - host: a\n  - host: b\n    alias: a\n",
+        ))
+        .unwrap_err();
+        assert_eq!(
+            vec![SettingsError::new(
+                "tentacles[1]",
+                "duplicate tentacle name a"
+            )],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn test_tentacle_auth_and_tls() {
+        let settings = Settings::from_config(&config(
+            "tentacles:\n  - host: a\n    protocol: https\n    auth:\n      type: basic\n      username: user\n      password: pass\n    tls:\n      ca_file: tests/tls/ca.pem\n",
+        ))
+        .unwrap();
+        let info = &settings.tentacle_infos()[0];
+        assert_eq!(
+            Some("Basic dXNlcjpwYXNz"),
+            info.auth.as_ref().map(|a| a.authorization()).as_deref()
+        );
+        let json = serde_json::to_string(&settings.tentacles).unwrap();
+        assert!(!json.contains("pass\""));
+
+        let errors = Settings::from_config(&config(
+            "tentacles:\n  - host: a\n    tls:\n      insecure: true\n  - host: b\n    protocol: https\n    tls:\n      ca_file: tests/tls/missing.pem\n  - host: c\n    auth:\n      type: digest\n",
+        ))
+        .unwrap_err();
+        let paths: Vec<&str> = errors.0.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            vec!["tentacles[2]", "tentacles[0].tls", "tentacles[1].tls"],
+            paths
+        );
+    }
+
+    #[test]
+    fn test_auth() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let settings = Settings::from_config(&config(&format!(
+            "auth:\n  tokens:\n    - name: grafana\n      token: t0ken\n  users:\n    - username: bob\n      password_hash: '{}'\n  jwt:\n    jwks_file: tests/jwt/jwks.json\n    audience: logtopus\n",
+            hash
+        )))
+        .unwrap();
+        assert!(settings.auth.is_enabled());
+        assert_eq!("sub", settings.auth.jwt.unwrap().username_claim);
+        let json = serde_json::to_string(&settings.auth.tokens).unwrap();
+        assert!(!json.contains("t0ken"));
+
+        let errors = Settings::from_config(&config(
+            "auth:\n  tokens:\n    - name: a\n      token: ''\n    - name: a\n      token: t\n    - token: t\n  users:\n    - username: bob\n      password_hash: secret\n  jwt:\n    jwks_file: tests/jwt/missing.json\n",
+        ))
+        .unwrap_err();
+        let paths: Vec<&str> = errors.0.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            vec![
+                "auth.tokens[2]",
+                "auth.tokens[0].token",
+                "auth.tokens[1]",
+                "auth.users[0].password_hash",
+                "auth.jwt.jwks_file",
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn test_registration_tokens() {
+        let settings = Settings::from_config(&config(
+            "registration:\n  tokens:\n    - name: app\n      token: t0ken\n      labels:\n        team: app\n",
+        ))
+        .unwrap();
+        let token = &settings.registration.tokens[0];
+        assert_eq!("app", token.name);
+        assert_eq!(Some(&"app".to_string()), token.labels.get("team"));
+        let json = serde_json::to_string(&settings.registration).unwrap();
+        assert!(!json.contains("t0ken"));
+
+        let errors = Settings::from_config(&config(
+            "registration:\n  tokens:\n    - name: ''\n      token: t\n    - name: a\n      token: ''\n    - name: a\n      token: t\n",
+        ))
+        .unwrap_err();
+        assert_eq!(
+            vec![
+                SettingsError::new("registration.tokens[0].name", "must not be empty"),
+                SettingsError::new("registration.tokens[1].token", "must not be empty"),
+                SettingsError::new("registration.tokens[2]", "duplicate token name a"),
+            ],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn test_transport_of_discovered_tentacles() {
+        let settings = Settings::from_config(&config(
+            "discovery:
+  - type: dns
+    name: tentacles.example.com
+    protocol: https
+    auth:
+      type: bearer
+      token: t
+    tls:
+      insecure: true
+registration:
+  tokens:
+    - name: app
+      token: t
+      auth:
+        type: basic
+        username: logtopus
+        password: p
+",
+        ))
+        .unwrap();
+        match &settings.discovery[0] {
+            DiscoverySettings::Dns(dns) => {
+                assert!(dns.auth.is_some());
+                assert!(dns.tls.as_ref().is_some_and(|tls| tls.insecure));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(settings.registration.tokens[0].auth.is_some());
+
+        let errors = Settings::from_config(&config(
+            "discovery:
+  - type: dns
+    name: tentacles.example.com
+    tls:
+      insecure: true
+registration:
+  tokens:
+    - name: app
+      token: t
+      auth:
+        type: bearer
+        token: ''
+",
+        ))
+        .unwrap_err();
+        let paths: Vec<&str> = errors.0.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            vec!["discovery[0].tls", "registration.tokens[0].auth"],
+            paths
+        );
+    }
+
+    #[test]
+    fn test_auth_required() {
+        let errors = Settings::from_config(&config("auth.allow_anonymous: false\n")).unwrap_err();
+        let paths: Vec<&str> = errors.0.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(vec!["auth"], paths);
+
+        let settings = Settings::from_config(&config(
+            "auth:\n  allow_anonymous: false\n  tokens:\n    - name: grafana\n      token: t\n",
+        ))
+        .unwrap();
+        assert!(!settings.auth.allow_anonymous);
+    }
+
+    #[test]
+    fn test_roles() {
+        let settings = Settings::from_config(&config(
+            "auth:\n  roles:\n    - name: app\n      sources: [app-*]\n      tentacles:\n        - team: app\n    - name: ops\n      sources: ['*', '!system-auth']\n  tokens:\n    - name: grafana\n      token: t\n      roles: [app, ops]\n",
+        ))
+        .unwrap();
+        assert_eq!(2, settings.auth.roles.len());
+        assert_eq!(vec!["app", "ops"], settings.auth.tokens[0].roles);
+
+        let errors = Settings::from_config(&config(
+            "auth:\n  roles:\n    - name: app\n      sources: []\n    - name: app\n      sources: [a]\n  tokens:\n    - name: grafana\n      token: t\n      roles: [app, admin]\n",
+        ))
+        .unwrap_err();
+        assert_eq!(
+            vec![
+                SettingsError::new("auth.roles[0].sources", "must not be empty"),
+                SettingsError::new("auth.roles[1]", "duplicate role name app"),
+                SettingsError::new("auth.tokens[0].roles", "unknown role admin"),
+            ],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let settings = Settings::from_config(&config(
+            "limits:\n  rates:\n    - route: /api/v1/sources/*\n      requests_per_minute: 60\n      burst: 10\n  max_concurrent_queries: 100\n  max_concurrent_queries_per_client: 4\n",
+        ))
+        .unwrap();
+        assert_eq!(Some(10), settings.limits.rates[0].burst);
+        assert_eq!(Some(4), settings.limits.max_concurrent_queries_per_client);
+
+        let errors = Settings::from_config(&config(
+            "limits:\n  rates:\n    - route: /api/*\n    - route: /api/*\n      requests_per_minute: -1\n  max_concurrent_queries: 0\n",
+        ))
+        .unwrap_err();
+        assert_eq!(
+            vec![
+                SettingsError::new("limits.rates[0]", "missing field `requests_per_minute`"),
+                SettingsError::new(
+                    "limits.rates[1]",
+                    "requests_per_minute must be greater than 0"
+                ),
+                SettingsError::new("limits.max_concurrent_queries", "must be greater than 0"),
+            ],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn test_text_formats() {
+        let settings =
+            Settings::from_config(&config("text_formats:\n  short: '{level} {message}'\n"))
+                .unwrap();
+        assert_eq!("{level} {message}", settings.text_formats["short"]);
+
+        let errors = Settings::from_config(&config(
+            "text_formats:\n  short: '{lvl} {message}'\n  '{iso}': '{ts} {message}'\n",
+        ))
+        .unwrap_err();
+        assert_eq!(
+            vec![
+                SettingsError::new("text_formats.short", "unknown placeholder {lvl}"),
+                SettingsError::new("text_formats.{iso}", "name must not contain {"),
+            ],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn test_compression() {
+        let settings = Settings::from_config(&config(
+            "compression:\n  algorithms: [gzip]\n  gzip_level: 9\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            CompressionSettings {
+                algorithms: vec![ContentCoding::Gzip],
+                gzip_level: 9,
+                ..CompressionSettings::default()
+            },
+            settings.compression
+        );
+
+        let errors =
+            Settings::from_config(&config("compression:\n  zstd_level: 30\n")).unwrap_err();
+        assert_eq!(
+            vec![SettingsError::new(
+                "compression",
+                "zstd_level must be between 1 and 22"
+            )],
+            errors.0
+        );
+    }
+}
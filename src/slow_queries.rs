@@ -0,0 +1,94 @@
+use crate::log_file::LogFileSettings;
+use crate::queries::QueryFilters;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Queries over a threshold are written to the slow query log when they end.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SlowQuerySettings {
+    /// Queries running at least this long are slow.
+    pub threshold_ms: Option<u64>,
+    /// Queries returning more lines are slow.
+    pub max_lines: Option<u64>,
+    pub log: LogFileSettings,
+}
+
+impl SlowQuerySettings {
+    pub fn validate(&self) -> Vec<String> {
+        if self.threshold_ms.is_none() && self.max_lines.is_none() {
+            vec![String::from("requires threshold_ms or max_lines")]
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn is_slow(&self, duration_ms: u64, lines: u64) -> bool {
+        self.threshold_ms.is_some_and(|t| duration_ms >= t)
+            || self.max_lines.is_some_and(|max| lines > max)
+    }
+}
+
+/// How a tentacle took part in a query, times are relative to its start.
+#[derive(Clone, Serialize, PartialEq, Debug, Default)]
+pub struct TentacleTiming {
+    pub lines: u64,
+    pub first_line_ms: Option<u64>,
+    /// When the tentacle stream ended, none if it was still open.
+    pub ended_ms: Option<u64>,
+    pub failed: bool,
+}
+
+/// One line of the slow query log.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct SlowQueryEntry {
+    pub id: u64,
+    pub principal: Option<String>,
+    pub source: String,
+    pub filters: QueryFilters,
+    pub started: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub lines: u64,
+    pub killed: bool,
+    pub tentacles: BTreeMap<String, TentacleTiming>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::slow_queries::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_is_slow() {
+        let settings = SlowQuerySettings {
+            threshold_ms: Some(1000),
+            max_lines: None,
+            log: LogFileSettings {
+                file: PathBuf::from("/var/log/logtopus/slow.log"),
+                max_size_bytes: 1024,
+                max_files: 1,
+            },
+        };
+        assert!(settings.validate().is_empty());
+        assert!(settings.is_slow(1000, 0));
+        assert!(!settings.is_slow(999, 1_000_000));
+
+        let max_lines = SlowQuerySettings {
+            threshold_ms: None,
+            max_lines: Some(10),
+            ..settings.clone()
+        };
+        assert!(max_lines.is_slow(0, 11));
+        assert!(!max_lines.is_slow(60_000, 10));
+
+        let neither = SlowQuerySettings {
+            max_lines: None,
+            ..max_lines
+        };
+        assert_eq!(
+            vec!["requires threshold_ms or max_lines"],
+            neither.validate()
+        );
+    }
+}
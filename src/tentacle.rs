@@ -1,35 +1,82 @@
+use crate::encoding::{Encoding, FrameDecoder};
 use crate::log_merge::{LogMerge, LogStream, LogStreamError};
-use actix_web::{client, HttpMessage};
-use config::{Config, Value};
-use futures::{Future, Stream};
+use crate::secret::Secret;
+use crate::tentacle_set::TentacleSet;
+use crate::tentacle_tls::{TentacleTls, TlsConnectors};
+use actix_web::{client, http::header, HttpMessage};
+use futures::{stream, Future, Stream};
+use log::*;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::collections::BTreeMap;
 use urlparse::quote;
 
-const DEFAULT_PORT: i64 = 8080;
-const DEFAULT_PROTOCOL: &'static str = "http";
+pub const DEFAULT_PORT: i64 = 8080;
+pub const DEFAULT_PROTOCOL: &str = "http";
 
 #[derive(Debug)]
 pub enum TentacleClientError {
     ClientError,
 }
 
-#[derive(Debug)]
-pub enum TentacleConfigError {
-    NoTableError,
-    NoHostSpecified,
-    IllegalHostError,
-    IllegalPortError,
-    IllegalProtocolError,
-    IllegalAliasError,
+/// Credentials sent to a tentacle, e.g. for an authenticating proxy in front of it.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum TentacleAuth {
+    Bearer { token: Secret },
+    Basic { username: String, password: Secret },
 }
 
-#[derive(Clone, PartialEq, Debug)]
+impl TentacleAuth {
+    /// Value of the `Authorization` header.
+    pub fn authorization(&self) -> String {
+        match self {
+            TentacleAuth::Bearer { token } => format!("Bearer {}", token.expose()),
+            TentacleAuth::Basic { username, password } => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password.expose()).as_bytes())
+            ),
+        }
+    }
+
+    /// Resolves the secrets of credentials given at runtime, which have to be
+    /// references to keep them out of stored files.
+    pub fn resolve(&self) -> Result<TentacleAuth, String> {
+        Ok(match self {
+            TentacleAuth::Bearer { token } => TentacleAuth::Bearer {
+                token: Secret::from_reference(token.expose())?,
+            },
+            TentacleAuth::Basic { username, password } => TentacleAuth::Basic {
+                username: username.clone(),
+                password: Secret::from_reference(password.expose())?,
+            },
+        })
+    }
+
+    /// Whether the secret was stored without a reference and has to be taken
+    /// from the configuration again.
+    pub fn is_redacted(&self) -> bool {
+        match self {
+            TentacleAuth::Bearer { token } => token.is_redacted(),
+            TentacleAuth::Basic { password, .. } => password.is_redacted(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct TentacleInfo {
     pub name: String,
     pub host: String,
     pub port: i64,
     pub protocol: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<TentacleAuth>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TentacleTls>,
+    /// Encodings the tentacle supports besides JSON, the cheapest is requested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub encodings: Vec<Encoding>,
 }
 
 impl TentacleInfo {
@@ -55,64 +102,18 @@ pub struct LogLine {
 }
 
 pub struct TentacleClient {
-    tentacles: Vec<TentacleInfo>,
+    tentacles: TentacleSet,
+    connectors: TlsConnectors,
 }
 
 impl TentacleClient {
-    pub fn parse_tentacle(v: Value) -> Result<TentacleInfo, TentacleConfigError> {
-        match v.into_table() {
-            Ok(table) => {
-                let host = table
-                    .get("host")
-                    .cloned()
-                    .ok_or(TentacleConfigError::NoHostSpecified)?
-                    .into_str()
-                    .map_err(|_| TentacleConfigError::IllegalHostError)?;
-                let port = table
-                    .get("port")
-                    .map(|v| {
-                        v.clone()
-                            .into_int()
-                            .map_err(|_| TentacleConfigError::IllegalPortError)
-                    })
-                    .unwrap_or(Ok(DEFAULT_PORT))?;
-                let protocol = table
-                    .get("protocol")
-                    .map(|v| {
-                        v.clone()
-                            .into_str()
-                            .map_err(|_| TentacleConfigError::IllegalProtocolError)
-                    })
-                    .unwrap_or(Ok(String::from(DEFAULT_PROTOCOL)))?;
-                let name = table
-                    .get("alias")
-                    .map(|v| {
-                        v.clone()
-                            .into_str()
-                            .map_err(|_| TentacleConfigError::IllegalAliasError)
-                    })
-                    .unwrap_or(Ok(host.clone()))?;
-                Ok(TentacleInfo {
-                    name,
-                    host,
-                    port,
-                    protocol,
-                })
-            }
-            Err(_e) => Err(TentacleConfigError::NoTableError),
+    pub fn new(tentacles: TentacleSet) -> TentacleClient {
+        TentacleClient {
+            tentacles,
+            connectors: TlsConnectors::default(),
         }
     }
 
-    pub fn from_settings(settings: Arc<Config>) -> Result<TentacleClient, TentacleConfigError> {
-        let tentacles: Result<Vec<TentacleInfo>, TentacleConfigError> = settings
-            .get_array("tentacles")
-            .unwrap() // we unwrap here as there is always an empty array defined in default config
-            .into_iter()
-            .map(|v| TentacleClient::parse_tentacle(v))
-            .collect();
-        tentacles.map(|infos| TentacleClient { tentacles: infos })
-    }
-
     fn query_tentacle(
         &self,
         tentacle: TentacleInfo,
@@ -131,50 +132,319 @@ impl TentacleClient {
             id_encoded,
             filter
         );
-        let req = client::get(url)
-            .header("User-Agent", "logtopus")
-            .header("Accept", "application/json")
-            .finish()
-            .unwrap()
-            .send()
-            .map_err(|_| TentacleClientError::ClientError);
-        let bytes = req
-            .map(|response| {
-                response
+        let mut builder = client::get(url);
+        builder.header("User-Agent", "logtopus").header(
+            "Accept",
+            Encoding::preferred(&tentacle.encodings).content_type(),
+        );
+        if let Some(auth) = &tentacle.auth {
+            builder.header(header::AUTHORIZATION, auth.authorization());
+        }
+        if let Some(tls) = &tentacle.tls {
+            match self.connectors.get(tls) {
+                Ok(connector) => {
+                    builder.with_connector(connector);
+                }
+                Err(e) => {
+                    error!("Tentacle {}: {}", tentacle.name, e);
+                    return Box::new(stream::once(Err(LogStreamError::DefaultError(
+                        tentacle.name,
+                    ))));
+                }
+            }
+        }
+        let tn0 = tentacle.name.clone();
+        let req = builder.finish().unwrap().send().map_err(move |e| {
+            error!("Request to tentacle {} failed: {}", tn0, e);
+            TentacleClientError::ClientError
+        });
+        let tn0 = tentacle.name.clone();
+        let tn1 = tentacle.name.clone();
+        let chunks = req
+            .and_then(move |response| {
+                if !response.status().is_success() {
+                    error!("Tentacle {} responded with {}", tn0, response.status());
+                    return Err(TentacleClientError::ClientError);
+                }
+                let encoding = response
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|content_type| content_type.to_str().ok())
+                    .map_or(Encoding::Json, Encoding::from_content_type);
+                let mut decoder = FrameDecoder::new(encoding);
+                Ok(response
                     .payload()
                     .map_err(|_| TentacleClientError::ClientError)
+                    .map(Some)
+                    // the end of the payload completes a last line without line break
+                    .chain(stream::once(Ok(None)))
+                    .and_then(move |chunk| {
+                        let lines = match chunk {
+                            Some(chunk) => decoder.push::<TentacleLogLine>(&chunk),
+                            None => decoder.finish(),
+                        };
+                        lines.map_err(|e| {
+                            error!(
+                                "Invalid {} from tentacle {}: {}",
+                                encoding.content_type(),
+                                tn1,
+                                e
+                            );
+                            TentacleClientError::ClientError
+                        })
+                    }))
             })
             .flatten_stream();
         let tn1 = tentacle.name.clone();
         let tn2 = tentacle.name.clone();
-        let lines = bytes
-            .map(move |b| {
-                let line = String::from_utf8(b.to_vec()).unwrap();
-                let log_line: TentacleLogLine = serde_json::from_str(&line).unwrap();
-                LogLine {
-                    timestamp: log_line.timestamp,
-                    message: log_line.message,
-                    loglevel: log_line.loglevel,
-                    id: id.clone(),
-                    source: tn1.clone(),
-                }
+        let lines = chunks
+            .map(stream::iter_ok::<_, TentacleClientError>)
+            .flatten()
+            .map(move |log_line: TentacleLogLine| LogLine {
+                timestamp: log_line.timestamp,
+                message: log_line.message,
+                loglevel: log_line.loglevel,
+                id: id.clone(),
+                source: tn1.clone(),
             })
             .map_err(move |_| LogStreamError::DefaultError(tn2.clone()));
         Box::new(lines)
     }
 
-    pub fn stream_logs(
+    /// Queries the source on all tentacles accepted by the filter, returns
+    /// the stream of each tentacle with its name.
+    pub fn query_tentacles(
         &self,
         id: String,
         from_ms: u64,
         loglevels: &Option<String>,
-    ) -> Box<dyn Stream<Item = LogLine, Error = TentacleClientError>> {
-        let streams: Vec<LogStream> = self
-            .tentacles
-            .clone()
+        tentacle_filter: &dyn Fn(&TentacleInfo) -> bool,
+    ) -> Vec<(String, LogStream)> {
+        self.tentacles
+            .snapshot()
             .into_iter()
-            .map(|t| self.query_tentacle(t.clone(), id.clone(), from_ms, loglevels))
-            .collect();
+            .filter(|t| tentacle_filter(t))
+            .map(|t| {
+                (
+                    t.name.clone(),
+                    self.query_tentacle(t.clone(), id.clone(), from_ms, loglevels),
+                )
+            })
+            .collect()
+    }
+
+    /// The names of all tentacles, including the drained ones.
+    pub fn tentacle_names(&self) -> Vec<String> {
+        self.tentacles
+            .entries()
+            .into_iter()
+            .map(|e| e.info.name)
+            .collect()
+    }
+
+    /// Merges the streams of the tentacles ordered by timestamp.
+    pub fn merge(
+        streams: Vec<LogStream>,
+    ) -> Box<dyn Stream<Item = LogLine, Error = TentacleClientError>> {
         Box::new(LogMerge::new(streams).map_err(|_| TentacleClientError::ClientError))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::tentacle::*;
+    use crate::testing;
+    use crate::testing::tentacle_line;
+    use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    const FAILED: &str = "A tentacle failed while retrieving the log.";
+
+    /// Serves a single request over TLS, returns the request head if the
+    /// handshake succeeded.
+    fn tls_stand_in(require_client_cert: bool) -> (u16, thread::JoinHandle<Option<String>>) {
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor
+            .set_private_key_file("tests/tls/server.key", SslFiletype::PEM)
+            .unwrap();
+        acceptor
+            .set_certificate_chain_file("tests/tls/server.pem")
+            .unwrap();
+        if require_client_cert {
+            acceptor.set_ca_file("tests/tls/ca.pem").unwrap();
+            acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(stream).ok()?;
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let len = stream.read(&mut buf).ok()?;
+                if len == 0 {
+                    return None;
+                }
+                request.extend_from_slice(&buf[..len]);
+            }
+            let body = r#"{"timestamp":1,"message":"hello","loglevel":"INFO"}"#;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .ok()?;
+            Some(String::from_utf8_lossy(&request).to_lowercase())
+        });
+        (port, server)
+    }
+
+    /// Serves a single plain request with the body, returns the request head.
+    fn plain_stand_in(
+        content_type: &'static str,
+        body: Vec<u8>,
+    ) -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+            String::from_utf8_lossy(&request).to_lowercase()
+        });
+        (port, server)
+    }
+
+    fn tentacle(port: u16, auth: Option<TentacleAuth>, tls: Option<TentacleTls>) -> TentacleInfo {
+        TentacleInfo {
+            protocol: String::from("https"),
+            auth,
+            tls,
+            ..testing::tentacle("tentacle_1", i64::from(port))
+        }
+    }
+
+    fn query(tentacle: TentacleInfo) -> Vec<String> {
+        let mut sys = actix::System::new("test");
+        let lines = sys
+            .block_on(futures::future::lazy(move || {
+                let streams = TentacleClient::new(TentacleSet::new(vec![tentacle]))
+                    .query_tentacles(String::from("app"), 0, &None, &|_| true);
+                assert_eq!(
+                    vec!["tentacle_1"],
+                    streams.iter().map(|(name, _)| name).collect::<Vec<_>>()
+                );
+                TentacleClient::merge(streams.into_iter().map(|(_, stream)| stream).collect())
+                    .collect()
+            }))
+            .unwrap();
+        lines.into_iter().map(|line| line.message).collect()
+    }
+
+    fn ca_only() -> TentacleTls {
+        TentacleTls {
+            ca_file: Some(PathBuf::from("tests/tls/ca.pem")),
+            ..TentacleTls::default()
+        }
+    }
+
+    #[test]
+    fn test_authorization() {
+        let basic = TentacleAuth::Basic {
+            username: String::from("user"),
+            password: serde_json::from_str(r#""pass""#).unwrap(),
+        };
+        assert_eq!("Basic dXNlcjpwYXNz", basic.authorization());
+    }
+
+    #[test]
+    fn test_ca_file_and_bearer_token() {
+        let (port, server) = tls_stand_in(false);
+        let auth = TentacleAuth::Bearer {
+            token: serde_json::from_str(r#""t0ken""#).unwrap(),
+        };
+        assert_eq!(
+            vec!["hello"],
+            query(tentacle(port, Some(auth), Some(ca_only())))
+        );
+        let request = server.join().unwrap().unwrap();
+        assert!(request.contains("authorization: bearer t0ken"));
+    }
+
+    #[test]
+    fn test_untrusted_certificate() {
+        let (port, server) = tls_stand_in(false);
+        assert_eq!(vec![FAILED], query(tentacle(port, None, None)));
+        assert_eq!(None, server.join().unwrap());
+
+        let (port, server) = tls_stand_in(false);
+        let insecure = TentacleTls {
+            insecure: true,
+            ..TentacleTls::default()
+        };
+        assert_eq!(vec!["hello"], query(tentacle(port, None, Some(insecure))));
+        assert!(server.join().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        let (port, server) = tls_stand_in(true);
+        assert_eq!(vec![FAILED], query(tentacle(port, None, Some(ca_only()))));
+        assert_eq!(None, server.join().unwrap());
+
+        let (port, server) = tls_stand_in(true);
+        let mutual = TentacleTls {
+            cert_file: Some(PathBuf::from("tests/tls/client.pem")),
+            key_file: Some(PathBuf::from("tests/tls/client.key")),
+            ..ca_only()
+        };
+        assert_eq!(vec!["hello"], query(tentacle(port, None, Some(mutual))));
+        assert!(server.join().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_binary_encodings() {
+        for encoding in &[Encoding::Msgpack, Encoding::Cbor] {
+            let mut body = encoding.encode(&tentacle_line(1, "hello", None));
+            body.extend(encoding.encode(&tentacle_line(1, "world", None)));
+            let (port, server) = plain_stand_in(encoding.content_type(), body);
+            let tentacle = TentacleInfo {
+                protocol: String::from("http"),
+                encodings: vec![*encoding],
+                ..tentacle(port, None, None)
+            };
+            assert_eq!(vec!["hello", "world"], query(tentacle));
+            let request = server.join().unwrap();
+            assert!(request.contains(&format!("accept: {}", encoding.content_type())));
+        }
+
+        // a tentacle answering JSON although it named another encoding
+        let body = br#"{"timestamp":1,"message":"hello","loglevel":"INFO"}"#.to_vec();
+        let (port, server) = plain_stand_in("application/json", body);
+        let tentacle = TentacleInfo {
+            protocol: String::from("http"),
+            encodings: vec![Encoding::Cbor],
+            ..tentacle(port, None, None)
+        };
+        assert_eq!(vec!["hello"], query(tentacle));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use crate::tentacle::TentacleInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// Origin of the tentacles added by an operator at runtime.
+pub const OVERLAY_ORIGIN: &str = "overlay";
+/// Origin of the statically configured tentacles.
+pub const CONFIG_ORIGIN: &str = "config";
+
+/// The set of tentacles a query is sent to.
+///
+/// It consists of the statically configured tentacles plus the tentacles
+/// reported by the discovery providers, each provider owning its own group.
+/// On top of that, the overlay holds the changes made by operators at runtime.
+/// The set is shared between all workers, queries take a snapshot when they
+/// start so that updates only affect new queries.
+#[derive(Clone)]
+pub struct TentacleSet {
+    inner: Arc<RwLock<TentacleSetState>>,
+}
+
+struct TentacleSetState {
+    base: Vec<TentacleInfo>,
+    discovered: BTreeMap<String, Vec<TentacleInfo>>,
+    overlay: Overlay,
+}
+
+/// Runtime changes to the tentacle set. Added tentacles replace tentacles of
+/// the same name, removed tentacles are hidden and drained tentacles are kept
+/// out of new queries.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Overlay {
+    #[serde(default)]
+    pub added: Vec<TentacleInfo>,
+    #[serde(default)]
+    pub removed: BTreeSet<String>,
+    #[serde(default)]
+    pub drained: BTreeSet<String>,
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct TentacleEntry {
+    #[serde(flatten)]
+    pub info: TentacleInfo,
+    pub origin: String,
+    pub drained: bool,
+}
+
+impl TentacleSetState {
+    /// All visible tentacles with their origin, overlay first. Tentacles with a
+    /// name already taken by an earlier entry are skipped.
+    fn entries(&self) -> Vec<TentacleEntry> {
+        let mut names = HashSet::new();
+        let overlay = self.overlay.added.iter().map(|t| (OVERLAY_ORIGIN, t));
+        let base = self.base.iter().map(|t| (CONFIG_ORIGIN, t));
+        let discovered = self
+            .discovered
+            .iter()
+            .flat_map(|(origin, ts)| ts.iter().map(move |t| (origin.as_str(), t)));
+        overlay
+            .chain(base)
+            .chain(discovered)
+            .filter(|(_, t)| names.insert(t.name.clone()))
+            .filter(|(_, t)| !self.overlay.removed.contains(&t.name))
+            .map(|(origin, t)| TentacleEntry {
+                info: t.clone(),
+                origin: origin.to_string(),
+                drained: self.overlay.drained.contains(&t.name),
+            })
+            .collect()
+    }
+}
+
+impl TentacleSet {
+    pub fn new(base: Vec<TentacleInfo>) -> TentacleSet {
+        TentacleSet {
+            inner: Arc::new(RwLock::new(TentacleSetState {
+                base,
+                discovered: BTreeMap::new(),
+                overlay: Overlay::default(),
+            })),
+        }
+    }
+
+    /// Replaces the statically configured tentacles.
+    pub fn set_base(&self, base: Vec<TentacleInfo>) {
+        self.inner.write().unwrap().base = base;
+    }
+
+    /// Replaces the group of tentacles reported by the given provider.
+    pub fn update(&self, provider: &str, tentacles: Vec<TentacleInfo>) {
+        let mut state = self.inner.write().unwrap();
+        state.discovered.insert(provider.to_string(), tentacles);
+    }
+
+    /// Drops the group of tentacles reported by the given provider.
+    pub fn remove_group(&self, provider: &str) {
+        self.inner.write().unwrap().discovered.remove(provider);
+    }
+
+    /// Returns the tentacles new queries are sent to, drained tentacles excluded.
+    pub fn snapshot(&self) -> Vec<TentacleInfo> {
+        self.entries()
+            .into_iter()
+            .filter(|e| !e.drained)
+            .map(|e| e.info)
+            .collect()
+    }
+
+    /// Returns all tentacles including the drained ones.
+    pub fn entries(&self) -> Vec<TentacleEntry> {
+        self.inner.read().unwrap().entries()
+    }
+
+    pub fn overlay(&self) -> Overlay {
+        self.inner.read().unwrap().overlay.clone()
+    }
+
+    pub fn set_overlay(&self, overlay: Overlay) {
+        self.inner.write().unwrap().overlay = overlay;
+    }
+
+    /// Adds a tentacle to the overlay, replacing any tentacle of the same name.
+    pub fn put(&self, info: TentacleInfo) {
+        let mut state = self.inner.write().unwrap();
+        state.overlay.removed.remove(&info.name);
+        state.overlay.added.retain(|t| t.name != info.name);
+        state.overlay.added.push(info);
+    }
+
+    /// Removes a tentacle, returns false if there is no such tentacle.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut state = self.inner.write().unwrap();
+        if !state.entries().iter().any(|e| e.info.name == name) {
+            return false;
+        }
+        state.overlay.added.retain(|t| t.name != name);
+        state.overlay.drained.remove(name);
+        if state.entries().iter().any(|e| e.info.name == name) {
+            // the tentacle is also configured or discovered, hide it
+            state.overlay.removed.insert(name.to_string());
+        }
+        true
+    }
+
+    /// Drains or undrains a tentacle, returns false if there is no such tentacle.
+    pub fn drain(&self, name: &str, drained: bool) -> bool {
+        let mut state = self.inner.write().unwrap();
+        if !state.entries().iter().any(|e| e.info.name == name) {
+            return false;
+        }
+        if drained {
+            state.overlay.drained.insert(name.to_string());
+        } else {
+            state.overlay.drained.remove(name);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tentacle_set::TentacleSet;
+    use crate::testing::tentacle;
+
+    #[test]
+    fn test_snapshot_keeps_base() {
+        let set = TentacleSet::new(vec![tentacle("t1", 18080)]);
+        set.update("file", vec![tentacle("t2", 18081)]);
+        assert_eq!(
+            vec![tentacle("t1", 18080), tentacle("t2", 18081)],
+            set.snapshot()
+        );
+
+        set.update("file", vec![]);
+        assert_eq!(vec![tentacle("t1", 18080)], set.snapshot());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let set = TentacleSet::new(vec![tentacle("t1", 18080), tentacle("t2", 18081)]);
+        set.put(tentacle("t1", 18090));
+        set.put(tentacle("t3", 18082));
+        assert_eq!(
+            vec![
+                tentacle("t1", 18090),
+                tentacle("t3", 18082),
+                tentacle("t2", 18081)
+            ],
+            set.snapshot()
+        );
+
+        // removing the overlay entry hides the configured one as well
+        assert!(set.remove("t1"));
+        assert!(!set.remove("t1"));
+        assert!(set.drain("t2", true));
+        assert_eq!(vec![tentacle("t3", 18082)], set.snapshot());
+        assert_eq!(2, set.entries().len());
+
+        assert!(set.drain("t2", false));
+        set.put(tentacle("t1", 18080));
+        assert_eq!(3, set.snapshot().len());
+        assert!(!set.drain("t4", true));
+    }
+
+    #[test]
+    fn test_snapshot_skips_duplicate_names() {
+        let set = TentacleSet::new(vec![tentacle("t1", 18080)]);
+        set.update("dns", vec![tentacle("t1", 18081), tentacle("t3", 18082)]);
+        assert_eq!(
+            vec![tentacle("t1", 18080), tentacle("t3", 18082)],
+            set.snapshot()
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use actix::{Actor, Addr};
+use actix_web::client::ClientConnector;
+use native_tls::{Certificate, Identity, TlsConnector};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// TLS settings of a https tentacle.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TentacleTls {
+    /// PEM bundle of CA certificates trusted in addition to the system ones.
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate presented to the tentacle, requires `key_file`.
+    pub cert_file: Option<PathBuf>,
+    /// PKCS#8 PEM key of the client certificate.
+    pub key_file: Option<PathBuf>,
+    /// Accepts any certificate and host name, for test environments only.
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Read(PathBuf, String),
+    Certificate(PathBuf, String),
+    Identity(String),
+    Connector(String),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Read(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            TlsError::Certificate(path, e) => {
+                write!(f, "invalid certificate in {}: {}", path.display(), e)
+            }
+            TlsError::Identity(e) => write!(f, "invalid client certificate or key: {}", e),
+            TlsError::Connector(e) => write!(f, "failed to set up tls: {}", e),
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|e| TlsError::Read(path.to_path_buf(), e.to_string()))
+}
+
+/// Reads all certificates of a PEM bundle, `Certificate::from_pem` only reads the first.
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let pem = String::from_utf8_lossy(&read(path)?).into_owned();
+    let certificates: Vec<Certificate> = pem
+        .split_inclusive(PEM_CERTIFICATE_END)
+        .filter(|block| block.contains(PEM_CERTIFICATE_END))
+        .map(|block| {
+            Certificate::from_pem(block.as_bytes())
+                .map_err(|e| TlsError::Certificate(path.to_path_buf(), e.to_string()))
+        })
+        .collect::<Result<_, _>>()?;
+    if certificates.is_empty() {
+        return Err(TlsError::Certificate(
+            path.to_path_buf(),
+            String::from("no PEM certificate found"),
+        ));
+    }
+    Ok(certificates)
+}
+
+impl TentacleTls {
+    /// Builds the connector, reading all certificate and key files.
+    pub fn connector(&self) -> Result<TlsConnector, TlsError> {
+        let mut builder = TlsConnector::builder();
+        if let Some(ca_file) = &self.ca_file {
+            for certificate in read_certificates(ca_file)? {
+                builder.add_root_certificate(certificate);
+            }
+        }
+        match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let identity = Identity::from_pkcs8(&read(cert_file)?, &read(key_file)?)
+                    .map_err(|e| TlsError::Identity(e.to_string()))?;
+                builder.identity(identity);
+            }
+            (None, None) => (),
+            _ => {
+                return Err(TlsError::Identity(String::from(
+                    "cert_file and key_file must be set together",
+                )))
+            }
+        }
+        if self.insecure {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        builder
+            .build()
+            .map_err(|e| TlsError::Connector(e.to_string()))
+    }
+}
+
+/// The client connectors of a worker, one per distinct TLS configuration.
+/// Tentacles without TLS settings use the default connector.
+#[derive(Default)]
+pub struct TlsConnectors {
+    connectors: RefCell<HashMap<TentacleTls, Addr<ClientConnector>>>,
+}
+
+impl TlsConnectors {
+    pub fn get(&self, tls: &TentacleTls) -> Result<Addr<ClientConnector>, TlsError> {
+        if let Some(connector) = self.connectors.borrow().get(tls) {
+            return Ok(connector.clone());
+        }
+        let connector = ClientConnector::with_connector(tls.connector()?.into()).start();
+        self.connectors
+            .borrow_mut()
+            .insert(tls.clone(), connector.clone());
+        Ok(connector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tentacle_tls::*;
+
+    #[test]
+    fn test_read_certificates() {
+        let bundle = std::env::temp_dir().join(format!("logtopus-ca-{}.pem", std::process::id()));
+        let ca = fs::read_to_string("tests/tls/ca.pem").unwrap();
+        let server = fs::read_to_string("tests/tls/server.pem").unwrap();
+        fs::write(&bundle, format!("# test bundle\n{}{}", ca, server)).unwrap();
+        assert_eq!(2, read_certificates(&bundle).unwrap().len());
+
+        fs::write(&bundle, "no certificate").unwrap();
+        assert!(read_certificates(&bundle).is_err());
+        fs::remove_file(&bundle).unwrap();
+    }
+
+    #[test]
+    fn test_connector() {
+        let tls = TentacleTls {
+            ca_file: Some(PathBuf::from("tests/tls/ca.pem")),
+            cert_file: Some(PathBuf::from("tests/tls/client.pem")),
+            key_file: Some(PathBuf::from("tests/tls/client.key")),
+            insecure: false,
+        };
+        assert!(tls.connector().is_ok());
+
+        let without_key = TentacleTls {
+            key_file: None,
+            ..tls.clone()
+        };
+        assert!(without_key.connector().is_err());
+
+        let missing_ca = TentacleTls {
+            ca_file: Some(PathBuf::from("tests/tls/missing.pem")),
+            ..tls
+        };
+        assert!(missing_ca.connector().is_err());
+    }
+}
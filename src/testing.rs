@@ -0,0 +1,36 @@
+use crate::tentacle::{LogLine, TentacleInfo, TentacleLogLine};
+use std::collections::BTreeMap;
+
+/// A line of the source `app-web` as tentacle `web-1` sends it.
+pub fn log_line(timestamp: i64, message: &str, loglevel: Option<&str>) -> LogLine {
+    LogLine {
+        timestamp,
+        message: String::from(message),
+        loglevel: loglevel.map(String::from),
+        id: String::from("app-web"),
+        source: String::from("web-1"),
+    }
+}
+
+/// A line as it is received from a tentacle.
+pub fn tentacle_line(timestamp: i64, message: &str, loglevel: Option<&str>) -> TentacleLogLine {
+    TentacleLogLine {
+        timestamp,
+        message: String::from(message),
+        loglevel: loglevel.map(String::from),
+    }
+}
+
+/// A plain http tentacle on localhost.
+pub fn tentacle(name: &str, port: i64) -> TentacleInfo {
+    TentacleInfo {
+        name: String::from(name),
+        host: String::from("localhost"),
+        port,
+        protocol: String::from("http"),
+        labels: BTreeMap::new(),
+        auth: None,
+        tls: None,
+        encodings: vec![],
+    }
+}
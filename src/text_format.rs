@@ -0,0 +1,279 @@
+use crate::color;
+use crate::color::TentacleColors;
+use crate::tentacle::LogLine;
+use chrono::format::{Item, StrftimeItems};
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use std::collections::BTreeMap;
+
+/// The text output without a template, kept as it was before templates.
+pub const DEFAULT_TEMPLATE: &str = "{ts:%H:%M:%S.%3f %d-%m-%Y} {message}";
+
+/// The timestamp format of `{ts}` without an explicit format.
+const DEFAULT_TS_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+
+#[derive(Clone, PartialEq, Debug)]
+enum Part {
+    Literal(String),
+    Timestamp(String),
+    Level,
+    Source,
+    Tentacle,
+    Message,
+}
+
+/// A parsed output template such as `{ts:%Y-%m-%dT%H:%M:%S%.3f} {level} [{source}] {message}`.
+///
+/// Placeholders are `{ts}` or `{ts:<strftime format>}`, `{level}`, `{source}`,
+/// `{tentacle}` and `{message}`, `{{` and `}}` are literal braces.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TextFormat {
+    parts: Vec<Part>,
+    tz: Tz,
+    colors: Option<TentacleColors>,
+}
+
+impl TextFormat {
+    pub fn parse(template: &str) -> Result<TextFormat, String> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| String::from("unclosed placeholder"))?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(parse_placeholder(&rest[..end])?);
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(String::from("unmatched }, use }} for a literal brace")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(TextFormat {
+            parts,
+            tz: Tz::UTC,
+            colors: None,
+        })
+    }
+
+    /// Resolves the `format` request parameter, the name of a configured preset
+    /// or, if it contains a placeholder, a template.
+    pub fn from_request(
+        format: Option<&str>,
+        tz: Option<&str>,
+        presets: &BTreeMap<String, String>,
+    ) -> Result<TextFormat, String> {
+        let template = match format {
+            None => DEFAULT_TEMPLATE,
+            Some(format) if format.contains('{') => format,
+            Some(name) => presets
+                .get(name)
+                .ok_or_else(|| format!("unknown text format {}", name))?,
+        };
+        Ok(TextFormat::parse(template)?.with_tz(parse_tz(tz)?))
+    }
+
+    /// Timestamps are shown in UTC unless another zone is set.
+    pub fn with_tz(self, tz: Tz) -> TextFormat {
+        TextFormat { tz, ..self }
+    }
+
+    /// Colors timestamps, loglevels and tentacle names with ANSI escape codes.
+    pub fn with_colors(self, colors: TentacleColors) -> TextFormat {
+        TextFormat {
+            colors: Some(colors),
+            ..self
+        }
+    }
+
+    fn push_colored(&self, text: &mut String, color: Option<&str>, value: &str) {
+        match color.filter(|_| self.colors.is_some()) {
+            Some(color) => {
+                text.push_str(color);
+                text.push_str(value);
+                text.push_str(color::RESET);
+            }
+            None => text.push_str(value),
+        }
+    }
+
+    /// Formats a line, including its line break.
+    pub fn format(&self, line: &LogLine) -> String {
+        let mut text = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => text.push_str(literal),
+                Part::Timestamp(format) => {
+                    let ts = match self.tz.timestamp_millis_opt(line.timestamp) {
+                        chrono::LocalResult::Single(ts) => ts.format(format).to_string(),
+                        _ => line.timestamp.to_string(),
+                    };
+                    self.push_colored(&mut text, Some(color::TIMESTAMP), &ts);
+                }
+                Part::Level => match &line.loglevel {
+                    Some(level) => self.push_colored(&mut text, color::level_color(level), level),
+                    None => text.push('-'),
+                },
+                Part::Source => text.push_str(&line.id),
+                Part::Tentacle => {
+                    let color = self.colors.as_ref().map(|c| c.color(&line.source));
+                    self.push_colored(&mut text, color.as_deref(), &line.source);
+                }
+                Part::Message => text.push_str(&line.message),
+            }
+        }
+        text.push('\n');
+        text
+    }
+
+    /// Formats the error ending a stream.
+    pub fn format_error(&self, error: &str) -> String {
+        let mut text = String::new();
+        self.push_colored(&mut text, Some(color::ERROR), &format!("error: {}", error));
+        text.push('\n');
+        text
+    }
+}
+
+/// Parses the `tz` request parameter, an IANA zone name defaulting to UTC.
+pub fn parse_tz(tz: Option<&str>) -> Result<Tz, String> {
+    tz.map_or(Ok(Tz::UTC), str::parse)
+}
+
+fn parse_placeholder(placeholder: &str) -> Result<Part, String> {
+    let (name, format) = match placeholder.find(':') {
+        Some(idx) => (&placeholder[..idx], Some(&placeholder[idx + 1..])),
+        None => (placeholder, None),
+    };
+    match (name, format) {
+        ("ts", None) => Ok(Part::Timestamp(String::from(DEFAULT_TS_FORMAT))),
+        ("ts", Some(format)) => {
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                Err(format!("invalid timestamp format {}", format))
+            } else {
+                Ok(Part::Timestamp(String::from(format)))
+            }
+        }
+        ("level", None) => Ok(Part::Level),
+        ("source", None) => Ok(Part::Source),
+        ("tentacle", None) => Ok(Part::Tentacle),
+        ("message", None) => Ok(Part::Message),
+        _ => Err(format!("unknown placeholder {{{}}}", placeholder)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::log_line;
+    use crate::text_format::*;
+
+    // 2019-03-31 01:30:00.250 UTC, 03:30 in Berlin after the switch to summer time
+    const TIMESTAMP: i64 = 1_553_995_800_250;
+
+    #[test]
+    fn test_default() {
+        let format = TextFormat::from_request(None, None, &BTreeMap::new()).unwrap();
+        assert_eq!(
+            "01:30:00.250 31-03-2019 hello\n",
+            format.format(&log_line(TIMESTAMP, "hello", Some("INFO")))
+        );
+    }
+
+    #[test]
+    fn test_template() {
+        let format = TextFormat::parse(
+            "{ts:%Y-%m-%dT%H:%M:%S%.3f} {level} [{source}/{tentacle}] {{{message}}}",
+        )
+        .unwrap();
+        assert_eq!(
+            "2019-03-31T01:30:00.250 ERROR [app-web/web-1] {hello}\n",
+            format.format(&log_line(TIMESTAMP, "hello", Some("ERROR")))
+        );
+        assert_eq!(
+            "2019-03-31T01:30:00.250 - [app-web/web-1] {hello}\n",
+            format.format(&log_line(TIMESTAMP, "hello", None))
+        );
+    }
+
+    #[test]
+    fn test_tz() {
+        let format =
+            TextFormat::from_request(Some("{ts}"), Some("Europe/Berlin"), &BTreeMap::new())
+                .unwrap();
+        assert_eq!(
+            "2019-03-31T03:30:00.250+02:00\n",
+            format.format(&log_line(TIMESTAMP, "hello", None))
+        );
+        assert!(TextFormat::from_request(None, Some("Mars/Olympus"), &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_presets() {
+        let mut presets = BTreeMap::new();
+        presets.insert(String::from("short"), String::from("{level} {message}"));
+        let format = TextFormat::from_request(Some("short"), None, &presets).unwrap();
+        assert_eq!(
+            "INFO hello\n",
+            format.format(&log_line(TIMESTAMP, "hello", Some("INFO")))
+        );
+        assert_eq!(
+            Err(String::from("unknown text format long")),
+            TextFormat::from_request(Some("long"), None, &presets)
+        );
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert_eq!(
+            Err(String::from("unknown placeholder {host}")),
+            TextFormat::parse("{host} {message}")
+        );
+        assert!(TextFormat::parse("{message").is_err());
+        assert!(TextFormat::parse("message}").is_err());
+        assert!(TextFormat::parse("{level:%Y}").is_err());
+        assert_eq!(
+            Err(String::from("invalid timestamp format %Q")),
+            TextFormat::parse("{ts:%Q}")
+        );
+    }
+
+    #[test]
+    fn test_colors() {
+        let format = TextFormat::parse("{ts:%H:%M} {level} {tentacle} {message}").unwrap();
+        let colors = TentacleColors::new(vec![String::from("web-1")]);
+        let tentacle = colors.color("web-1");
+        let colored = format.clone().with_colors(colors);
+        assert_eq!(
+            format!(
+                "\x1b[2m01:30\x1b[0m \x1b[1;31mERROR\x1b[0m {}web-1\x1b[0m hello\n",
+                tentacle
+            ),
+            colored.format(&log_line(TIMESTAMP, "hello", Some("ERROR")))
+        );
+        assert!(colored
+            .format(&log_line(TIMESTAMP, "hello", Some("NOTICE")))
+            .starts_with("\x1b[2m01:30\x1b[0m NOTICE \x1b[38;5;"));
+        assert_eq!(
+            "\x1b[1;31merror: killed\x1b[0m\n",
+            colored.format_error("killed")
+        );
+        assert_eq!("error: killed\n", format.format_error("killed"));
+    }
+}
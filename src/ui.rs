@@ -0,0 +1,224 @@
+use crate::cfg::SettingsHandle;
+use crate::compression::accepts;
+use actix_web::fs::{file_extension_to_mime, NamedFile, StaticFileConfig};
+use actix_web::http::{header, ContentEncoding, Method};
+use actix_web::{App, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+const INDEX: &str = "index.html";
+
+/// Precompressed variants looked up next to a file, in order of preference.
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+fn default_max_age_secs() -> u32 {
+    3600
+}
+
+/// The static bundle of the web UI served at `/`.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UiSettings {
+    /// Directory containing `index.html` and the assets.
+    pub dir: PathBuf,
+    /// How long browsers may cache assets, `index.html` is always revalidated.
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u32,
+}
+
+impl UiSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.dir.join(INDEX).is_file() {
+            Ok(())
+        } else {
+            Err(format!("{} contains no {}", self.dir.display(), INDEX))
+        }
+    }
+}
+
+/// UI files are only served for GET and HEAD requests.
+#[derive(Default)]
+struct UiFileConfig;
+
+impl StaticFileConfig for UiFileConfig {
+    fn is_method_allowed(method: &Method) -> bool {
+        method == Method::GET || method == Method::HEAD
+    }
+}
+
+/// The relative file path of a request path, `None` if it leaves the directory.
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let decoded = urlparse::unquote(path).ok()?;
+    let relative = PathBuf::from(decoded.trim_start_matches('/'));
+    if relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        Some(relative)
+    } else {
+        None
+    }
+}
+
+fn serve_file(
+    req: &HttpRequest<SettingsHandle>,
+    file: &Path,
+    cache_control: &str,
+) -> actix_web::Result<HttpResponse> {
+    let accept_encoding = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let precompressed = PRECOMPRESSED
+        .iter()
+        .filter(|(encoding, _)| accepts(accept_encoding, encoding))
+        .map(|(encoding, extension)| {
+            let mut variant = file.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(extension);
+            (*encoding, PathBuf::from(variant))
+        })
+        .find(|(_, variant)| variant.is_file());
+    let content_type =
+        file_extension_to_mime(file.extension().and_then(|ext| ext.to_str()).unwrap_or(""));
+
+    let mut response = match &precompressed {
+        Some((_, variant)) => NamedFile::open_with_config(variant, UiFileConfig)?
+            // already compressed, must not be encoded again
+            .set_content_encoding(ContentEncoding::Identity),
+        None => NamedFile::open_with_config(file, UiFileConfig)?,
+    }
+    .set_content_type(content_type)
+    .respond_to(req)?;
+
+    let headers = response.headers_mut();
+    if let Some((encoding, _)) = precompressed {
+        headers.insert(
+            header::CONTENT_ENCODING,
+            header::HeaderValue::from_static(encoding),
+        );
+    }
+    headers.remove(header::CONTENT_DISPOSITION);
+    headers.insert(
+        header::VARY,
+        header::HeaderValue::from_static("Accept-Encoding"),
+    );
+    if let Ok(value) = header::HeaderValue::from_str(cache_control) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+    Ok(response)
+}
+
+/// Serves the files of the UI directory. Paths without a file extension
+/// which match no file get `index.html`, the UI routes them itself.
+fn serve(req: &HttpRequest<SettingsHandle>) -> actix_web::Result<HttpResponse> {
+    let ui = match &req.state().get().ui {
+        Some(ui) => ui.clone(),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let relative = match relative_path(req.path()) {
+        Some(relative) => relative,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let file = ui.dir.join(&relative);
+    if file.is_file() && relative != Path::new(INDEX) {
+        return serve_file(req, &file, &format!("public, max-age={}", ui.max_age_secs));
+    }
+    if file.is_file() || file.is_dir() || relative.extension().is_none() {
+        return serve_file(req, &ui.dir.join(INDEX), "no-cache");
+    }
+    Ok(HttpResponse::NotFound().finish())
+}
+
+/// The app serving the UI, to be registered after the API app.
+pub fn ui_app(settings: SettingsHandle) -> App<SettingsHandle> {
+    App::with_state(settings)
+        .middleware(actix_web::middleware::Logger::default())
+        .default_resource(|r| r.f(serve))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::Settings;
+    use crate::ui::*;
+    use actix_web::test::TestRequest;
+    use std::fs;
+
+    fn settings(dir: &Path) -> SettingsHandle {
+        let mut settings = Settings::anonymous_defaults();
+        settings.ui = Some(UiSettings {
+            dir: dir.to_path_buf(),
+            max_age_secs: 600,
+        });
+        SettingsHandle::new(settings)
+    }
+
+    fn get(settings: &SettingsHandle, path: &str, accept_encoding: &str) -> HttpResponse {
+        let req = TestRequest::with_state(settings.clone())
+            .uri(path)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .finish();
+        serve(&req).unwrap()
+    }
+
+    fn header_value(response: &HttpResponse, name: header::HeaderName) -> &str {
+        response
+            .headers()
+            .get(name)
+            .map(|v| v.to_str().unwrap())
+            .unwrap_or("")
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(None, relative_path("/../Cargo.toml"));
+        assert_eq!(None, relative_path("/assets/%2e%2e/%2e%2e/Cargo.toml"));
+        assert_eq!(Some(PathBuf::from("a b.js")), relative_path("/a%20b.js"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = std::env::temp_dir().join(format!("logtopus-ui-{}", std::process::id()));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join(INDEX), "<html></html>").unwrap();
+        fs::write(dir.join("assets/app.js"), "app()").unwrap();
+        fs::write(dir.join("assets/app.js.br"), "brotli").unwrap();
+        fs::write(dir.join("assets/app.js.gz"), "gzip").unwrap();
+        let settings = settings(&dir);
+
+        let index = get(&settings, "/", "");
+        assert_eq!(200, index.status().as_u16());
+        assert_eq!("text/html", header_value(&index, header::CONTENT_TYPE));
+        assert_eq!("no-cache", header_value(&index, header::CACHE_CONTROL));
+
+        let route = get(&settings, "/sources/app-web", "");
+        assert_eq!("text/html", header_value(&route, header::CONTENT_TYPE));
+
+        let plain = get(&settings, "/assets/app.js", "identity");
+        assert_eq!("", header_value(&plain, header::CONTENT_ENCODING));
+        assert_eq!(
+            "public, max-age=600",
+            header_value(&plain, header::CACHE_CONTROL)
+        );
+        assert!(header_value(&plain, header::CONTENT_TYPE).contains("javascript"));
+
+        let brotli = get(&settings, "/assets/app.js", "gzip, br");
+        assert_eq!("br", header_value(&brotli, header::CONTENT_ENCODING));
+        assert!(header_value(&brotli, header::CONTENT_TYPE).contains("javascript"));
+        assert_eq!("6", header_value(&brotli, header::CONTENT_LENGTH));
+        let gzip = get(&settings, "/assets/app.js", "gzip");
+        assert_eq!("gzip", header_value(&gzip, header::CONTENT_ENCODING));
+
+        assert_eq!(
+            404,
+            get(&settings, "/assets/missing.js", "").status().as_u16()
+        );
+        assert_eq!(
+            404,
+            get(&settings, "/%2e%2e/Cargo.toml", "").status().as_u16()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}